@@ -34,7 +34,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     write("./send_csr_csr.csr", req.to_pem()?).await?;
 
     let response = client
-        .sign_csr(Request::new(grpc::SignCsrRequest { csr: req.to_pem()? }))
+        .sign_csr(Request::new(grpc::SignCsrRequest {
+            csr: req.to_pem()?,
+            profile: "default".to_string(),
+        }))
         .await?;
 
     write("./send_csr_csr.crt", response.into_inner().certificate).await?;
@@ -0,0 +1,228 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use openssl::asn1::Asn1Time;
+
+use crate::cert_store::store::{CertificateAuthority, CertificateStore};
+use crate::cert_store::utils::{create_new_ca, create_new_key, is_created_ca, KeyAlgorithm};
+use crate::crl::CrlPublisher;
+use crate::leader_election::LeaderElection;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Replaces the active CA with a successor before it expires. The previous CA
+/// is retired: it stays in the trust bundle until it expires, but all new
+/// certificates are signed by the successor.
+pub struct CaRotation {
+    cert_store: Arc<dyn CertificateStore>,
+    crl_publisher: Arc<CrlPublisher>,
+    leader_election: Arc<LeaderElection>,
+    threshold_days: u32,
+    key_algorithm: KeyAlgorithm,
+}
+
+impl CaRotation {
+    pub fn new(
+        cert_store: Arc<dyn CertificateStore>,
+        crl_publisher: Arc<CrlPublisher>,
+        leader_election: Arc<LeaderElection>,
+        threshold_days: u32,
+        key_algorithm: KeyAlgorithm,
+    ) -> Self {
+        Self {
+            cert_store,
+            crl_publisher,
+            leader_election,
+            threshold_days,
+            key_algorithm,
+        }
+    }
+
+    /// Rotate the CA if it expires within the threshold and remove expired
+    /// retired CAs. Returns `true` if the CA was rotated.
+    pub async fn rotate_if_needed(&self) -> Result<bool, Box<dyn Error>> {
+        debug!("Check if the CA must be rotated.");
+
+        let mut authorities = self.cert_store.authorities().as_ref().clone();
+        let now = Asn1Time::days_from_now(0)?;
+        let retired_count = authorities.retired.len();
+        authorities.retired.retain(|ca| ca.cert.not_after() > now);
+        let pruned = retired_count - authorities.retired.len();
+
+        let renewal = Asn1Time::days_from_now(self.threshold_days)?;
+        let expires = authorities.active.cert.not_after() <= renewal;
+        // The successor of an intermediate CA must be signed by the offline root and
+        // an imported CA is managed elsewhere, thus only CAs that were created by the
+        // PKI are rotated automatically.
+        let created = is_created_ca(&authorities.active.cert);
+        let rotate = expires && created && authorities.active.key.private_key().is_some();
+        if expires && authorities.active.is_intermediate() {
+            warn!(
+                "The intermediate CA expires on {}. Create a new CSR with '--intermediate-csr' \
+                 and import the renewed certificate with '--import-intermediate'.",
+                authorities.active.cert.not_after()
+            );
+        } else if expires && !created {
+            warn!(
+                "The imported CA expires on {}. It is managed outside of the PKI, thus it is not \
+                 rotated automatically.",
+                authorities.active.cert.not_after()
+            );
+        } else if expires && !rotate {
+            // A key in a PKCS#11 token must be generated in the token beforehand.
+            warn!(
+                "The CA expires on {}. Its key is held in a PKCS#11 token, thus the CA is not \
+                 rotated automatically.",
+                authorities.active.cert.not_after()
+            );
+        }
+        if rotate {
+            let key = create_new_key(self.key_algorithm)?;
+            let cert = create_new_ca(&key)?;
+            let previous = std::mem::replace(
+                &mut authorities.active,
+                CertificateAuthority {
+                    cert,
+                    key: Arc::new(key),
+                    chain: Vec::new(),
+                },
+            );
+            authorities.retired.insert(0, previous);
+        }
+
+        if !rotate && pruned == 0 {
+            return Ok(false);
+        }
+
+        self.cert_store.store_authorities(authorities).await?;
+        if pruned > 0 {
+            info!("Removed {} expired CA(s) from the trust bundle.", pruned);
+        }
+        if rotate {
+            info!("Rotated the CA, new certificates are signed by the successor CA.");
+        }
+        // Every CA publishes its own CRL, thus the CRLs change with the CAs.
+        self.crl_publisher.regenerate().await?;
+
+        Ok(rotate)
+    }
+
+    /// Check periodically if the CA must be rotated while this replica is the leader.
+    pub async fn run(self: Arc<Self>) {
+        self.leader_election
+            .lead(|| async {
+                let mut interval = tokio::time::interval(CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = self.rotate_if_needed().await {
+                        error!("Could not rotate the CA: {}", e);
+                    }
+                }
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::nid::Nid;
+    use openssl::x509::extension::{BasicConstraints, KeyUsage};
+    use openssl::x509::{X509Name, X509};
+    use time::Duration;
+
+    use crate::ca_rotation::CaRotation;
+    use crate::cert_store::memory_store::InMemoryStore;
+    use crate::cert_store::store::{CertificateAuthority, CertificateStore};
+    use crate::cert_store::utils::{
+        create_new_ca, create_new_key, signature_digest, KeyAlgorithm, CA_VALIDITY_DAYS,
+    };
+    use crate::crl::CrlPublisher;
+    use crate::leader_election::LeaderElection;
+
+    /// A CA that was created elsewhere, e.g. by a corporate PKI.
+    fn imported_authority() -> CertificateAuthority {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "Corporate CA")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(name.as_ref()).unwrap();
+        builder.set_issuer_name(name.as_ref()).unwrap();
+        builder.set_pubkey(key.as_ref()).unwrap();
+        builder
+            .set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+            .unwrap();
+        builder
+            .set_not_after(Asn1Time::days_from_now(30).unwrap().as_ref())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder
+            .append_extension(KeyUsage::new().critical().key_cert_sign().build().unwrap())
+            .unwrap();
+        builder.sign(&key, signature_digest(&key)).unwrap();
+        CertificateAuthority {
+            cert: builder.build(),
+            key: Arc::new(key),
+            chain: Vec::new(),
+        }
+    }
+
+    fn rotation(authority: CertificateAuthority) -> (Arc<dyn CertificateStore>, CaRotation) {
+        let store: Arc<dyn CertificateStore> = Arc::new(InMemoryStore::with_authority(authority));
+        let leader_election = Arc::new(LeaderElection::single());
+        let crl_publisher = Arc::new(CrlPublisher::new(
+            store.clone(),
+            leader_election.clone(),
+            Duration::hours(1),
+        ));
+        // The threshold exceeds the validity of new CAs, thus every CA expires within it.
+        let rotation = CaRotation::new(
+            store.clone(),
+            crl_publisher,
+            leader_election,
+            CA_VALIDITY_DAYS + 1,
+            KeyAlgorithm::EcdsaP256,
+        );
+        (store, rotation)
+    }
+
+    #[tokio::test]
+    async fn rotate_created_cas() {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let (store, rotation) = rotation(CertificateAuthority {
+            cert: create_new_ca(&key).unwrap(),
+            key: Arc::new(key),
+            chain: Vec::new(),
+        });
+        let previous = store.cert();
+
+        assert!(rotation.rotate_if_needed().await.unwrap());
+
+        let authorities = store.authorities();
+        assert_ne!(authorities.active.cert, previous);
+        assert_eq!(authorities.retired.len(), 1);
+        assert_eq!(authorities.retired[0].cert, previous);
+    }
+
+    #[tokio::test]
+    async fn keep_imported_cas() {
+        let authority = imported_authority();
+        let imported = authority.cert.clone();
+        let (store, rotation) = rotation(authority);
+
+        assert!(!rotation.rotate_if_needed().await.unwrap());
+
+        let authorities = store.authorities();
+        assert_eq!(authorities.active.cert, imported);
+        assert!(authorities.retired.is_empty());
+    }
+}
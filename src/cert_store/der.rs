@@ -0,0 +1,378 @@
+//! Minimal DER encoding and decoding helpers for the ASN.1 structures that
+//! OpenSSL does not offer builders or parsers for (e.g. certificate revocation
+//! lists and OCSP messages).
+
+use std::error::Error;
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, Id, PKeyRef};
+use time::OffsetDateTime;
+
+use crate::cert_store::signer::Signer;
+use crate::cert_store::utils::signature_digest;
+
+pub const TAG_BOOLEAN: u8 = 0x01;
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_NULL: u8 = 0x05;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_ENUMERATED: u8 = 0x0a;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_SEQUENCE: u8 = 0x30;
+
+pub const OID_SHA256_WITH_RSA: &[u64] = &[1, 2, 840, 113549, 1, 1, 11];
+pub const OID_ECDSA_WITH_SHA256: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+pub const OID_ECDSA_WITH_SHA384: &[u64] = &[1, 2, 840, 10045, 4, 3, 3];
+pub const OID_ED25519: &[u64] = &[1, 3, 101, 112];
+pub const OID_AUTHORITY_KEY_IDENTIFIER: &[u64] = &[2, 5, 29, 35];
+pub const OID_BASIC_CONSTRAINTS: &[u64] = &[2, 5, 29, 19];
+pub const OID_KEY_USAGE: &[u64] = &[2, 5, 29, 15];
+pub const OID_CRL_NUMBER: &[u64] = &[2, 5, 29, 20];
+pub const OID_CRL_REASON: &[u64] = &[2, 5, 29, 21];
+pub const OID_OCSP: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1];
+pub const OID_OCSP_BASIC: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 1];
+pub const OID_OCSP_NONCE: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 2];
+pub const OID_SHA1: &[u64] = &[1, 3, 14, 3, 2, 26];
+pub const OID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+pub const OID_CURVE_P256: &[u64] = &[1, 2, 840, 10045, 3, 1, 7];
+pub const OID_CURVE_P384: &[u64] = &[1, 3, 132, 0, 34];
+
+/// A single DER element that was read from encoded data.
+#[derive(Debug, Clone, Copy)]
+pub struct Element<'a> {
+    pub tag: u8,
+    pub content: &'a [u8],
+    /// The full encoding of the element (tag, length and content).
+    pub raw: &'a [u8],
+}
+
+/// Read the first element from the data and return it
+/// together with the data that follows the element.
+pub fn read(data: &[u8]) -> Result<(Element<'_>, &[u8]), Box<dyn Error>> {
+    if data.len() < 2 {
+        return Err("Unexpected end of DER data.".into());
+    }
+
+    let tag = data[0];
+    let (length, header) = match data[1] {
+        length if length < 0x80 => (length as usize, 2),
+        0x80 => return Err("Indefinite length is not allowed in DER.".into()),
+        length => {
+            let count = (length & 0x7f) as usize;
+            if count > std::mem::size_of::<usize>() || data.len() < 2 + count {
+                return Err("Invalid DER length.".into());
+            }
+            let length = data[2..2 + count]
+                .iter()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            (length, 2 + count)
+        }
+    };
+
+    if data.len() - header < length {
+        return Err("Unexpected end of DER data.".into());
+    }
+
+    let element = Element {
+        tag,
+        content: &data[header..header + length],
+        raw: &data[..header + length],
+    };
+    Ok((element, &data[header + length..]))
+}
+
+/// Read all elements that are contained in the given data
+/// (e.g. the content of a SEQUENCE).
+pub fn read_all(mut data: &[u8]) -> Result<Vec<Element<'_>>, Box<dyn Error>> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        let (element, rest) = read(data)?;
+        elements.push(element);
+        data = rest;
+    }
+    Ok(elements)
+}
+
+/// Read the single element in the data and check its tag.
+pub fn expect(data: &[u8], tag: u8) -> Result<Element<'_>, Box<dyn Error>> {
+    let (element, rest) = read(data)?;
+    if element.tag != tag || !rest.is_empty() {
+        return Err(format!("Expected DER element with tag {:#04x}.", tag).into());
+    }
+    Ok(element)
+}
+
+/// Return the value of the extension with the given id from
+/// the DER encoded certificate (if the certificate contains it).
+pub fn certificate_extension<'a>(
+    cert: &'a [u8],
+    id: &[u64],
+) -> Result<Option<&'a [u8]>, Box<dyn Error>> {
+    // extensions [3]
+    signed_extension(cert, 0xa3, id)
+}
+
+/// Return the value of the extension with the given id from
+/// the DER encoded CRL (if the CRL contains it).
+pub fn crl_extension<'a>(crl: &'a [u8], id: &[u64]) -> Result<Option<&'a [u8]>, Box<dyn Error>> {
+    // crlExtensions [0]
+    signed_extension(crl, 0xa0, id)
+}
+
+/// Find the extension in the to-be-signed part of a signed structure
+/// (certificate or CRL) whose extensions are tagged with `tag`.
+fn signed_extension<'a>(
+    signed: &'a [u8],
+    tag: u8,
+    id: &[u64],
+) -> Result<Option<&'a [u8]>, Box<dyn Error>> {
+    let signed = expect(signed, TAG_SEQUENCE)?;
+    let tbs = match read_all(signed.content)?.first() {
+        Some(tbs) if tbs.tag == TAG_SEQUENCE => tbs.content,
+        _ => return Err("Invalid signed DER structure.".into()),
+    };
+
+    let id = oid(id);
+    for field in read_all(tbs)? {
+        if field.tag != tag {
+            continue;
+        }
+        let extensions = expect(field.content, TAG_SEQUENCE)?;
+        for extension in read_all(extensions.content)? {
+            let parts = read_all(extension.content)?;
+            match (parts.first(), parts.last()) {
+                (Some(extension_id), Some(value)) if extension_id.raw == id.as_slice() => {
+                    return Ok(Some(expect(value.raw, TAG_OCTET_STRING)?.content));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    let length = content.len();
+    if length < 0x80 {
+        result.push(length as u8);
+    } else {
+        let bytes = length.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        result.push(0x80 | (bytes.len() - skip) as u8);
+        result.extend_from_slice(&bytes[skip..]);
+    }
+    result.extend_from_slice(content);
+    result
+}
+
+pub fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    tlv(TAG_SEQUENCE, items.concat().as_slice())
+}
+
+/// Context specific tag (e.g. `[0]`). Constructed tags are used
+/// for explicit tagging, primitive tags for implicit tagging of primitives.
+pub fn context(number: u8, constructed: bool, content: &[u8]) -> Vec<u8> {
+    let form = if constructed { 0x20 } else { 0x00 };
+    tlv(0x80 | form | number, content)
+}
+
+pub fn boolean(value: bool) -> Vec<u8> {
+    tlv(TAG_BOOLEAN, &[if value { 0xff } else { 0x00 }])
+}
+
+/// Encode an unsigned big endian number as INTEGER.
+pub fn integer(magnitude: &[u8]) -> Vec<u8> {
+    let skip = magnitude
+        .iter()
+        .take_while(|b| **b == 0)
+        .count()
+        .min(magnitude.len().saturating_sub(1));
+    let magnitude = &magnitude[skip..];
+    let mut content = Vec::with_capacity(magnitude.len() + 1);
+    if magnitude.is_empty() || magnitude[0] & 0x80 != 0 {
+        content.push(0);
+    }
+    content.extend_from_slice(magnitude);
+    tlv(TAG_INTEGER, content.as_slice())
+}
+
+pub fn small_integer(value: u64) -> Vec<u8> {
+    integer(&value.to_be_bytes())
+}
+
+pub fn enumerated(value: u8) -> Vec<u8> {
+    tlv(TAG_ENUMERATED, &[value])
+}
+
+pub fn null() -> Vec<u8> {
+    tlv(TAG_NULL, &[])
+}
+
+pub fn octet_string(content: &[u8]) -> Vec<u8> {
+    tlv(TAG_OCTET_STRING, content)
+}
+
+pub fn bit_string(content: &[u8]) -> Vec<u8> {
+    let mut data = vec![0];
+    data.extend_from_slice(content);
+    tlv(TAG_BIT_STRING, data.as_slice())
+}
+
+pub fn oid(components: &[u64]) -> Vec<u8> {
+    let mut content = vec![(components[0] * 40 + components[1]) as u8];
+    for component in &components[2..] {
+        let mut bytes = vec![(component & 0x7f) as u8];
+        let mut value = component >> 7;
+        while value > 0 {
+            bytes.push(0x80 | (value & 0x7f) as u8);
+            value >>= 7;
+        }
+        bytes.reverse();
+        content.extend(bytes);
+    }
+    tlv(TAG_OID, content.as_slice())
+}
+
+/// Encode a point in time as UTCTime (before 2050) or GeneralizedTime.
+pub fn time(time: OffsetDateTime) -> Vec<u8> {
+    if time.year() >= 2050 {
+        return generalized_time(time);
+    }
+
+    let value = format!(
+        "{:02}{:02}{:02}{:02}{:02}{:02}Z",
+        time.year() % 100,
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    );
+    tlv(TAG_UTC_TIME, value.as_bytes())
+}
+
+pub fn generalized_time(time: OffsetDateTime) -> Vec<u8> {
+    let value = format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}Z",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    );
+    tlv(TAG_GENERALIZED_TIME, value.as_bytes())
+}
+
+/// Encode an X.509 extension with the given (DER encoded) value.
+pub fn extension(id: &[u64], critical: bool, value: &[u8]) -> Vec<u8> {
+    let mut items = vec![oid(id)];
+    if critical {
+        items.push(boolean(true));
+    }
+    items.push(octet_string(value));
+    sequence(items.as_slice())
+}
+
+/// Encode a `uniformResourceIdentifier` general name.
+pub fn uri_name(uri: &str) -> Vec<u8> {
+    context(6, false, uri.as_bytes())
+}
+
+/// The algorithm identifier for signatures that are created with the given key.
+pub fn signature_algorithm<T: HasPublic>(key: &PKeyRef<T>) -> Result<Vec<u8>, Box<dyn Error>> {
+    match key.id() {
+        Id::RSA => Ok(sequence(&[oid(OID_SHA256_WITH_RSA), null()])),
+        Id::EC if signature_digest(key) == MessageDigest::sha384() => {
+            Ok(sequence(&[oid(OID_ECDSA_WITH_SHA384)]))
+        }
+        Id::EC => Ok(sequence(&[oid(OID_ECDSA_WITH_SHA256)])),
+        Id::ED25519 => Ok(sequence(&[oid(OID_ED25519)])),
+        _ => Err("Unsupported key type for signatures.".into()),
+    }
+}
+
+/// Sign the given DER encoded data and return the signature as BIT STRING.
+pub fn signature(signer: &dyn Signer, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let signature = signer.sign(data)?;
+    Ok(bit_string(signature.as_slice()))
+}
+
+/// Sign the given DER encoded data and return the full signed
+/// structure (`SEQUENCE { data, algorithm, signature }`).
+pub fn sign(signer: &dyn Signer, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(sequence(&[
+        data.to_vec(),
+        signer.signature_algorithm()?,
+        signature(signer, data)?,
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Object;
+    use time::OffsetDateTime;
+
+    use crate::cert_store::der;
+
+    #[test]
+    fn encode_integers() {
+        assert_eq!(der::small_integer(0), vec![0x02, 0x01, 0x00]);
+        assert_eq!(der::small_integer(127), vec![0x02, 0x01, 0x7f]);
+        assert_eq!(der::small_integer(128), vec![0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(
+            der::integer(&[0, 0, 0x01, 0x02]),
+            vec![0x02, 0x02, 0x01, 0x02]
+        );
+        assert_eq!(der::integer(&[]), vec![0x02, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn encode_long_lengths() {
+        let content = vec![0xab; 300];
+        let encoded = der::octet_string(content.as_slice());
+
+        assert_eq!(&encoded[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        let element = der::expect(encoded.as_slice(), der::TAG_OCTET_STRING).unwrap();
+        assert_eq!(element.content, content.as_slice());
+        assert_eq!(element.raw, encoded.as_slice());
+    }
+
+    #[test]
+    fn encode_oids_like_openssl() {
+        for oid in [der::OID_CRL_REASON, der::OID_OCSP_BASIC, der::OID_SHA256] {
+            let text = oid
+                .iter()
+                .map(|component| component.to_string())
+                .collect::<Vec<_>>()
+                .join(".");
+            let expected = Asn1Object::from_str(text.as_str()).unwrap();
+            assert_eq!(&der::oid(oid)[2..], expected.as_slice());
+        }
+    }
+
+    #[test]
+    fn encode_times() {
+        assert_eq!(
+            der::time(OffsetDateTime::from_unix_timestamp(1_649_999_167).unwrap()),
+            der::tlv(der::TAG_UTC_TIME, b"220415050607Z")
+        );
+        assert_eq!(
+            der::time(OffsetDateTime::from_unix_timestamp(2_524_608_000).unwrap()),
+            der::tlv(der::TAG_GENERALIZED_TIME, b"20500101000000Z")
+        );
+    }
+
+    #[test]
+    fn reject_invalid_der() {
+        assert!(der::read(&[0x30]).is_err());
+        assert!(der::read(&[0x30, 0x80, 0x00, 0x00]).is_err());
+        assert!(der::read(&[0x04, 0x03, 0x01]).is_err());
+        assert!(der::expect(&[0x05, 0x00, 0x05, 0x00], der::TAG_NULL).is_err());
+        assert!(der::expect(&[0x05, 0x00], der::TAG_INTEGER).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use openssl::pkey::PKey;
+
+use crate::cert_store::kubernetes_store::{KubernetesContext, KubernetesStore};
+use crate::cert_store::store::CertificateAuthority;
+use crate::cert_store::utils::{check_ca_certificate, parse_certificates, verify_chain};
+
+/// Parse and validate an existing CA that shall be imported into the store.
+/// The certificate data may contain the chain of the CA after its certificate.
+pub fn parse_authority(cert: &[u8], key: &[u8]) -> Result<CertificateAuthority, Box<dyn Error>> {
+    let mut certs = parse_certificates(cert)?;
+    if certs.is_empty() {
+        return Err("The CA certificate is missing.".into());
+    }
+    let cert = certs.remove(0);
+    let key = PKey::private_key_from_pem(key)?;
+
+    if !cert.public_key()?.public_eq(key.as_ref()) {
+        return Err("The CA key does not match the CA certificate.".into());
+    }
+    check_ca_certificate(cert.as_ref())?;
+    verify_chain(&cert, certs.as_slice())?;
+
+    Ok(CertificateAuthority {
+        cert,
+        key: Arc::new(key),
+        chain: certs,
+    })
+}
+
+/// Load the CA that shall be imported from a Kubernetes TLS secret.
+pub async fn authority_from_secret(
+    kubernetes: &KubernetesContext,
+    name: &str,
+) -> Result<CertificateAuthority, Box<dyn Error>> {
+    let (cert, key) = KubernetesStore::load_tls_secret(kubernetes, name).await?;
+    parse_authority(cert.as_slice(), key.as_slice())
+}
@@ -0,0 +1,329 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::info;
+use openssl::stack::Stack;
+use openssl::x509::extension::{BasicConstraints, KeyUsage};
+use openssl::x509::{X509NameRef, X509Req};
+
+use crate::cert_store::signer::{sign_request, KeyStorage, Signer};
+use crate::cert_store::store::{Authorities, CertificateAuthority, CertificateStore};
+use crate::cert_store::utils::{check_ca_certificate, parse_certificates, verify_chain};
+
+/// File mode of the key of the intermediate CA (read and write for the owner only).
+#[cfg(unix)]
+const KEY_FILE_MODE: u32 = 0o600;
+
+/// Create a CSR with the given subject for the new key of the intermediate CA that
+/// can be signed by an (offline) root CA to run the PKI as intermediate CA. The CSR
+/// does not depend on the store, thus no CA is created to request it.
+pub fn create_intermediate_csr(
+    subject: &X509NameRef,
+    key: &dyn Signer,
+) -> Result<X509Req, Box<dyn Error>> {
+    let mut builder = X509Req::builder()?;
+    builder.set_version(0)?;
+    builder.set_subject_name(subject)?;
+    builder.set_pubkey(key.public_key()?.as_ref())?;
+
+    let mut extensions = Stack::new()?;
+    extensions.push(BasicConstraints::new().critical().ca().pathlen(0).build()?)?;
+    extensions.push(
+        KeyUsage::new()
+            .critical()
+            .key_cert_sign()
+            .crl_sign()
+            .build()?,
+    )?;
+    builder.add_extensions(extensions.as_ref())?;
+
+    sign_request(builder, key)
+}
+
+/// Write the key of the intermediate CA (encoded with the key storage, i.e. encrypted
+/// if a passphrase is configured) to the given path. Existing files are not overwritten.
+pub async fn write_intermediate_key(
+    path: &Path,
+    key: &dyn Signer,
+    key_storage: &KeyStorage,
+) -> Result<(), Box<dyn Error>> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(KEY_FILE_MODE);
+
+    let mut file = options.open(path).await?;
+    file.write_all(key_storage.encode(key)?.as_slice()).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Read the key of the intermediate CA that was written by [write_intermediate_key].
+pub async fn read_intermediate_key(
+    path: &Path,
+    key_storage: &KeyStorage,
+) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
+    key_storage.decode(tokio::fs::read(path).await?.as_slice())
+}
+
+/// Parse the intermediate CA of the PEM data, which must contain the intermediate
+/// certificate followed by the certificates of its issuers (up to the root CA). The
+/// intermediate certificate must be issued for the given key (see [create_intermediate_csr]).
+pub fn intermediate_authority(
+    key: Arc<dyn Signer>,
+    pem: &[u8],
+) -> Result<CertificateAuthority, Box<dyn Error>> {
+    let mut certs = parse_certificates(pem)?;
+    if certs.len() < 2 {
+        return Err("The file must contain the intermediate certificate and its chain.".into());
+    }
+    let cert = certs.remove(0);
+    let chain = certs;
+
+    if !cert.public_key()?.public_eq(key.public_key()?.as_ref()) {
+        return Err(
+            "The intermediate certificate does not match the key of the intermediate CA.".into(),
+        );
+    }
+    check_ca_certificate(cert.as_ref())?;
+    verify_chain(&cert, chain.as_slice())?;
+    Ok(CertificateAuthority { cert, key, chain })
+}
+
+/// Use the intermediate CA as active CA. The previous CA is retired,
+/// thus the certificates it issued stay valid until it expires.
+pub fn import_intermediate(
+    mut authorities: Authorities,
+    intermediate: CertificateAuthority,
+) -> Result<Authorities, Box<dyn Error>> {
+    let previous = std::mem::replace(&mut authorities.active, intermediate);
+    // A renewed intermediate certificate for the same key replaces the previous one.
+    if !previous
+        .key
+        .public_key()?
+        .public_eq(authorities.active.key.public_key()?.as_ref())
+    {
+        authorities.retired.insert(0, previous);
+    }
+    Ok(authorities)
+}
+
+/// Install the intermediate CA in the store. A store without a CA gets the
+/// intermediate CA as its first CA, thus no self-signed CA with an online key
+/// is created (and trusted) before. Otherwise, the active CA is retired (see
+/// [import_intermediate]).
+pub async fn install_intermediate(
+    store: &mut dyn CertificateStore,
+    intermediate: CertificateAuthority,
+) -> Result<(), Box<dyn Error>> {
+    if !store.has_authority().await? {
+        info!("The store does not contain a CA, use the intermediate CA as first CA.");
+        return store.init(Some(intermediate)).await;
+    }
+
+    store.init(None).await?;
+    let authorities = import_intermediate(store.authorities().as_ref().clone(), intermediate)?;
+    store.store_authorities(authorities).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::stack::Stack;
+    use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectKeyIdentifier};
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509Name, X509Req, X509StoreContext, X509};
+
+    use crate::cert_store::intermediate::{
+        create_intermediate_csr, import_intermediate, install_intermediate, intermediate_authority,
+    };
+    use crate::cert_store::memory_store::InMemoryStore;
+    use crate::cert_store::profile::Profiles;
+    use crate::cert_store::signer::KeyStorage;
+    use crate::cert_store::store::{Authorities, CertificateAuthority, CertificateStore};
+    use crate::cert_store::utils::{
+        certificates_to_pem, create_new_ca, create_new_key, signature_digest, KeyAlgorithm,
+    };
+
+    /// Issue a CA certificate for the request, signed by the key (self-signed if no issuer is given).
+    fn issue_ca(request: &X509Req, issuer: Option<&X509>, key: &PKey<Private>) -> X509 {
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(request.subject_name()).unwrap();
+        builder
+            .set_issuer_name(issuer.map_or(request.subject_name(), |issuer| issuer.subject_name()))
+            .unwrap();
+        builder
+            .set_pubkey(request.public_key().unwrap().as_ref())
+            .unwrap();
+        builder
+            .set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+            .unwrap();
+        builder
+            .set_not_after(Asn1Time::days_from_now(365).unwrap().as_ref())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder
+            .append_extension(
+                KeyUsage::new()
+                    .critical()
+                    .key_cert_sign()
+                    .crl_sign()
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        let subject_key_identifier = SubjectKeyIdentifier::new()
+            .build(&builder.x509v3_context(issuer.map(|issuer| issuer.as_ref()), None))
+            .unwrap();
+        builder.append_extension(subject_key_identifier).unwrap();
+        let serial_number = BigNum::from_u32(2).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial_number).unwrap();
+        builder.sign(key, signature_digest(key)).unwrap();
+        builder.build()
+    }
+
+    fn root() -> (X509, PKey<Private>) {
+        let key = create_new_key(KeyAlgorithm::EcdsaP384).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "Offline Root")
+            .unwrap();
+        let mut request = X509Req::builder().unwrap();
+        request.set_subject_name(name.build().as_ref()).unwrap();
+        request.set_pubkey(key.as_ref()).unwrap();
+        request.sign(&key, signature_digest(&key)).unwrap();
+        (issue_ca(&request.build(), None, &key), key)
+    }
+
+    fn intermediate_subject() -> X509Name {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "Intermediate")
+            .unwrap();
+        name.build()
+    }
+
+    fn authorities() -> Authorities {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        Authorities {
+            active: CertificateAuthority {
+                cert: create_new_ca(&key).unwrap(),
+                key: Arc::new(key),
+                chain: Vec::new(),
+            },
+            retired: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn create_the_csr_for_a_new_key() {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let csr = create_intermediate_csr(&intermediate_subject(), &key).unwrap();
+
+        assert!(csr.verify(csr.public_key().unwrap().as_ref()).unwrap());
+        assert!(csr.public_key().unwrap().public_eq(&key));
+        assert_eq!(
+            csr.subject_name().to_der().unwrap(),
+            intermediate_subject().to_der().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn import_the_signed_intermediate() {
+        let (root, root_key) = root();
+        let authorities = authorities();
+        let previous = authorities.active.cert.clone();
+        let key = Arc::new(create_new_key(KeyAlgorithm::EcdsaP256).unwrap());
+        let csr = create_intermediate_csr(&intermediate_subject(), key.as_ref()).unwrap();
+        let intermediate = issue_ca(&csr, Some(&root), &root_key);
+        let pem = certificates_to_pem(&[intermediate.clone(), root.clone()]).unwrap();
+
+        let other_key = Arc::new(create_new_key(KeyAlgorithm::EcdsaP256).unwrap());
+        assert!(intermediate_authority(other_key, pem.as_slice()).is_err());
+        let incomplete = certificates_to_pem(std::slice::from_ref(&intermediate)).unwrap();
+        assert!(intermediate_authority(key.clone(), incomplete.as_slice()).is_err());
+
+        let authority = intermediate_authority(key, pem.as_slice()).unwrap();
+        let authorities = import_intermediate(authorities, authority).unwrap();
+        assert_eq!(authorities.active.cert, intermediate);
+        assert_eq!(authorities.active.chain, vec![root.clone()]);
+        assert_eq!(authorities.retired.len(), 1);
+        assert_eq!(authorities.retired[0].cert, previous);
+
+        // A leaf certificate of the installed intermediate CA verifies up to the root.
+        let store = InMemoryStore::with_authority(authorities.active.clone());
+        store.store_authorities(authorities).await.unwrap();
+        let leaf_key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "leaf").unwrap();
+        let name = name.build();
+        let mut request = X509Req::builder().unwrap();
+        request.set_subject_name(name.as_ref()).unwrap();
+        request.set_pubkey(leaf_key.as_ref()).unwrap();
+        request
+            .sign(&leaf_key, signature_digest(&leaf_key))
+            .unwrap();
+        let profiles = Profiles::load(None, 24, "default".to_string())
+            .await
+            .unwrap();
+        let leaf = store
+            .sign_csr(
+                request.build(),
+                name.as_ref(),
+                &[],
+                profiles.get("").unwrap(),
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let mut trust = X509StoreBuilder::new().unwrap();
+        trust.add_cert(root).unwrap();
+        let trust = trust.build();
+        let mut chain = Stack::new().unwrap();
+        chain.push(intermediate).unwrap();
+        let mut context = X509StoreContext::new().unwrap();
+        let error = context
+            .init(&trust, &leaf, &chain, |context| {
+                context.verify_cert().map(|_| context.error())
+            })
+            .unwrap();
+        assert_eq!(error.as_raw(), 0, "{}", error);
+    }
+
+    #[tokio::test]
+    async fn install_the_intermediate_as_first_ca() {
+        let (root, root_key) = root();
+        let key = Arc::new(create_new_key(KeyAlgorithm::EcdsaP256).unwrap());
+        let csr = create_intermediate_csr(&intermediate_subject(), key.as_ref()).unwrap();
+        let intermediate = issue_ca(&csr, Some(&root), &root_key);
+        let pem = certificates_to_pem(&[intermediate.clone(), root]).unwrap();
+        let authority = intermediate_authority(key, pem.as_slice()).unwrap();
+
+        // No self-signed CA is created (and retired) in an empty store.
+        let mut store = InMemoryStore::new(KeyAlgorithm::EcdsaP256, KeyStorage::default());
+        install_intermediate(&mut store, authority.clone())
+            .await
+            .unwrap();
+        assert_eq!(store.authorities().active.cert, intermediate);
+        assert!(store.authorities().retired.is_empty());
+
+        // An existing CA is retired.
+        let authorities = authorities();
+        let previous = authorities.active.cert.clone();
+        let mut store = InMemoryStore::with_authority(authorities.active);
+        install_intermediate(&mut store, authority).await.unwrap();
+        assert_eq!(store.authorities().active.cert, intermediate);
+        assert_eq!(store.authorities().retired.len(), 1);
+        assert_eq!(store.authorities().retired[0].cert, previous);
+    }
+}
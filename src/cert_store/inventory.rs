@@ -0,0 +1,121 @@
+use std::error::Error;
+
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::x509::X509Ref;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::cert_store::utils::{certificate_alt_names, name_to_string};
+
+/// A certificate that was issued by the PKI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateRecord {
+    /// The serial number of the certificate in uppercase hex notation.
+    pub serial_number: String,
+    pub subject: String,
+    pub subject_alt_names: Vec<String>,
+    /// Unix timestamp of the start of the validity.
+    pub not_before: i64,
+    /// Unix timestamp of the end of the validity.
+    pub not_after: i64,
+    /// The identity of the caller that requested the certificate.
+    pub requester: String,
+    /// The name of the issuance profile.
+    pub profile: String,
+}
+
+impl CertificateRecord {
+    pub fn new(cert: &X509Ref, requester: &str, profile: &str) -> Result<Self, Box<dyn Error>> {
+        let subject_alt_names = certificate_alt_names(cert)?
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        Ok(Self {
+            serial_number: cert.serial_number().to_bn()?.to_hex_str()?.to_string(),
+            subject: name_to_string(cert.subject_name())?,
+            subject_alt_names,
+            not_before: unix_timestamp(cert.not_before())?,
+            not_after: unix_timestamp(cert.not_after())?,
+            requester: requester.to_string(),
+            profile: profile.to_string(),
+        })
+    }
+
+    /// Check if the certificate is expired.
+    pub fn is_expired(&self) -> bool {
+        self.not_after < OffsetDateTime::now_utc().unix_timestamp()
+    }
+}
+
+/// Selects issued certificates, empty fields match all certificates.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CertificateFilter {
+    /// Text that the subject must contain.
+    pub subject: String,
+    pub profile: String,
+    pub requester: String,
+    pub include_expired: bool,
+}
+
+impl CertificateFilter {
+    pub fn matches(&self, record: &CertificateRecord) -> bool {
+        (self.include_expired || !record.is_expired())
+            && record.subject.contains(self.subject.as_str())
+            && (self.profile.is_empty() || record.profile == self.profile)
+            && (self.requester.is_empty() || record.requester == self.requester)
+    }
+}
+
+/// Remove the expired certificates from the records and
+/// return the number of removed records.
+pub fn prune_expired(records: &mut Vec<CertificateRecord>) -> usize {
+    let count = records.len();
+    records.retain(|record| !record.is_expired());
+    count - records.len()
+}
+
+fn unix_timestamp(time: &Asn1TimeRef) -> Result<i64, Box<dyn Error>> {
+    let epoch = Asn1Time::from_unix(0)?;
+    let diff = epoch.diff(time)?;
+    Ok(i64::from(diff.days) * 86400 + i64::from(diff.secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use crate::cert_store::inventory::{prune_expired, CertificateRecord};
+
+    fn record(serial_number: &str, not_after: i64) -> CertificateRecord {
+        CertificateRecord {
+            serial_number: serial_number.to_string(),
+            subject: format!("CN={}", serial_number),
+            subject_alt_names: Vec::new(),
+            not_before: 0,
+            not_after,
+            requester: "anonymous".to_string(),
+            profile: "default".to_string(),
+        }
+    }
+
+    #[test]
+    fn prune_expired_records() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut records = vec![
+            record("01", now - 3600),
+            record("02", now + 3600),
+            record("03", 0),
+            record("04", now + 60),
+        ];
+
+        assert_eq!(prune_expired(&mut records), 2);
+        let serial_numbers: Vec<&str> = records
+            .iter()
+            .map(|record| record.serial_number.as_str())
+            .collect();
+        assert_eq!(serial_numbers, vec!["02", "04"]);
+        assert_eq!(prune_expired(&mut records), 0);
+    }
+}
@@ -2,208 +2,1210 @@ use std::collections::BTreeMap;
 use std::env;
 use std::error::Error;
 use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use k8s_openapi::api::core::v1::Secret;
+use futures::TryStreamExt;
+use k8s_openapi::api::core::v1::{ConfigMap, Event, EventSource, ObjectReference, Secret};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::Utc;
 use k8s_openapi::ByteString;
-use kube::api::PostParams;
+use kube::api::{ListParams, ObjectMeta, Patch, PatchParams, PostParams};
 use kube::config::Kubeconfig;
-use kube::{Api, Client};
-use log::{debug, info};
-use openssl::pkey::{PKey, PKeyRef, Private};
-use openssl::x509::{X509Ref, X509};
+use kube::runtime::watcher;
+use kube::runtime::watcher::watch_object;
+use kube::{Api, Client, Resource};
+use log::{debug, error, info, warn};
+use openssl::hash::{hash, MessageDigest};
+use openssl::x509::X509;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
 use tokio::fs::read_to_string;
+use tokio::sync::watch;
 
-use crate::cert_store::store::CertificateStore;
-use crate::cert_store::utils::{create_new_ca, create_new_key};
+use crate::cert_store::inventory::{prune_expired, CertificateRecord};
+use crate::cert_store::key_encryption::KeyEncryption;
+use crate::cert_store::registry::{StoreBuilder, StoreContext, StoreUrl};
+use crate::cert_store::revocation::RevokedCertificate;
+use crate::cert_store::signer::KeyStorage;
+use crate::cert_store::store::{
+    Authorities, CertificateAuthority, CertificateStore, StoredAuthority,
+};
+use crate::cert_store::utils::{
+    certificates_to_pem, create_new_ca, parse_certificates, KeyAlgorithm,
+};
+use crate::leader_election::LeaderElection;
 
 const SECRET_KEY: &str = "caKey";
 const SECRET_CERTIFICATE: &str = "caCert";
-const SECRET_SERIAL_NUMBER: &str = "serialNumber";
+const SECRET_CHAIN: &str = "caChain";
+const SECRET_RETIRED_AUTHORITIES: &str = "retiredAuthorities";
+/// The keys of the records that previous versions kept in the secret,
+/// they are moved to the record ConfigMaps by the leader.
+const SECRET_REVOKED_CERTIFICATES: &str = "revokedCertificates";
+const SECRET_ISSUED_CERTIFICATES: &str = "issuedCertificates";
+const SECRET_CRL: &str = "crl";
+/// The keys of the secret that hold the CAs, a change of other keys does not reload the CAs.
+const AUTHORITY_KEYS: [&str; 4] = [
+    SECRET_KEY,
+    SECRET_CERTIFICATE,
+    SECRET_CHAIN,
+    SECRET_RETIRED_AUTHORITIES,
+];
+
+/// Label of the ConfigMaps that hold the records (revocations, CRL and inventory)
+/// of the store, its value is the name of the secret. The records are kept out of
+/// the secret, thus recording a certificate does not rewrite the CA and no object
+/// grows beyond the size limit of 1 MiB.
+const RECORDS_LABEL: &str = "pki.wirepact.io/store";
+/// The ConfigMap `<secret>-revocations` holds a revoked certificate per serial number.
+const REVOCATIONS_SUFFIX: &str = "revocations";
+/// The ConfigMap `<secret>-crl` holds the CRLs that are shared with the other replicas.
+const CRL_SUFFIX: &str = "crl";
+const CONFIG_MAP_CRL: &str = "crl";
+/// The inventory is sharded by the last hex digit of the serial number into the
+/// ConfigMaps `<secret>-inventory-0` to `<secret>-inventory-f`, each holds
+/// a certificate record per serial number.
+const INVENTORY_SUFFIX: &str = "inventory";
+
+const TLS_SECRET_CERTIFICATE: &str = "tls.crt";
+const TLS_SECRET_KEY: &str = "tls.key";
+
+/// Maximum number of attempts to store the secret when it is modified concurrently.
+const MAX_UPDATE_ATTEMPTS: usize = 10;
+/// HTTP status of a rejected write due to a stale `resourceVersion` or an existing object.
+const CONFLICT: u16 = 409;
+const NOT_FOUND: u16 = 404;
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
 
 const DEFAULT_NAMESPACE: &str = "default";
 const DOWNWARD_API_ENV: &str = "POD_NAMESPACE";
 const DOWNWARD_API_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
 
-#[derive(Debug, Default)]
+/// The client for the Kubernetes API and the namespace of the PKI. It is created
+/// once and shared by all parts of the PKI that access the Kubernetes API.
+#[derive(Clone)]
+pub struct KubernetesContext {
+    pub client: Client,
+    pub namespace: String,
+}
+
+impl KubernetesContext {
+    /// Create the client from the kubeconfig or the in-cluster configuration.
+    /// If no namespace is given, the current namespace is resolved.
+    pub async fn new(namespace: Option<String>) -> Result<Self, Box<dyn Error>> {
+        let client = Client::try_default().await?;
+        let namespace = match namespace {
+            Some(namespace) => namespace,
+            None => current_namespace().await?,
+        };
+        debug!("Use the Kubernetes namespace '{}'.", namespace);
+        Ok(Self { client, namespace })
+    }
+
+    /// Return the API for namespaced resources in the namespace of the PKI.
+    pub fn api<K>(&self) -> Api<K>
+    where
+        K: Resource,
+        <K as Resource>::DynamicType: Default,
+    {
+        Api::namespaced(self.client.clone(), self.namespace.as_str())
+    }
+}
+
+/// Resolve the namespace of the PKI from the kubeconfig, the downward API
+/// or fall back to the `default` namespace.
+async fn current_namespace() -> Result<String, Box<dyn Error>> {
+    if let Ok(config) = Kubeconfig::read() {
+        let default_context = "".to_string();
+        let current_context_name = config.current_context.as_ref().unwrap_or(&default_context);
+        let current_namespace = config
+            .contexts
+            .iter()
+            .find(|&ctx| ctx.name == *current_context_name)
+            .expect("No context with name found.")
+            .clone()
+            .context
+            .namespace
+            .unwrap_or_else(|| "".to_string());
+
+        if !current_namespace.is_empty() {
+            return Ok(current_namespace);
+        }
+    }
+
+    if let Ok(value) = env::var(DOWNWARD_API_ENV) {
+        return Ok(value);
+    }
+
+    let path = Path::new(DOWNWARD_API_FILE);
+    if path.exists() {
+        let content = read_to_string(path).await?;
+        return Ok(content.trim().to_string());
+    }
+
+    Ok(DEFAULT_NAMESPACE.to_string())
+}
+
+/// The configuration of the Kubernetes secret store (`k8s://<namespace>/<secret>`).
+/// If the namespace is omitted (`k8s:///<secret>`), the namespace of the PKI is used.
+#[derive(Debug, Clone)]
+pub struct KubernetesStoreConfig {
+    pub namespace: Option<String>,
+    pub secret_name: String,
+}
+
+impl StoreBuilder for KubernetesStoreConfig {
+    fn from_url(url: &StoreUrl) -> Result<Self, Box<dyn Error>> {
+        let (namespace, secret_name) = match url.location().split_once('/') {
+            Some((namespace, secret_name)) => (namespace, secret_name),
+            None => ("", url.location()),
+        };
+        if secret_name.is_empty() || secret_name.contains('/') {
+            return Err(format!(
+                "Invalid Kubernetes store '{}', use 'k8s://<namespace>/<secret>'.",
+                url
+            )
+            .into());
+        }
+
+        Ok(Self {
+            namespace: Some(namespace.to_string()).filter(|namespace| !namespace.is_empty()),
+            secret_name: secret_name.to_string(),
+        })
+    }
+
+    fn build(self, context: &StoreContext) -> Result<Box<dyn CertificateStore>, Box<dyn Error>> {
+        let mut kubernetes = context
+            .kubernetes
+            .clone()
+            .ok_or("The Kubernetes store requires the client for the Kubernetes API.")?;
+        if let Some(namespace) = self.namespace {
+            kubernetes.namespace = namespace;
+        }
+
+        Ok(Box::new(KubernetesStore::new(
+            kubernetes,
+            self.secret_name,
+            context.key_algorithm,
+            context.key_storage.clone(),
+            context.leader_election.clone(),
+        )))
+    }
+}
+
 pub struct KubernetesStore {
-    pub(super) secret_name: String,
-    cert: Option<X509>,
-    key: Option<PKey<Private>>,
+    kubernetes: KubernetesContext,
+    secret_name: String,
+    key_algorithm: KeyAlgorithm,
+    key_storage: KeyStorage,
+    authorities: RwLock<Option<Arc<Authorities>>>,
+    /// The records as seen by the watch of the record ConfigMaps,
+    /// `None` until the watch listed them.
+    records: RwLock<Option<RecordCache>>,
+    /// The digest of the CA data of the last loaded secret (see [authorities_digest]).
+    authorities_digest: Mutex<Option<Vec<u8>>>,
+    reloads: watch::Sender<u64>,
+    revocations: watch::Sender<u64>,
+    leader_election: Arc<LeaderElection>,
 }
 
 impl KubernetesStore {
-    async fn current_namespace(&self) -> Result<String, Box<dyn Error>> {
-        if let Ok(config) = Kubeconfig::read() {
-            let default_context = "".to_string();
-            let current_context_name = config.current_context.as_ref().unwrap_or(&default_context);
-            let current_namespace = config
-                .contexts
-                .iter()
-                .find(|&ctx| ctx.name == *current_context_name)
-                .expect("No context with name found.")
-                .clone()
-                .context
-                .namespace
-                .unwrap_or_else(|| "".to_string());
-
-            if !current_namespace.is_empty() {
-                return Ok(current_namespace);
-            }
+    pub fn new(
+        kubernetes: KubernetesContext,
+        secret_name: String,
+        key_algorithm: KeyAlgorithm,
+        key_storage: KeyStorage,
+        leader_election: Arc<LeaderElection>,
+    ) -> Self {
+        Self {
+            kubernetes,
+            secret_name,
+            key_algorithm,
+            key_storage,
+            authorities: RwLock::new(None),
+            records: RwLock::new(None),
+            authorities_digest: Mutex::new(None),
+            reloads: watch::channel(0).0,
+            revocations: watch::channel(0).0,
+            leader_election,
         }
+    }
 
-        if let Ok(value) = env::var(DOWNWARD_API_ENV) {
-            return Ok(value);
-        }
+    /// Load the certificate and the key of a Kubernetes TLS secret
+    /// (`tls.crt` and `tls.key`) in the namespace of the PKI.
+    pub(super) async fn load_tls_secret(
+        kubernetes: &KubernetesContext,
+        name: &str,
+    ) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+        debug!("Load Kubernetes TLS secret '{}'.", name);
 
-        let path = Path::new(DOWNWARD_API_FILE);
-        if path.exists() {
-            let content = read_to_string(path).await?;
-            return Ok(content.trim().to_string());
+        let secrets: Api<Secret> = kubernetes.api();
+        let data = secrets.get(name).await?.data.unwrap_or_default();
+        match (data.get(TLS_SECRET_CERTIFICATE), data.get(TLS_SECRET_KEY)) {
+            (Some(cert), Some(key)) => Ok((cert.0.clone(), key.0.clone())),
+            _ => Err(format!(
+                "The secret '{}' must contain '{}' and '{}'.",
+                name, TLS_SECRET_CERTIFICATE, TLS_SECRET_KEY
+            )
+            .into()),
         }
+    }
 
-        Ok(DEFAULT_NAMESPACE.to_string())
+    fn secrets(&self) -> Api<Secret> {
+        self.kubernetes.api()
     }
 
-    async fn load_secret(&self) -> Result<Secret, Box<dyn Error>> {
-        debug!("Load Kubernetes secret.");
+    /// Apply the update to the data of the secret and store it. The write is
+    /// rejected if the secret was modified since it was loaded (`resourceVersion`
+    /// precondition), in which case the update is retried on the current secret.
+    /// The update returns `false` if the secret does not need to be stored.
+    async fn update_secret<F>(&self, mut update: F) -> Result<bool, Box<dyn Error>>
+    where
+        F: FnMut(&mut BTreeMap<String, ByteString>) -> Result<bool, Box<dyn Error>> + Send,
+    {
+        let secrets = self.secrets();
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let mut secret = secrets.get(self.secret_name.as_str()).await?;
+            let mut data = secret.data.take().unwrap_or_default();
+            if !update(&mut data)? {
+                return Ok(false);
+            }
 
-        let client = Client::try_default().await?;
-        let secrets: Api<Secret> =
-            Api::namespaced(client, self.current_namespace().await?.as_str());
-
-        let secret = secrets.get(self.secret_name.as_str()).await;
-        match secret {
-            Ok(secret) => Ok(secret),
-            Err(_) => {
-                info!(
-                    "Kubernetes secret '{}' does not exist, create it.",
-                    self.secret_name
-                );
-                let mut secret = Secret::default();
-                secret.metadata.name = Some(self.secret_name.clone());
+            debug!("Store Kubernetes secret.");
+            secret.data = Some(data);
+            match secrets
+                .replace(self.secret_name.as_str(), &PostParams::default(), &secret)
+                .await
+            {
+                Ok(_) => return Ok(true),
+                Err(kube::Error::Api(e)) if e.code == CONFLICT => {
+                    debug!("Kubernetes secret was modified concurrently, retry.")
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
 
-                let mut annotations = BTreeMap::new();
-                annotations.insert("controlled-by".to_string(), "wirepact-k8s-pki".to_string());
-                secret.metadata.annotations = Some(annotations);
+        Err(format!(
+            "Could not store the Kubernetes secret '{}' due to concurrent modifications.",
+            self.secret_name
+        )
+        .into())
+    }
 
-                let mut data = BTreeMap::new();
-                data.insert(SECRET_SERIAL_NUMBER.to_string(), "0".to_string());
-                data.insert(SECRET_KEY.to_string(), "".to_string());
-                data.insert(SECRET_CERTIFICATE.to_string(), "".to_string());
-                secret.string_data = Some(data);
+    fn contains_authority(secret: &Secret) -> bool {
+        let data = secret.data.as_ref();
+        [SECRET_KEY, SECRET_CERTIFICATE].iter().all(|key| {
+            data.and_then(|data| data.get(*key))
+                .is_some_and(|value| !value.0.is_empty())
+        })
+    }
 
-                let secret = secrets.create(&PostParams::default(), &secret).await?;
-                Ok(secret)
+    fn read_authorities(&self, secret: &Secret) -> Result<Authorities, Box<dyn Error>> {
+        let data = secret.data.clone().unwrap_or_default();
+        let value = |key: &str| {
+            data.get(key)
+                .map(|value| value.0.as_slice())
+                .unwrap_or_default()
+        };
+        let retired = Self::parse_list::<StoredAuthority>(secret, SECRET_RETIRED_AUTHORITIES)?;
+        Ok(Authorities {
+            active: CertificateAuthority {
+                cert: X509::from_pem(value(SECRET_CERTIFICATE))?,
+                key: self.key_storage.decode(value(SECRET_KEY))?,
+                chain: parse_certificates(value(SECRET_CHAIN))?,
+            },
+            retired: Authorities::parse_retired(retired.as_slice(), &self.key_storage)?,
+        })
+    }
+
+    /// Load the CAs of the changed secret. The CAs are only parsed if their data
+    /// changed. The in-memory CAs are swapped at once, thus a signing operation
+    /// never sees a partial CA. Returns a message if the CAs were changed.
+    fn reload(&self, secret: &Secret) -> Option<String> {
+        let data = secret.data.clone().unwrap_or_default();
+        let digest = match authorities_digest(&data) {
+            Ok(digest) => digest,
+            Err(e) => {
+                error!("Could not hash the CA of the Kubernetes secret: {}", e);
+                return None;
             }
+        };
+        let mut last_digest = self.authorities_digest.lock().unwrap();
+        if last_digest.as_ref() == Some(&digest) {
+            return None;
         }
+
+        let authorities = match self.read_authorities(secret) {
+            Ok(authorities) => authorities,
+            Err(e) => {
+                error!("Could not load the CA from the Kubernetes secret: {}", e);
+                return None;
+            }
+        };
+        *last_digest = Some(digest);
+        let mut current = self.authorities.write().unwrap();
+        if current
+            .as_ref()
+            .is_some_and(|current| current.same_certificates(&authorities))
+        {
+            return None;
+        }
+
+        let message = format!(
+            "Reloaded the CA from the Kubernetes secret '{}': active CA with serial number {} \
+             valid until {}, {} retired CA(s).",
+            self.secret_name,
+            authorities
+                .active
+                .cert
+                .serial_number()
+                .to_bn()
+                .and_then(|serial| serial.to_hex_str())
+                .map(|serial| serial.to_string())
+                .unwrap_or_default(),
+            authorities.active.cert.not_after(),
+            authorities.retired.len()
+        );
+        info!("{}", message);
+        *current = Some(Arc::new(authorities));
+        self.reloads.send_modify(|count| *count += 1);
+        Some(message)
     }
 
-    async fn store_secret(&self, secret: &Secret) -> Result<(), Box<dyn Error>> {
-        debug!("Store Kubernetes secret.");
+    /// Record a Kubernetes event for the reload of the CA on the secret.
+    async fn record_reload_event(&self, secret: &Secret, message: String) {
+        let now = Time(Utc::now());
+        let event = Event {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("{}-", self.secret_name)),
+                ..ObjectMeta::default()
+            },
+            involved_object: ObjectReference {
+                api_version: Some("v1".to_string()),
+                kind: Some("Secret".to_string()),
+                name: secret.metadata.name.clone(),
+                namespace: secret.metadata.namespace.clone(),
+                uid: secret.metadata.uid.clone(),
+                resource_version: secret.metadata.resource_version.clone(),
+                ..ObjectReference::default()
+            },
+            reason: Some("CAReloaded".to_string()),
+            message: Some(message),
+            type_: Some("Normal".to_string()),
+            source: Some(EventSource {
+                component: Some("wirepact-k8s-pki".to_string()),
+                ..EventSource::default()
+            }),
+            first_timestamp: Some(now.clone()),
+            last_timestamp: Some(now),
+            count: Some(1),
+            ..Event::default()
+        };
 
-        let client = Client::try_default().await?;
-        let secrets: Api<Secret> =
-            Api::namespaced(client, self.current_namespace().await?.as_str());
+        let events: Api<Event> = self.kubernetes.api();
+        if let Err(e) = events.create(&PostParams::default(), &event).await {
+            warn!("Could not record the reload event: {}", e);
+        }
+    }
 
-        secrets
-            .replace(self.secret_name.as_str(), &PostParams::default(), secret)
-            .await?;
+    fn write_authorities(
+        &self,
+        data: &mut BTreeMap<String, ByteString>,
+        authorities: &Authorities,
+    ) -> Result<(), Box<dyn Error>> {
+        let key = self.key_storage.encode(authorities.active.key.as_ref())?;
+        let cert = authorities.active.cert.to_pem()?;
+        let chain = certificates_to_pem(authorities.active.chain.as_slice())?;
+        let retired = serde_json::to_vec(&authorities.stored_retired(&self.key_storage)?)?;
+        data.insert(SECRET_KEY.to_string(), ByteString(key));
+        data.insert(SECRET_CERTIFICATE.to_string(), ByteString(cert));
+        data.insert(SECRET_CHAIN.to_string(), ByteString(chain));
+        data.insert(SECRET_RETIRED_AUTHORITIES.to_string(), ByteString(retired));
         Ok(())
     }
 
-    async fn load_key(&self) -> Result<Option<PKey<Private>>, Box<dyn Error>> {
-        debug!("Load CA private key from Kubernetes secret.");
-
-        let secret = self.load_secret().await?;
-        Ok(match secret.data.unwrap().get(SECRET_KEY) {
-            None => None,
-            Some(data) => match PKey::private_key_from_pem(data.0.as_slice()) {
-                Ok(key) => Some(key),
-                Err(_) => None,
-            },
+    fn parse_list<T: DeserializeOwned>(
+        secret: &Secret,
+        key: &str,
+    ) -> Result<Vec<T>, Box<dyn Error>> {
+        Ok(match secret.data.as_ref().and_then(|data| data.get(key)) {
+            None => Vec::new(),
+            Some(data) => serde_json::from_slice(data.0.as_slice())?,
         })
     }
 
-    async fn store_key(&self, key: &PKeyRef<Private>) -> Result<(), Box<dyn Error>> {
-        debug!("Store CA private key to Kubernetes secret.");
+    fn config_maps(&self) -> Api<ConfigMap> {
+        self.kubernetes.api()
+    }
 
-        let mut secret = self.load_secret().await?;
-        if let Some(mut data) = secret.data {
-            *data.entry(SECRET_KEY.to_string()).or_default() =
-                ByteString(key.private_key_to_pem_pkcs8()?);
-            secret.data = Some(data);
+    fn records_selector(&self) -> String {
+        format!("{}={}", RECORDS_LABEL, self.secret_name)
+    }
+
+    /// Merge the patch into the record ConfigMap, which is created if it does not
+    /// exist. A merge patch only touches the given keys, thus concurrent writes of
+    /// other records are not lost and the ConfigMap is not loaded before.
+    async fn patch_records(&self, name: &str, patch: Value) -> Result<(), Box<dyn Error>> {
+        let config_maps = self.config_maps();
+        let patch = Patch::Merge(patch);
+        match config_maps
+            .patch(name, &PatchParams::default(), &patch)
+            .await
+        {
+            Err(kube::Error::Api(e)) if e.code == NOT_FOUND => {}
+            result => return result.map(|_| ()).map_err(|e| e.into()),
         }
 
-        self.store_secret(&secret).await?;
+        info!("Kubernetes ConfigMap '{}' does not exist, create it.", name);
+        let config_map = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                labels: Some(BTreeMap::from([(
+                    RECORDS_LABEL.to_string(),
+                    self.secret_name.clone(),
+                )])),
+                annotations: Some(BTreeMap::from([(
+                    "controlled-by".to_string(),
+                    "wirepact-k8s-pki".to_string(),
+                )])),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        match config_maps
+            .create(&PostParams::default(), &config_map)
+            .await
+        {
+            Ok(_) => {}
+            Err(kube::Error::Api(e)) if e.code == CONFLICT => {}
+            Err(e) => return Err(e.into()),
+        }
+        config_maps
+            .patch(name, &PatchParams::default(), &patch)
+            .await?;
         Ok(())
     }
 
-    async fn load_cert(&self) -> Result<Option<X509>, Box<dyn Error>> {
-        debug!("Load CA certificate from Kubernetes secret.");
+    /// Read from the records of the watch or, if the watch did not list
+    /// the record ConfigMaps yet, from the current ConfigMaps.
+    async fn read_records<F, T>(&self, read: F) -> Result<T, Box<dyn Error>>
+    where
+        F: Fn(&RecordCache) -> T + Send,
+        T: Send,
+    {
+        if let Some(records) = self.records.read().unwrap().as_ref() {
+            return Ok(read(records));
+        }
 
-        let secret = self.load_secret().await?;
-        Ok(match secret.data.unwrap().get(SECRET_CERTIFICATE) {
-            None => None,
-            Some(data) => match X509::from_pem(data.0.as_slice()) {
-                Ok(cert) => Some(cert),
-                Err(_) => None,
-            },
-        })
+        debug!("Load the records from the Kubernetes ConfigMaps.");
+        let config_maps = self
+            .config_maps()
+            .list(&ListParams::default().labels(self.records_selector().as_str()))
+            .await?;
+        Ok(read(&self.record_cache(config_maps.items)))
     }
 
-    async fn store_cert(&self, cert: &X509Ref) -> Result<(), Box<dyn Error>> {
-        debug!("Store CA certificate to Kubernetes secret.");
+    /// Apply the change of the own records to the watched records,
+    /// thus they can be read before the watch sees the change.
+    fn update_records<F: FnOnce(&mut RecordCache)>(&self, update: F) {
+        if let Some(records) = self.records.write().unwrap().as_mut() {
+            update(records);
+        }
+    }
 
-        let mut secret = self.load_secret().await?;
-        if let Some(mut data) = secret.data {
-            *data.entry(SECRET_CERTIFICATE.to_string()).or_default() = ByteString(cert.to_pem()?);
-            secret.data = Some(data);
+    /// Apply the watched change of the record ConfigMap and notify
+    /// about changed revocations (e.g. by another replica).
+    fn apply_records(&self, name: &str, data: Option<&BTreeMap<String, String>>) {
+        let changed = match self.records.write().unwrap().as_mut() {
+            Some(records) => records.apply(name, data),
+            None => false,
+        };
+        if changed {
+            debug!("The revoked certificates were changed.");
+            self.revocations.send_modify(|count| *count += 1);
+        }
+    }
+
+    fn record_cache(&self, config_maps: Vec<ConfigMap>) -> RecordCache {
+        let mut records = RecordCache::new(self.secret_name.as_str());
+        for config_map in config_maps {
+            records.apply(
+                config_map.metadata.name.unwrap_or_default().as_str(),
+                config_map.data.as_ref(),
+            );
+        }
+        records
+    }
+
+    async fn watch_authorities(&self) {
+        let secrets = self.secrets();
+
+        debug!("Watch the Kubernetes secret '{}'.", self.secret_name);
+        let mut events = Box::pin(watch_object(secrets, self.secret_name.as_str()));
+        loop {
+            match events.try_next().await {
+                Ok(Some(Some(secret))) if Self::contains_authority(&secret) => {
+                    if let Some(message) = self.reload(&secret) {
+                        self.record_reload_event(&secret, message).await;
+                    }
+                }
+                Ok(Some(_)) => warn!("The Kubernetes secret does not contain a CA."),
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Error while watching the Kubernetes secret: {}", e);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Keep the records of the store up to date, thus OCSP and CRL
+    /// requests are served without a request to the Kubernetes API.
+    async fn watch_records(&self) {
+        debug!(
+            "Watch the Kubernetes ConfigMaps of the records ({}).",
+            self.records_selector()
+        );
+        let params = ListParams::default().labels(self.records_selector().as_str());
+        let mut events = Box::pin(watcher(self.config_maps(), params));
+        loop {
+            match events.try_next().await {
+                Ok(Some(watcher::Event::Restarted(config_maps))) => {
+                    let records = self.record_cache(config_maps);
+                    let revoked = records.revoked.clone();
+                    let previous = self.records.write().unwrap().replace(records);
+                    if previous.is_some_and(|previous| previous.revoked != revoked) {
+                        debug!("The revoked certificates were changed.");
+                        self.revocations.send_modify(|count| *count += 1);
+                    }
+                }
+                Ok(Some(watcher::Event::Applied(config_map))) => self.apply_records(
+                    config_map.metadata.name.as_deref().unwrap_or_default(),
+                    config_map.data.as_ref(),
+                ),
+                Ok(Some(watcher::Event::Deleted(config_map))) => self.apply_records(
+                    config_map.metadata.name.as_deref().unwrap_or_default(),
+                    None,
+                ),
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Error while watching the Kubernetes ConfigMaps: {}", e);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
         }
+    }
 
-        self.store_secret(&secret).await?;
+    /// Move the records that previous versions kept in the secret to the
+    /// record ConfigMaps and remove them from the secret.
+    async fn migrate_records(&self, secret: &Secret) -> Result<(), Box<dyn Error>> {
+        let data = secret.data.as_ref();
+        if ![
+            SECRET_REVOKED_CERTIFICATES,
+            SECRET_ISSUED_CERTIFICATES,
+            SECRET_CRL,
+        ]
+        .iter()
+        .any(|key| data.is_some_and(|data| data.contains_key(*key)))
+        {
+            return Ok(());
+        }
+
+        info!(
+            "Move the records of the Kubernetes secret '{}' to ConfigMaps.",
+            self.secret_name
+        );
+        let revoked = Self::parse_list::<RevokedCertificate>(secret, SECRET_REVOKED_CERTIFICATES)?;
+        if !revoked.is_empty() {
+            let mut patch = Map::new();
+            for certificate in revoked.iter() {
+                patch.insert(
+                    certificate.serial_number.clone(),
+                    Value::String(serde_json::to_string(certificate)?),
+                );
+            }
+            self.patch_records(
+                records_name(self.secret_name.as_str(), REVOCATIONS_SUFFIX).as_str(),
+                json!({ "data": patch }),
+            )
+            .await?;
+        }
+
+        let mut issued = Self::parse_list::<CertificateRecord>(secret, SECRET_ISSUED_CERTIFICATES)?;
+        prune_expired(&mut issued);
+        let mut shards: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+        for record in issued.iter() {
+            shards
+                .entry(inventory_shard(
+                    self.secret_name.as_str(),
+                    record.serial_number.as_str(),
+                ))
+                .or_default()
+                .insert(
+                    record.serial_number.clone(),
+                    Value::String(serde_json::to_string(record)?),
+                );
+        }
+        for (name, patch) in shards {
+            self.patch_records(name.as_str(), json!({ "data": patch }))
+                .await?;
+        }
+
+        self.update_secret(|data| {
+            let mut changed = false;
+            for key in [
+                SECRET_REVOKED_CERTIFICATES,
+                SECRET_ISSUED_CERTIFICATES,
+                SECRET_CRL,
+            ] {
+                changed |= data.remove(key).is_some();
+            }
+            Ok(changed)
+        })
+        .await?;
         Ok(())
     }
 }
 
 #[tonic::async_trait]
 impl CertificateStore for KubernetesStore {
-    async fn init(&mut self) -> Result<(), Box<dyn Error>> {
-        let key = self.load_key().await?;
-        let key = match key {
-            Some(key) => key,
-            None => {
-                info!("Key does not exist, create new.");
-                let new_key = create_new_key()?;
-                self.store_key(new_key.as_ref()).await?;
-                new_key
+    async fn init(
+        &mut self,
+        bootstrap: Option<CertificateAuthority>,
+    ) -> Result<(), Box<dyn Error>> {
+        let secrets = self.secrets();
+        let mut imported = false;
+        let secret = loop {
+            let existing = secrets.get_opt(self.secret_name.as_str()).await?;
+            if let Some(secret) = existing.as_ref().filter(|s| Self::contains_authority(s)) {
+                break secret.clone();
             }
-        };
 
-        let cert = self.load_cert().await?;
-        let cert = match cert {
-            Some(cert) => cert,
-            None => {
-                info!("CA certificate does not exist, create new.");
-                let certificate = create_new_ca(key.as_ref())?;
-                self.store_cert(certificate.as_ref()).await?;
-                certificate
+            // Only the leader creates the CA, the other replicas wait for it.
+            if !self.leader_election.is_leader() {
+                info!("CA does not exist, wait for the leader to create it.");
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+
+            let authorities = Authorities {
+                active: match bootstrap.clone() {
+                    Some(authority) => {
+                        info!("CA does not exist, import the given CA.");
+                        authority
+                    }
+                    None => {
+                        info!("CA does not exist, create new.");
+                        let key = self.key_storage.create_key(self.key_algorithm)?;
+                        CertificateAuthority {
+                            cert: create_new_ca(key.as_ref())?,
+                            key,
+                            chain: Vec::new(),
+                        }
+                    }
+                },
+                retired: Vec::new(),
+            };
+
+            // The key and the certificate are written together, thus no other replica
+            // can see a partial CA. Creating the secret fails if it already exists and
+            // replacing it fails if it was modified since it was loaded.
+            let result = match existing {
+                None => {
+                    info!(
+                        "Kubernetes secret '{}' does not exist, create it.",
+                        self.secret_name
+                    );
+                    let mut secret = Secret::default();
+                    secret.metadata.name = Some(self.secret_name.clone());
+                    secret.metadata.annotations = Some(BTreeMap::from([(
+                        "controlled-by".to_string(),
+                        "wirepact-k8s-pki".to_string(),
+                    )]));
+                    let mut data = BTreeMap::new();
+                    self.write_authorities(&mut data, &authorities)?;
+                    secret.data = Some(data);
+                    secrets.create(&PostParams::default(), &secret).await
+                }
+                Some(mut secret) => {
+                    let mut data = secret.data.take().unwrap_or_default();
+                    self.write_authorities(&mut data, &authorities)?;
+                    secret.data = Some(data);
+                    secrets
+                        .replace(self.secret_name.as_str(), &PostParams::default(), &secret)
+                        .await
+                }
+            };
+
+            match result {
+                Ok(secret) => {
+                    imported = bootstrap.is_some();
+                    break secret;
+                }
+                Err(kube::Error::Api(e)) if e.code == CONFLICT => {
+                    info!("Another replica stored a CA concurrently, use its CA.")
+                }
+                Err(e) => return Err(e.into()),
             }
         };
 
-        self.cert = Some(cert);
-        self.key = Some(key);
+        let authorities = self.read_authorities(&secret)?;
+        *self.authorities.get_mut().unwrap() = Some(Arc::new(authorities));
+        if self.leader_election.is_leader() {
+            self.migrate_records(&secret).await?;
+        }
+
+        if let Some(authority) = bootstrap.filter(|_| !imported) {
+            self.authorities().check_import(&authority)?;
+        }
 
         debug!("Initialized the Kubernetes secret storage.");
         Ok(())
     }
 
-    fn cert(&self) -> &X509 {
-        self.cert.as_ref().unwrap()
+    async fn revoke_certificate(
+        &self,
+        certificate: RevokedCertificate,
+    ) -> Result<bool, Box<dyn Error>> {
+        debug!("Store revoked certificate to Kubernetes ConfigMap.");
+
+        let name = records_name(self.secret_name.as_str(), REVOCATIONS_SUFFIX);
+        let revoked = self
+            .config_maps()
+            .get_opt(name.as_str())
+            .await?
+            .and_then(|config_map| config_map.data)
+            .is_some_and(|data| data.contains_key(certificate.serial_number.as_str()));
+        if revoked {
+            return Ok(false);
+        }
+
+        self.patch_records(
+            name.as_str(),
+            json!({ "data": { &certificate.serial_number: serde_json::to_string(&certificate)? } }),
+        )
+        .await?;
+        self.update_records(|records| {
+            records
+                .revoked
+                .insert(certificate.serial_number.clone(), certificate.clone());
+        });
+        Ok(true)
+    }
+
+    async fn revoked_certificates(&self) -> Result<Vec<RevokedCertificate>, Box<dyn Error>> {
+        self.read_records(|records| records.revoked.values().cloned().collect())
+            .await
+    }
+
+    async fn record_certificate(&self, record: CertificateRecord) -> Result<(), Box<dyn Error>> {
+        debug!("Store issued certificate to Kubernetes ConfigMap.");
+
+        // The expired certificates of the shard are removed with the same patch.
+        let name = inventory_shard(self.secret_name.as_str(), record.serial_number.as_str());
+        let mut patch = Map::new();
+        let expired = self
+            .records
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|records| records.expired(name.as_str()))
+            .unwrap_or_default();
+        if !expired.is_empty() {
+            debug!(
+                "Remove {} expired certificate(s) from the inventory.",
+                expired.len()
+            );
+        }
+        for serial_number in expired.iter() {
+            patch.insert(serial_number.clone(), Value::Null);
+        }
+        patch.insert(
+            record.serial_number.clone(),
+            Value::String(serde_json::to_string(&record)?),
+        );
+
+        self.patch_records(name.as_str(), json!({ "data": patch }))
+            .await?;
+        self.update_records(|records| {
+            let shard = records.issued.entry(name.clone()).or_default();
+            for serial_number in expired.iter() {
+                shard.remove(serial_number);
+            }
+            shard.insert(record.serial_number.clone(), record.clone());
+        });
+        Ok(())
+    }
+
+    async fn issued_certificates(&self) -> Result<Vec<CertificateRecord>, Box<dyn Error>> {
+        self.read_records(|records| records.issued()).await
+    }
+
+    async fn issued_certificate(
+        &self,
+        serial_number: &str,
+    ) -> Result<Option<CertificateRecord>, Box<dyn Error>> {
+        let name = inventory_shard(self.secret_name.as_str(), serial_number);
+        self.read_records(|records| {
+            records
+                .issued
+                .get(name.as_str())
+                .and_then(|shard| shard.get(serial_number))
+                .cloned()
+        })
+        .await
+    }
+
+    async fn watch(self: Arc<Self>) {
+        tokio::join!(self.watch_authorities(), self.watch_records());
+    }
+
+    async fn has_authority(&self) -> Result<bool, Box<dyn Error>> {
+        let secret = self.secrets().get_opt(self.secret_name.as_str()).await?;
+        Ok(secret.as_ref().is_some_and(Self::contains_authority))
+    }
+
+    async fn rewrap_keys(&mut self, encryption: KeyEncryption) -> Result<(), Box<dyn Error>> {
+        if !self.has_authority().await? {
+            return Err(format!(
+                "The Kubernetes secret '{}' does not contain a CA to re-wrap.",
+                self.secret_name
+            )
+            .into());
+        }
+        self.init(None).await?;
+
+        info!(
+            "Re-wrap the CA keys in the Kubernetes secret '{}'.",
+            self.secret_name
+        );
+        self.key_storage.encryption = encryption;
+        self.store_authorities(self.authorities().as_ref().clone())
+            .await
+    }
+
+    fn reloads(&self) -> watch::Receiver<u64> {
+        self.reloads.subscribe()
+    }
+
+    fn revocations(&self) -> watch::Receiver<u64> {
+        self.revocations.subscribe()
+    }
+
+    async fn store_crl(&self, crl: &[u8]) -> Result<(), Box<dyn Error>> {
+        debug!("Store CRL to Kubernetes ConfigMap.");
+
+        let crl = String::from_utf8(crl.to_vec())?;
+        self.patch_records(
+            records_name(self.secret_name.as_str(), CRL_SUFFIX).as_str(),
+            json!({ "data": { CONFIG_MAP_CRL: &crl } }),
+        )
+        .await?;
+        self.update_records(|records| records.crl = Some(crl.into_bytes()));
+        Ok(())
+    }
+
+    async fn stored_crl(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.read_records(|records| records.crl.clone()).await
+    }
+
+    fn authorities(&self) -> Arc<Authorities> {
+        self.authorities.read().unwrap().clone().unwrap()
+    }
+
+    async fn store_authorities(&self, authorities: Authorities) -> Result<(), Box<dyn Error>> {
+        debug!("Store CA certificates and keys to Kubernetes secret.");
+
+        self.update_secret(|data| {
+            // The CAs were read before the update, another replica (e.g. the previous
+            // leader) may have changed them since. Its change must not be overwritten.
+            if let Some(stored) = data.get(SECRET_CERTIFICATE) {
+                let stored = X509::from_pem(stored.0.as_slice())?;
+                if !authorities.succeeds(&stored)? {
+                    return Err(format!(
+                        "The CA in the Kubernetes secret '{}' was changed concurrently, \
+                         the update is aborted.",
+                        self.secret_name
+                    )
+                    .into());
+                }
+            }
+            self.write_authorities(data, &authorities)?;
+            Ok(true)
+        })
+        .await?;
+
+        *self.authorities.write().unwrap() = Some(Arc::new(authorities));
+        Ok(())
+    }
+}
+
+/// Return the name of the record ConfigMap with the suffix.
+fn records_name(secret_name: &str, suffix: &str) -> String {
+    format!("{}-{}", secret_name, suffix)
+}
+
+/// Return the name of the inventory ConfigMap that holds the certificate
+/// with the serial number (see [INVENTORY_SUFFIX]).
+fn inventory_shard(secret_name: &str, serial_number: &str) -> String {
+    let digit = serial_number
+        .chars()
+        .last()
+        .filter(|digit| digit.is_ascii_hexdigit())
+        .unwrap_or('0')
+        .to_ascii_lowercase();
+    format!("{}-{}-{}", secret_name, INVENTORY_SUFFIX, digit)
+}
+
+/// The records of the store (revocations, CRL and inventory) that are read
+/// from the data of the record ConfigMaps.
+#[derive(Debug)]
+struct RecordCache {
+    secret_name: String,
+    /// The revoked certificates by their serial number.
+    revoked: BTreeMap<String, RevokedCertificate>,
+    crl: Option<Vec<u8>>,
+    /// The issued certificates by their serial number by the name of their shard.
+    issued: BTreeMap<String, BTreeMap<String, CertificateRecord>>,
+}
+
+impl RecordCache {
+    fn new(secret_name: &str) -> Self {
+        Self {
+            secret_name: secret_name.to_string(),
+            revoked: BTreeMap::new(),
+            crl: None,
+            issued: BTreeMap::new(),
+        }
     }
 
-    fn key(&self) -> &PKey<Private> {
-        self.key.as_ref().unwrap()
+    /// Replace the records of the ConfigMap with its (changed) data, the data
+    /// is `None` if the ConfigMap was deleted. Returns whether the revoked
+    /// certificates were changed.
+    fn apply(&mut self, name: &str, data: Option<&BTreeMap<String, String>>) -> bool {
+        if name == records_name(self.secret_name.as_str(), REVOCATIONS_SUFFIX) {
+            let revoked = parse_records(name, data);
+            let changed = revoked != self.revoked;
+            self.revoked = revoked;
+            return changed;
+        } else if name == records_name(self.secret_name.as_str(), CRL_SUFFIX) {
+            self.crl = data
+                .and_then(|data| data.get(CONFIG_MAP_CRL))
+                .map(|crl| crl.as_bytes().to_vec());
+        } else if name
+            .starts_with(records_name(self.secret_name.as_str(), INVENTORY_SUFFIX).as_str())
+        {
+            match data {
+                Some(data) => {
+                    self.issued
+                        .insert(name.to_string(), parse_records(name, Some(data)));
+                }
+                None => {
+                    self.issued.remove(name);
+                }
+            }
+        }
+        false
+    }
+
+    /// Return all issued certificates in the order of their issuance.
+    fn issued(&self) -> Vec<CertificateRecord> {
+        let mut issued: Vec<CertificateRecord> = self
+            .issued
+            .values()
+            .flat_map(|shard| shard.values().cloned())
+            .collect();
+        issued.sort_by(|a, b| {
+            (a.not_before, &a.serial_number).cmp(&(b.not_before, &b.serial_number))
+        });
+        issued
+    }
+
+    /// Return the serial numbers of the expired certificates in the shard.
+    fn expired(&self, shard: &str) -> Vec<String> {
+        self.issued
+            .get(shard)
+            .map(|records| {
+                records
+                    .values()
+                    .filter(|record| record.is_expired())
+                    .map(|record| record.serial_number.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 }
 
-// TODO: Tests.
+/// Parse the JSON encoded records of the ConfigMap data by their key,
+/// invalid records are skipped.
+fn parse_records<T: DeserializeOwned>(
+    name: &str,
+    data: Option<&BTreeMap<String, String>>,
+) -> BTreeMap<String, T> {
+    let mut records = BTreeMap::new();
+    for (key, value) in data.into_iter().flatten() {
+        match serde_json::from_str(value) {
+            Ok(record) => {
+                records.insert(key.clone(), record);
+            }
+            Err(e) => warn!(
+                "Ignore the invalid record '{}' of the Kubernetes ConfigMap '{}': {}",
+                key, name, e
+            ),
+        }
+    }
+    records
+}
+
+/// Return a digest of the CA data (see [AUTHORITY_KEYS]) of the secret.
+fn authorities_digest(data: &BTreeMap<String, ByteString>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut values = Vec::new();
+    for key in AUTHORITY_KEYS {
+        let value = data
+            .get(key)
+            .map(|value| value.0.as_slice())
+            .unwrap_or_default();
+        // The length separates the values, thus moved bytes change the digest.
+        values.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        values.extend_from_slice(value);
+    }
+    Ok(hash(MessageDigest::sha256(), values.as_slice())?.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use k8s_openapi::ByteString;
+    use time::OffsetDateTime;
+
+    use crate::cert_store::inventory::CertificateRecord;
+    use crate::cert_store::kubernetes_store::{
+        authorities_digest, inventory_shard, RecordCache, SECRET_CERTIFICATE, SECRET_CHAIN,
+        SECRET_CRL, SECRET_ISSUED_CERTIFICATES, SECRET_KEY, SECRET_REVOKED_CERTIFICATES,
+    };
+    use crate::cert_store::revocation::{RevocationReason, RevokedCertificate};
+
+    fn secret_data(entries: &[(&str, &str)]) -> BTreeMap<String, ByteString> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), ByteString(value.as_bytes().to_vec())))
+            .collect()
+    }
+
+    fn record(serial_number: &str, not_before: i64, not_after: i64) -> CertificateRecord {
+        CertificateRecord {
+            serial_number: serial_number.to_string(),
+            subject: format!("CN={}", serial_number),
+            subject_alt_names: Vec::new(),
+            not_before,
+            not_after,
+            requester: "anonymous".to_string(),
+            profile: "default".to_string(),
+        }
+    }
+
+    fn config_map_data(records: &[CertificateRecord]) -> BTreeMap<String, String> {
+        records
+            .iter()
+            .map(|record| {
+                (
+                    record.serial_number.clone(),
+                    serde_json::to_string(record).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shard_the_inventory_by_serial_number() {
+        assert_eq!(inventory_shard("ca", "1A2B"), "ca-inventory-b");
+        assert_eq!(inventory_shard("ca", "70"), "ca-inventory-0");
+        assert_eq!(inventory_shard("ca", ""), "ca-inventory-0");
+    }
+
+    #[test]
+    fn read_the_records_of_the_config_maps() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut records = RecordCache::new("ca");
+
+        let revoked = RevokedCertificate {
+            serial_number: "1A".to_string(),
+            revoked_at: now,
+            reason: RevocationReason::KeyCompromise,
+        };
+        let mut revocations = BTreeMap::from([(
+            revoked.serial_number.clone(),
+            serde_json::to_string(&revoked).unwrap(),
+        )]);
+        revocations.insert("2B".to_string(), "invalid".to_string());
+        assert!(records.apply("ca-revocations", Some(&revocations)));
+        assert!(!records.apply("ca-revocations", Some(&revocations)));
+        assert!(!records.apply(
+            "ca-crl",
+            Some(&BTreeMap::from([("crl".to_string(), "{}".to_string())])),
+        ));
+        records.apply(
+            "ca-inventory-a",
+            Some(&config_map_data(&[
+                record("1A", now - 60, now + 3600),
+                record("2A", now - 7200, now - 3600),
+            ])),
+        );
+        records.apply(
+            "ca-inventory-b",
+            Some(&config_map_data(&[record("1B", now - 120, now + 3600)])),
+        );
+        // The records of other stores are ignored.
+        records.apply(
+            "other-inventory-c",
+            Some(&config_map_data(&[record("1C", now, now + 3600)])),
+        );
+
+        assert_eq!(records.revoked.values().collect::<Vec<_>>(), vec![&revoked]);
+        assert_eq!(records.crl, Some(b"{}".to_vec()));
+        let serial_numbers = |records: &RecordCache| {
+            records
+                .issued()
+                .into_iter()
+                .map(|record| record.serial_number)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(serial_numbers(&records), vec!["2A", "1B", "1A"]);
+        assert_eq!(records.expired("ca-inventory-a"), vec!["2A"]);
+        assert!(records.expired("ca-inventory-b").is_empty());
+
+        records.apply("ca-inventory-a", None);
+        records.apply("ca-crl", None);
+        assert_eq!(serial_numbers(&records), vec!["1B"]);
+        assert_eq!(records.crl, None);
+    }
+
+    #[test]
+    fn reload_only_when_the_authorities_change() {
+        let data = secret_data(&[(SECRET_KEY, "key"), (SECRET_CERTIFICATE, "cert")]);
+        let digest = authorities_digest(&data).unwrap();
+
+        let mut unrelated = data.clone();
+        unrelated.extend(secret_data(&[
+            (SECRET_CRL, "crl"),
+            (SECRET_REVOKED_CERTIFICATES, "[]"),
+            (SECRET_ISSUED_CERTIFICATES, "[]"),
+        ]));
+        assert_eq!(authorities_digest(&unrelated).unwrap(), digest);
+
+        let rotated = secret_data(&[(SECRET_KEY, "key"), (SECRET_CERTIFICATE, "new cert")]);
+        assert_ne!(authorities_digest(&rotated).unwrap(), digest);
+
+        let chained = secret_data(&[
+            (SECRET_KEY, "key"),
+            (SECRET_CERTIFICATE, "cert"),
+            (SECRET_CHAIN, "chain"),
+        ]);
+        assert_ne!(authorities_digest(&chained).unwrap(), digest);
+
+        let moved = secret_data(&[(SECRET_KEY, "keyc"), (SECRET_CERTIFICATE, "ert")]);
+        assert_ne!(authorities_digest(&moved).unwrap(), digest);
+    }
+}
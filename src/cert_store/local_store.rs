@@ -1,83 +1,485 @@
 use std::error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 use log::{debug, info};
-use openssl::pkey::{PKey, Private};
 use openssl::x509::X509;
-use tokio::fs::{create_dir_all, read_to_string, write};
+use serde::de::DeserializeOwned;
+use tokio::fs::{create_dir_all, read_to_string, remove_file, rename, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
 
-use crate::cert_store::store::CertificateStore;
-use crate::cert_store::utils::{create_new_ca, create_new_key};
+use crate::cert_store::inventory::CertificateRecord;
+use crate::cert_store::key_encryption::KeyEncryption;
+use crate::cert_store::registry::{StoreBuilder, StoreContext, StoreUrl};
+use crate::cert_store::revocation::RevokedCertificate;
+use crate::cert_store::signer::{KeyStorage, Signer};
+use crate::cert_store::store::{
+    Authorities, CertificateAuthority, CertificateStore, StoredAuthority,
+};
+use crate::cert_store::utils::{
+    certificates_to_pem, create_new_ca, parse_certificates, KeyAlgorithm,
+};
 
-const LOCAL_FILES_PATH: &str = "./ca";
-const LOCAL_KEY_PATH: &str = "./ca/ca.key";
-const LOCAL_CERT_PATH: &str = "./ca/ca.crt";
+const LOCAL_KEY_FILE: &str = "ca.key";
+const LOCAL_CERT_FILE: &str = "ca.crt";
+const LOCAL_CHAIN_FILE: &str = "chain.pem";
+const LOCAL_REVOKED_FILE: &str = "revoked.json";
+const LOCAL_ISSUED_FILE: &str = "issued.json";
+const LOCAL_RETIRED_FILE: &str = "retired.json";
 
-#[derive(Debug, Default)]
+/// File mode of the files with private keys, i.e. the key and the retired CAs
+/// (read and write for the owner only).
+#[cfg(unix)]
+const KEY_FILE_MODE: u32 = 0o600;
+
+/// The configuration of the local store (`file://<directory>`,
+/// e.g. `file:///var/lib/pki` or `file://./ca`).
+#[derive(Debug, Clone)]
+pub struct LocalStoreConfig {
+    pub directory: PathBuf,
+}
+
+impl StoreBuilder for LocalStoreConfig {
+    fn from_url(url: &StoreUrl) -> Result<Self, Box<dyn Error>> {
+        if url.location().is_empty() {
+            return Err("The local store requires a directory ('file://<directory>').".into());
+        }
+
+        Ok(Self {
+            directory: PathBuf::from(url.location()),
+        })
+    }
+
+    fn build(self, context: &StoreContext) -> Result<Box<dyn CertificateStore>, Box<dyn Error>> {
+        Ok(Box::new(LocalStore::new(
+            self.directory,
+            context.key_algorithm,
+            context.key_storage.clone(),
+        )))
+    }
+}
+
+#[derive(Debug)]
 pub struct LocalStore {
-    cert: Option<X509>,
-    key: Option<PKey<Private>>,
+    directory: PathBuf,
+    key_algorithm: KeyAlgorithm,
+    key_storage: KeyStorage,
+    authorities: RwLock<Option<Arc<Authorities>>>,
+    /// Serializes the read-modify-write cycles of the JSON files.
+    write_lock: Mutex<()>,
 }
 
 impl LocalStore {
-    async fn load_key(&self) -> Result<PKey<Private>, Box<dyn Error>> {
+    pub fn new(directory: PathBuf, key_algorithm: KeyAlgorithm, key_storage: KeyStorage) -> Self {
+        Self {
+            directory,
+            key_algorithm,
+            key_storage,
+            authorities: RwLock::new(None),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.directory.join(file)
+    }
+
+    /// Write the file atomically: the content is written to a temporary file
+    /// which then replaces the file. Private files are only accessible by the owner.
+    /// A stale temporary file (e.g. of a crash) is removed, thus the temporary file
+    /// is always created with the mode of the file.
+    async fn write_file(
+        &self,
+        file: &str,
+        content: &[u8],
+        private: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = self.path(file);
+        let temp_path = self.path(format!(".{}.tmp", file).as_str());
+
+        match remove_file(&temp_path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if private {
+            options.mode(KEY_FILE_MODE);
+        }
+        #[cfg(not(unix))]
+        let _ = private;
+
+        let mut temp_file = options.open(&temp_path).await?;
+        temp_file.write_all(content).await?;
+        temp_file.sync_all().await?;
+        drop(temp_file);
+        rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    /// Refuse to use a private key that is accessible by the group or others.
+    #[cfg(unix)]
+    fn check_key_permissions(path: &Path) -> Result<(), Box<dyn Error>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(format!(
+                "The file '{}' holds CA keys and is accessible by other users (mode {:o}), \
+                 restrict it to {:o}.",
+                path.display(),
+                mode & 0o777,
+                KEY_FILE_MODE
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check_key_permissions(_path: &Path) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn load_key(&self) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
         debug!("Load CA private key from local file path.");
-        let path = Path::new(LOCAL_KEY_PATH);
+        let path = self.path(LOCAL_KEY_FILE);
+        Self::check_key_permissions(&path)?;
         let content = read_to_string(path).await?;
-        let key = PKey::private_key_from_pem(content.as_bytes())?;
-        Ok(key)
+        self.key_storage.decode(content.as_bytes())
     }
 
     async fn load_cert(&self) -> Result<X509, Box<dyn Error>> {
         debug!("Load CA certificate from local file path.");
-        let path = Path::new(LOCAL_CERT_PATH);
+        let path = self.path(LOCAL_CERT_FILE);
         let content = read_to_string(path).await?;
         let cert = X509::from_pem(content.as_bytes())?;
         Ok(cert)
     }
+
+    async fn load_chain(&self) -> Result<Vec<X509>, Box<dyn Error>> {
+        debug!("Load CA chain from local file path.");
+        let path = self.path(LOCAL_CHAIN_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = read_to_string(path).await?;
+        parse_certificates(content.as_bytes())
+    }
+
+    async fn load_list<T: DeserializeOwned>(&self, file: &str) -> Result<Vec<T>, Box<dyn Error>> {
+        debug!("Load {} from local file path.", file);
+        let path = self.path(file);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = read_to_string(path).await?;
+        let list = serde_json::from_str(content.as_str())?;
+        Ok(list)
+    }
 }
 
 #[tonic::async_trait]
 impl CertificateStore for LocalStore {
-    async fn init(&mut self) -> Result<(), Box<dyn Error>> {
-        create_dir_all(LOCAL_FILES_PATH).await?;
-
-        let key = match Path::new(LOCAL_KEY_PATH).exists() {
-            true => self.load_key().await?,
-            false => {
-                info!("Key does not exist, create new.");
-                let new_key = create_new_key()?;
-                let key_path = Path::new(LOCAL_KEY_PATH);
-                write(key_path, new_key.private_key_to_pem_pkcs8()?).await?;
-                new_key
+    async fn init(
+        &mut self,
+        bootstrap: Option<CertificateAuthority>,
+    ) -> Result<(), Box<dyn Error>> {
+        create_dir_all(&self.directory).await?;
+        info!("Use the local directory '{}'.", self.directory.display());
+
+        let bootstrap = match bootstrap {
+            Some(authority)
+                if !self.path(LOCAL_KEY_FILE).exists() && !self.path(LOCAL_CERT_FILE).exists() =>
+            {
+                info!("CA does not exist, import the given CA.");
+                self.store_authorities(Authorities {
+                    active: authority,
+                    retired: Vec::new(),
+                })
+                .await?;
+                None
             }
+            bootstrap => bootstrap,
         };
 
-        let cert = match Path::new(LOCAL_CERT_PATH).exists() {
-            true => self.load_cert().await?,
-            false => {
-                info!("CA certificate does not exist, create new.");
-                let certificate = create_new_ca(key.as_ref())?;
-                let cert_path = Path::new(LOCAL_CERT_PATH);
-                write(cert_path, certificate.to_pem()?).await?;
-                certificate
+        // The key and the certificate are written one after the other, thus a crash
+        // (or a manual change) could leave a CA that cannot sign valid certificates.
+        let (key, cert) = match (
+            self.path(LOCAL_KEY_FILE).exists(),
+            self.path(LOCAL_CERT_FILE).exists(),
+        ) {
+            (true, true) => {
+                let key = self.load_key().await?;
+                let cert = self.load_cert().await?;
+                if !cert.public_key()?.public_eq(key.public_key()?.as_ref()) {
+                    return Err(format!(
+                        "The CA key '{}' does not match the CA certificate '{}'.",
+                        self.path(LOCAL_KEY_FILE).display(),
+                        self.path(LOCAL_CERT_FILE).display()
+                    )
+                    .into());
+                }
+                (key, cert)
+            }
+            (false, false) => {
+                info!("CA does not exist, create new.");
+                let key = self.key_storage.create_key(self.key_algorithm)?;
+                let cert = create_new_ca(key.as_ref())?;
+                let encoded = self.key_storage.encode(key.as_ref())?;
+                self.write_file(LOCAL_KEY_FILE, encoded.as_slice(), true)
+                    .await?;
+                self.write_file(LOCAL_CERT_FILE, cert.to_pem()?.as_slice(), false)
+                    .await?;
+                (key, cert)
+            }
+            (key_exists, _) => {
+                let (existing, missing) = match key_exists {
+                    true => (LOCAL_KEY_FILE, LOCAL_CERT_FILE),
+                    false => (LOCAL_CERT_FILE, LOCAL_KEY_FILE),
+                };
+                return Err(format!(
+                    "The local directory '{}' contains '{}' but not '{}', restore the missing \
+                     file or remove both to create a new CA.",
+                    self.directory.display(),
+                    existing,
+                    missing
+                )
+                .into());
             }
         };
 
-        self.cert = Some(cert);
-        self.key = Some(key);
+        if self.path(LOCAL_RETIRED_FILE).exists() {
+            Self::check_key_permissions(&self.path(LOCAL_RETIRED_FILE))?;
+        }
+        let retired: Vec<StoredAuthority> = self.load_list(LOCAL_RETIRED_FILE).await?;
+        *self.authorities.get_mut().unwrap() = Some(Arc::new(Authorities {
+            active: CertificateAuthority {
+                cert,
+                key,
+                chain: self.load_chain().await?,
+            },
+            retired: Authorities::parse_retired(retired.as_slice(), &self.key_storage)?,
+        }));
+
+        if let Some(authority) = bootstrap {
+            self.authorities().check_import(&authority)?;
+        }
 
         debug!("Initialized the local storage.");
         Ok(())
     }
 
-    fn cert(&self) -> &X509 {
-        self.cert.as_ref().unwrap()
+    async fn revoke_certificate(
+        &self,
+        certificate: RevokedCertificate,
+    ) -> Result<bool, Box<dyn Error>> {
+        let _lock = self.write_lock.lock().await;
+
+        let mut revoked: Vec<RevokedCertificate> = self.load_list(LOCAL_REVOKED_FILE).await?;
+        if revoked
+            .iter()
+            .any(|r| r.serial_number == certificate.serial_number)
+        {
+            return Ok(false);
+        }
+
+        debug!("Store revoked certificate to local file path.");
+        revoked.push(certificate);
+        self.write_file(
+            LOCAL_REVOKED_FILE,
+            serde_json::to_vec_pretty(&revoked)?.as_slice(),
+            false,
+        )
+        .await?;
+        Ok(true)
+    }
+
+    async fn revoked_certificates(&self) -> Result<Vec<RevokedCertificate>, Box<dyn Error>> {
+        self.load_list(LOCAL_REVOKED_FILE).await
+    }
+
+    async fn record_certificate(&self, record: CertificateRecord) -> Result<(), Box<dyn Error>> {
+        let _lock = self.write_lock.lock().await;
+
+        let mut issued: Vec<CertificateRecord> = self.load_list(LOCAL_ISSUED_FILE).await?;
+        debug!("Store issued certificate to local file path.");
+        issued.push(record);
+        self.write_file(
+            LOCAL_ISSUED_FILE,
+            serde_json::to_vec_pretty(&issued)?.as_slice(),
+            false,
+        )
+        .await?;
+        Ok(())
     }
 
-    fn key(&self) -> &PKey<Private> {
-        self.key.as_ref().unwrap()
+    async fn issued_certificates(&self) -> Result<Vec<CertificateRecord>, Box<dyn Error>> {
+        self.load_list(LOCAL_ISSUED_FILE).await
+    }
+
+    async fn has_authority(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.path(LOCAL_KEY_FILE).exists() && self.path(LOCAL_CERT_FILE).exists())
+    }
+
+    async fn rewrap_keys(&mut self, encryption: KeyEncryption) -> Result<(), Box<dyn Error>> {
+        if !self.has_authority().await? {
+            return Err(format!(
+                "The local directory '{}' does not contain a CA to re-wrap.",
+                self.directory.display()
+            )
+            .into());
+        }
+        self.init(None).await?;
+
+        info!("Re-wrap the CA keys in the local directory.");
+        self.key_storage.encryption = encryption;
+        self.store_authorities(self.authorities().as_ref().clone())
+            .await
+    }
+
+    fn authorities(&self) -> Arc<Authorities> {
+        self.authorities.read().unwrap().clone().unwrap()
+    }
+
+    async fn store_authorities(&self, authorities: Authorities) -> Result<(), Box<dyn Error>> {
+        let _lock = self.write_lock.lock().await;
+
+        debug!("Store CA certificates and keys to local file path.");
+        let retired = serde_json::to_vec_pretty(&authorities.stored_retired(&self.key_storage)?)?;
+        let key = self.key_storage.encode(authorities.active.key.as_ref())?;
+        let cert = authorities.active.cert.to_pem()?;
+        let chain = certificates_to_pem(authorities.active.chain.as_slice())?;
+        // The retired CAs are written first, thus the previous CA is never lost.
+        self.write_file(LOCAL_RETIRED_FILE, retired.as_slice(), true)
+            .await?;
+        self.write_file(LOCAL_KEY_FILE, key.as_slice(), true)
+            .await?;
+        self.write_file(LOCAL_CERT_FILE, cert.as_slice(), false)
+            .await?;
+        self.write_file(LOCAL_CHAIN_FILE, chain.as_slice(), false)
+            .await?;
+
+        *self.authorities.write().unwrap() = Some(Arc::new(authorities));
+        Ok(())
     }
 }
 
-// TODO: Tests.
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::cert_store::local_store::{
+        LocalStore, LOCAL_CERT_FILE, LOCAL_KEY_FILE, LOCAL_RETIRED_FILE,
+    };
+    use crate::cert_store::signer::KeyStorage;
+    use crate::cert_store::store::{Authorities, CertificateAuthority, CertificateStore};
+    use crate::cert_store::utils::{create_new_ca, create_new_key, KeyAlgorithm};
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "k8s-pki-local-store-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn authority() -> CertificateAuthority {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        CertificateAuthority {
+            cert: create_new_ca(&key).unwrap(),
+            key: Arc::new(key),
+            chain: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_an_incomplete_or_mismatching_ca() {
+        let directory = directory("mismatch");
+        let store = || {
+            LocalStore::new(
+                directory.clone(),
+                KeyAlgorithm::EcdsaP256,
+                KeyStorage::default(),
+            )
+        };
+        store().init(None).await.unwrap();
+        let key = std::fs::read(directory.join(LOCAL_KEY_FILE)).unwrap();
+
+        std::fs::remove_file(directory.join(LOCAL_KEY_FILE)).unwrap();
+        assert!(store().init(None).await.is_err());
+        assert!(!directory.join(LOCAL_KEY_FILE).exists());
+
+        // The key of another CA does not match the certificate.
+        let mut other = LocalStore::new(
+            directory.join("other"),
+            KeyAlgorithm::EcdsaP256,
+            KeyStorage::default(),
+        );
+        other.init(None).await.unwrap();
+        std::fs::rename(
+            directory.join("other").join(LOCAL_KEY_FILE),
+            directory.join(LOCAL_KEY_FILE),
+        )
+        .unwrap();
+        assert!(store().init(None).await.is_err());
+
+        std::fs::write(directory.join(LOCAL_KEY_FILE), key).unwrap();
+        std::fs::remove_file(directory.join(LOCAL_CERT_FILE)).unwrap();
+        assert!(store().init(None).await.is_err());
+        assert!(!directory.join(LOCAL_CERT_FILE).exists());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn store_the_keys_of_retired_cas_privately() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = directory("retired");
+        let mut store = LocalStore::new(
+            directory.clone(),
+            KeyAlgorithm::EcdsaP256,
+            KeyStorage::default(),
+        );
+        store.init(None).await.unwrap();
+        // A stale temporary file with a wider mode must not be reused.
+        std::fs::write(directory.join(format!(".{}.tmp", LOCAL_RETIRED_FILE)), b"").unwrap();
+        let retired = store.authorities().active.clone();
+        store
+            .store_authorities(Authorities {
+                active: authority(),
+                retired: vec![retired],
+            })
+            .await
+            .unwrap();
+
+        for file in [LOCAL_KEY_FILE, LOCAL_RETIRED_FILE] {
+            let mode = std::fs::metadata(directory.join(file))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", file);
+        }
+
+        let path = directory.join(LOCAL_RETIRED_FILE);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let mut reopened = LocalStore::new(
+            directory.clone(),
+            KeyAlgorithm::EcdsaP256,
+            KeyStorage::default(),
+        );
+        assert!(reopened.init(None).await.is_err());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
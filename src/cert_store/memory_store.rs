@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+
+use log::{debug, info, warn};
+
+use crate::cert_store::inventory::CertificateRecord;
+use crate::cert_store::registry::{StoreBuilder, StoreContext, StoreUrl};
+use crate::cert_store::revocation::RevokedCertificate;
+use crate::cert_store::signer::KeyStorage;
+use crate::cert_store::store::{Authorities, CertificateAuthority, CertificateStore};
+use crate::cert_store::utils::{create_new_ca, KeyAlgorithm};
+
+/// The configuration of the in-memory store (`memory://`).
+#[derive(Debug, Clone)]
+pub struct InMemoryStoreConfig;
+
+impl StoreBuilder for InMemoryStoreConfig {
+    fn from_url(url: &StoreUrl) -> Result<Self, Box<dyn Error>> {
+        if !url.location().is_empty() {
+            return Err(format!("Invalid in-memory store '{}', use 'memory://'.", url).into());
+        }
+
+        Ok(Self)
+    }
+
+    fn build(self, context: &StoreContext) -> Result<Box<dyn CertificateStore>, Box<dyn Error>> {
+        Ok(Box::new(InMemoryStore::new(
+            context.key_algorithm,
+            context.key_storage.clone(),
+        )))
+    }
+}
+
+/// Keeps the PKI in memory only, thus all data (including the CA) is lost when
+/// the PKI stops. Used for tests and ephemeral environments (e.g. dev clusters).
+#[derive(Debug)]
+pub struct InMemoryStore {
+    key_algorithm: KeyAlgorithm,
+    key_storage: KeyStorage,
+    authorities: RwLock<Option<Arc<Authorities>>>,
+    revoked: Mutex<Vec<RevokedCertificate>>,
+    issued: Mutex<Vec<CertificateRecord>>,
+    crl: Mutex<Option<Vec<u8>>>,
+}
+
+impl InMemoryStore {
+    /// Create an empty store, the CA is created (or imported) on `init`.
+    pub fn new(key_algorithm: KeyAlgorithm, key_storage: KeyStorage) -> Self {
+        Self {
+            key_algorithm,
+            key_storage,
+            authorities: RwLock::new(None),
+            revoked: Mutex::new(Vec::new()),
+            issued: Mutex::new(Vec::new()),
+            crl: Mutex::new(None),
+        }
+    }
+
+    /// Create a store that is seeded with the given CA. The store
+    /// is ready to use, `init` keeps the CA.
+    pub fn with_authority(authority: CertificateAuthority) -> Self {
+        let store = Self::new(KeyAlgorithm::Rsa2048, KeyStorage::default());
+        *store.authorities.write().unwrap() = Some(Arc::new(Authorities {
+            active: authority,
+            retired: Vec::new(),
+        }));
+        store
+    }
+}
+
+#[tonic::async_trait]
+impl CertificateStore for InMemoryStore {
+    async fn init(
+        &mut self,
+        bootstrap: Option<CertificateAuthority>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(authorities) = self.authorities.get_mut().unwrap().as_ref() {
+            if let Some(authority) = bootstrap {
+                authorities.check_import(&authority)?;
+            }
+        } else {
+            let active = match bootstrap {
+                Some(authority) => {
+                    info!("Import the given CA.");
+                    authority
+                }
+                None => {
+                    info!("Create a new CA.");
+                    let key = self.key_storage.create_key(self.key_algorithm)?;
+                    CertificateAuthority {
+                        cert: create_new_ca(key.as_ref())?,
+                        key,
+                        chain: Vec::new(),
+                    }
+                }
+            };
+            *self.authorities.get_mut().unwrap() = Some(Arc::new(Authorities {
+                active,
+                retired: Vec::new(),
+            }));
+        }
+
+        warn!("The PKI is only stored in memory, the CA and all certificates are lost on restart.");
+        debug!("Initialized the in-memory storage.");
+        Ok(())
+    }
+
+    async fn has_authority(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.authorities.read().unwrap().is_some())
+    }
+
+    fn authorities(&self) -> Arc<Authorities> {
+        self.authorities.read().unwrap().clone().unwrap()
+    }
+
+    async fn store_authorities(&self, authorities: Authorities) -> Result<(), Box<dyn Error>> {
+        *self.authorities.write().unwrap() = Some(Arc::new(authorities));
+        Ok(())
+    }
+
+    async fn revoke_certificate(
+        &self,
+        certificate: RevokedCertificate,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut revoked = self.revoked.lock().unwrap();
+        if revoked
+            .iter()
+            .any(|r| r.serial_number == certificate.serial_number)
+        {
+            return Ok(false);
+        }
+
+        revoked.push(certificate);
+        Ok(true)
+    }
+
+    async fn revoked_certificates(&self) -> Result<Vec<RevokedCertificate>, Box<dyn Error>> {
+        Ok(self.revoked.lock().unwrap().clone())
+    }
+
+    async fn record_certificate(&self, record: CertificateRecord) -> Result<(), Box<dyn Error>> {
+        self.issued.lock().unwrap().push(record);
+        Ok(())
+    }
+
+    async fn issued_certificates(&self) -> Result<Vec<CertificateRecord>, Box<dyn Error>> {
+        Ok(self.issued.lock().unwrap().clone())
+    }
+
+    async fn store_crl(&self, crl: &[u8]) -> Result<(), Box<dyn Error>> {
+        *self.crl.lock().unwrap() = Some(crl.to_vec());
+        Ok(())
+    }
+
+    async fn stored_crl(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.crl.lock().unwrap().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::cert_store::memory_store::InMemoryStore;
+    use crate::cert_store::signer::KeyStorage;
+    use crate::cert_store::store::{CertificateAuthority, CertificateStore};
+    use crate::cert_store::utils::{create_new_ca, create_new_key, KeyAlgorithm};
+
+    fn authority() -> CertificateAuthority {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        CertificateAuthority {
+            cert: create_new_ca(&key).unwrap(),
+            key: Arc::new(key),
+            chain: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn import_a_ca_only_into_an_empty_store() {
+        let imported = authority();
+        let mut store = InMemoryStore::new(KeyAlgorithm::EcdsaP256, KeyStorage::default());
+        store.init(Some(imported.clone())).await.unwrap();
+        assert_eq!(store.cert(), imported.cert);
+
+        // The same CA may be configured again, e.g. after a restart.
+        store.init(Some(imported.clone())).await.unwrap();
+        assert!(store.init(Some(authority())).await.is_err());
+        assert_eq!(store.cert(), imported.cert);
+    }
+}
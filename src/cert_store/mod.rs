@@ -1,20 +1,21 @@
-use local_store::LocalStore;
-use store::CertificateStore;
+pub use crate::cert_store::kubernetes_store::KubernetesContext;
+pub use crate::cert_store::registry::{StoreBuilder, StoreContext, StoreRegistry, StoreUrl};
+pub use crate::cert_store::store::CertificateStore;
 
-use crate::cert_store::kubernetes_store::KubernetesStore;
-
-mod kubernetes_store;
-mod local_store;
+mod der;
+pub mod import;
+pub mod intermediate;
+pub mod inventory;
+pub mod key_encryption;
+pub mod kubernetes_store;
+pub mod local_store;
+pub mod memory_store;
+pub mod ocsp;
+pub mod pkcs11;
+pub mod profile;
+pub mod registry;
+pub mod revocation;
+pub mod signer;
+pub mod sqlite_store;
 pub mod store;
-mod utils;
-
-pub fn create_store(local: bool, kubernetes_secret: String) -> Box<dyn CertificateStore> {
-    match local {
-        true => Box::new(LocalStore::default()),
-        false => {
-            let mut store = KubernetesStore::default();
-            store.secret_name = kubernetes_secret;
-            Box::new(store)
-        }
-    }
-}
+pub mod utils;
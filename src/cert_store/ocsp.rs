@@ -0,0 +1,491 @@
+use std::error::Error;
+
+use openssl::asn1::{Asn1Object, Asn1OctetString};
+use openssl::bn::BigNum;
+use openssl::hash::{hash, MessageDigest};
+use openssl::x509::{X509Extension, X509Ref};
+use time::OffsetDateTime;
+
+use crate::cert_store::der;
+use crate::cert_store::revocation::{RevocationReason, RevokedCertificate};
+use crate::cert_store::signer::Signer;
+
+/// The status of an OCSP response (RFC 6960, section 4.2.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OcspResponseStatus {
+    MalformedRequest = 1,
+    InternalError = 2,
+    Unauthorized = 6,
+}
+
+/// The status of a single certificate in an OCSP response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertificateStatus {
+    Good,
+    Revoked(RevokedCertificate),
+    Unknown,
+}
+
+/// Identifies a certificate in an OCSP request.
+#[derive(Clone)]
+pub struct CertId {
+    /// The DER encoding of the id, which is returned as is in the response.
+    raw: Vec<u8>,
+    hash_algorithm: MessageDigest,
+    issuer_name_hash: Vec<u8>,
+    issuer_key_hash: Vec<u8>,
+    /// The serial number in uppercase hex notation.
+    pub serial_number: String,
+}
+
+impl CertId {
+    fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let element = der::expect(data, der::TAG_SEQUENCE)?;
+        let fields = der::read_all(element.content)?;
+        if fields.len() != 4 {
+            return Err("Invalid CertID in OCSP request.".into());
+        }
+
+        let algorithm = der::read_all(fields[0].content)?;
+        let hash_algorithm = match algorithm.first() {
+            Some(oid) if oid.raw == der::oid(der::OID_SHA1).as_slice() => MessageDigest::sha1(),
+            Some(oid) if oid.raw == der::oid(der::OID_SHA256).as_slice() => MessageDigest::sha256(),
+            _ => return Err("Unsupported hash algorithm in OCSP request.".into()),
+        };
+
+        let issuer_name_hash = der::expect(fields[1].raw, der::TAG_OCTET_STRING)?;
+        let issuer_key_hash = der::expect(fields[2].raw, der::TAG_OCTET_STRING)?;
+        let serial_number = der::expect(fields[3].raw, der::TAG_INTEGER)?;
+        let serial_number = BigNum::from_slice(serial_number.content)?;
+
+        Ok(Self {
+            raw: element.raw.to_vec(),
+            hash_algorithm,
+            issuer_name_hash: issuer_name_hash.content.to_vec(),
+            issuer_key_hash: issuer_key_hash.content.to_vec(),
+            serial_number: serial_number.to_hex_str()?.to_string(),
+        })
+    }
+
+    /// Check if the id references a certificate that was issued by the given CA.
+    pub fn is_issued_by(&self, issuer: &X509Ref) -> Result<bool, Box<dyn Error>> {
+        let name_hash = hash(
+            self.hash_algorithm,
+            issuer.subject_name().to_der()?.as_slice(),
+        )?;
+        if *name_hash != *self.issuer_name_hash {
+            return Ok(false);
+        }
+
+        let public_key = issuer.public_key()?.public_key_to_der()?;
+        let public_key = der::expect(public_key.as_slice(), der::TAG_SEQUENCE)?;
+        let public_key = der::read_all(public_key.content)?;
+        let key_bits = match public_key.get(1) {
+            Some(bits) if bits.tag == der::TAG_BIT_STRING && !bits.content.is_empty() => {
+                &bits.content[1..]
+            }
+            _ => return Err("Invalid public key of the issuer.".into()),
+        };
+        let key_hash = hash(self.hash_algorithm, key_bits)?;
+
+        Ok(*key_hash == *self.issuer_key_hash)
+    }
+}
+
+/// A parsed OCSP request.
+#[derive(Clone)]
+pub struct OcspRequest {
+    pub cert_ids: Vec<CertId>,
+    /// The encoded nonce extension of the request that is echoed in the response.
+    nonce: Option<Vec<u8>>,
+}
+
+impl OcspRequest {
+    pub fn from_der(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let request = der::expect(data, der::TAG_SEQUENCE)?;
+        let request = der::read_all(request.content)?;
+        let tbs_request = match request.first() {
+            Some(tbs) if tbs.tag == der::TAG_SEQUENCE => tbs,
+            _ => return Err("Invalid OCSP request.".into()),
+        };
+
+        let mut cert_ids = Vec::new();
+        let mut nonce = None;
+        for field in der::read_all(tbs_request.content)? {
+            match field.tag {
+                // requestList
+                der::TAG_SEQUENCE => {
+                    for single_request in der::read_all(field.content)? {
+                        let single_request = der::read_all(single_request.content)?;
+                        match single_request.first() {
+                            Some(cert_id) => cert_ids.push(CertId::parse(cert_id.raw)?),
+                            None => return Err("Invalid OCSP request.".into()),
+                        }
+                    }
+                }
+                // requestExtensions [2]
+                0xa2 => {
+                    let extensions = der::expect(field.content, der::TAG_SEQUENCE)?;
+                    for extension in der::read_all(extensions.content)? {
+                        let parts = der::read_all(extension.content)?;
+                        if parts
+                            .first()
+                            .map(|oid| oid.raw == der::oid(der::OID_OCSP_NONCE).as_slice())
+                            .unwrap_or(false)
+                        {
+                            nonce = Some(extension.raw.to_vec());
+                        }
+                    }
+                }
+                // version [0] and requestorName [1]
+                _ => {}
+            }
+        }
+
+        if cert_ids.is_empty() {
+            return Err("The OCSP request contains no certificates.".into());
+        }
+
+        Ok(Self { cert_ids, nonce })
+    }
+}
+
+/// Create an OCSP response that only contains the (error) status.
+pub fn error_response(status: OcspResponseStatus) -> Vec<u8> {
+    der::sequence(&[der::enumerated(status as u8)])
+}
+
+/// Create a successful OCSP response with the status of the requested
+/// certificates. The response is signed with the given responder key. If the
+/// responder is not the CA itself (delegated responder), the responder
+/// certificate is embedded in the response.
+pub fn create_response(
+    request: &OcspRequest,
+    statuses: &[CertificateStatus],
+    responder_cert: &X509Ref,
+    responder_key: &dyn Signer,
+    delegated: bool,
+    this_update: OffsetDateTime,
+    next_update: OffsetDateTime,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut responses = Vec::new();
+    for (cert_id, status) in request.cert_ids.iter().zip(statuses.iter()) {
+        let status = match status {
+            CertificateStatus::Good => der::context(0, false, &[]),
+            CertificateStatus::Revoked(revoked) => {
+                let mut info = vec![der::generalized_time(OffsetDateTime::from_unix_timestamp(
+                    revoked.revoked_at,
+                )?)];
+                if revoked.reason != RevocationReason::Unspecified {
+                    info.push(der::context(
+                        0,
+                        true,
+                        der::enumerated(revoked.reason.code()).as_slice(),
+                    ));
+                }
+                der::context(1, true, info.concat().as_slice())
+            }
+            CertificateStatus::Unknown => der::context(2, false, &[]),
+        };
+
+        responses.push(der::sequence(&[
+            cert_id.raw.clone(),
+            status,
+            der::generalized_time(this_update),
+            der::context(0, true, der::generalized_time(next_update).as_slice()),
+        ]));
+    }
+
+    let mut response_data = vec![
+        der::context(1, true, responder_cert.subject_name().to_der()?.as_slice()),
+        der::generalized_time(this_update),
+        der::sequence(responses.as_slice()),
+    ];
+    if let Some(nonce) = request.nonce.as_ref() {
+        response_data.push(der::context(
+            1,
+            true,
+            der::sequence(std::slice::from_ref(nonce)).as_slice(),
+        ));
+    }
+    let response_data = der::sequence(response_data.as_slice());
+
+    let mut basic_response = vec![
+        response_data.clone(),
+        responder_key.signature_algorithm()?,
+        der::signature(responder_key, response_data.as_slice())?,
+    ];
+    if delegated {
+        basic_response.push(der::context(
+            0,
+            true,
+            der::sequence(&[responder_cert.to_der()?]).as_slice(),
+        ));
+    }
+
+    let response_bytes = der::sequence(&[
+        der::oid(der::OID_OCSP_BASIC),
+        der::octet_string(der::sequence(basic_response.as_slice()).as_slice()),
+    ]);
+
+    Ok(der::sequence(&[
+        der::enumerated(0),
+        der::context(0, true, response_bytes.as_slice()),
+    ]))
+}
+
+/// Create the authority information access extension that points
+/// to the OCSP responder with the given URL.
+pub fn authority_info_access(url: &str) -> Result<X509Extension, Box<dyn Error>> {
+    let access_description = der::sequence(&[der::oid(der::OID_OCSP), der::uri_name(url)]);
+    let value = der::sequence(&[access_description]);
+
+    Ok(X509Extension::new_from_der(
+        Asn1Object::from_str("1.3.6.1.5.5.7.1.1")?.as_ref(),
+        false,
+        Asn1OctetString::new_from_bytes(value.as_slice())?.as_ref(),
+    )?)
+}
+
+/// Create the `id-pkix-ocsp-nocheck` extension for delegated OCSP responders.
+pub fn ocsp_no_check() -> Result<X509Extension, Box<dyn Error>> {
+    Ok(X509Extension::new_from_der(
+        Asn1Object::from_str("1.3.6.1.5.5.7.48.1.5")?.as_ref(),
+        false,
+        Asn1OctetString::new_from_bytes(der::null().as_slice())?.as_ref(),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::ocsp::{
+        OcspCertId, OcspCertStatus, OcspFlag, OcspRequest as OpensslRequest, OcspResponse,
+        OcspResponseStatus as OpensslResponseStatus,
+    };
+    use openssl::pkey::{PKey, Private};
+    use openssl::stack::Stack;
+    use openssl::x509::extension::ExtendedKeyUsage;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509Name, X509};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::cert_store::ocsp::{
+        create_response, error_response, CertificateStatus, OcspRequest, OcspResponseStatus,
+    };
+    use crate::cert_store::revocation::{RevocationReason, RevokedCertificate};
+    use crate::cert_store::utils::{create_new_ca, create_new_key, signature_digest, KeyAlgorithm};
+
+    /// Issue a certificate with the given serial number (and extended key usage) by the CA.
+    fn issue(
+        ca: &X509,
+        ca_key: &PKey<Private>,
+        serial_number: &str,
+        extended_key_usage: Option<&mut ExtendedKeyUsage>,
+    ) -> (X509, PKey<Private>) {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, serial_number)
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial_number = BigNum::from_hex_str(serial_number).unwrap();
+        builder
+            .set_serial_number(serial_number.to_asn1_integer().unwrap().as_ref())
+            .unwrap();
+        builder.set_subject_name(name.as_ref()).unwrap();
+        builder.set_issuer_name(ca.subject_name()).unwrap();
+        builder.set_pubkey(key.as_ref()).unwrap();
+        builder
+            .set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+            .unwrap();
+        builder
+            .set_not_after(Asn1Time::days_from_now(1).unwrap().as_ref())
+            .unwrap();
+        if let Some(extended_key_usage) = extended_key_usage {
+            builder
+                .append_extension(extended_key_usage.build().unwrap())
+                .unwrap();
+        }
+        builder
+            .sign(ca_key.as_ref(), signature_digest(ca_key))
+            .unwrap();
+        (builder.build(), key)
+    }
+
+    fn request_der(digest: MessageDigest, ca: &X509, certificates: &[&X509]) -> Vec<u8> {
+        let mut request = OpensslRequest::new().unwrap();
+        for certificate in certificates {
+            request
+                .add_id(OcspCertId::from_cert(digest, certificate, ca).unwrap())
+                .unwrap();
+        }
+        request.to_der().unwrap()
+    }
+
+    #[test]
+    fn parse_request() {
+        let ca_key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let ca = create_new_ca(&ca_key).unwrap();
+        let other_key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let other_ca = create_new_ca(&other_key).unwrap();
+        let (first, _) = issue(&ca, &ca_key, "1F", None);
+        let (second, _) = issue(&ca, &ca_key, "ABCDEF0123", None);
+
+        for digest in [MessageDigest::sha1(), MessageDigest::sha256()] {
+            let der = request_der(digest, &ca, &[&first, &second]);
+            let request = OcspRequest::from_der(der.as_slice()).unwrap();
+
+            let serial_numbers: Vec<&str> = request
+                .cert_ids
+                .iter()
+                .map(|id| id.serial_number.as_str())
+                .collect();
+            assert_eq!(serial_numbers, vec!["1F", "ABCDEF0123"]);
+            for cert_id in request.cert_ids.iter() {
+                assert!(cert_id.is_issued_by(&ca).unwrap());
+                assert!(!cert_id.is_issued_by(&other_ca).unwrap());
+            }
+            assert!(request.nonce.is_none());
+        }
+    }
+
+    #[test]
+    fn reject_invalid_requests() {
+        let ca_key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let ca = create_new_ca(&ca_key).unwrap();
+        let (certificate, _) = issue(&ca, &ca_key, "01", None);
+
+        assert!(OcspRequest::from_der(b"not a request").is_err());
+        assert!(
+            OcspRequest::from_der(request_der(MessageDigest::sha1(), &ca, &[]).as_slice()).is_err()
+        );
+        let md5 = request_der(MessageDigest::md5(), &ca, &[&certificate]);
+        assert!(OcspRequest::from_der(md5.as_slice()).is_err());
+    }
+
+    #[test]
+    fn create_signed_response() {
+        let now = OffsetDateTime::now_utc();
+        for algorithm in [
+            KeyAlgorithm::Rsa2048,
+            KeyAlgorithm::EcdsaP384,
+            KeyAlgorithm::Ed25519,
+        ] {
+            let ca_key = create_new_key(algorithm).unwrap();
+            let ca = create_new_ca(&ca_key).unwrap();
+            let certificates = [
+                issue(&ca, &ca_key, "0A", None).0,
+                issue(&ca, &ca_key, "0B", None).0,
+                issue(&ca, &ca_key, "0C", None).0,
+            ];
+            let der = request_der(
+                MessageDigest::sha1(),
+                &ca,
+                &certificates.iter().collect::<Vec<_>>(),
+            );
+            let request = OcspRequest::from_der(der.as_slice()).unwrap();
+            let statuses = [
+                CertificateStatus::Good,
+                CertificateStatus::Revoked(RevokedCertificate {
+                    serial_number: "0B".to_string(),
+                    revoked_at: 1_650_000_000,
+                    reason: RevocationReason::KeyCompromise,
+                }),
+                CertificateStatus::Unknown,
+            ];
+
+            let response = create_response(
+                &request,
+                &statuses,
+                &ca,
+                &ca_key,
+                false,
+                now,
+                now + Duration::hours(1),
+            )
+            .unwrap();
+            let response = OcspResponse::from_der(response.as_slice()).unwrap();
+            assert_eq!(response.status(), OpensslResponseStatus::SUCCESSFUL);
+
+            // Responses of the CA do not embed the CA certificate.
+            let basic = response.basic().unwrap();
+            let mut signers = Stack::new().unwrap();
+            signers.push(ca.clone()).unwrap();
+            let mut store = X509StoreBuilder::new().unwrap();
+            store.add_cert(ca.clone()).unwrap();
+            basic
+                .verify(signers.as_ref(), &store.build(), OcspFlag::empty())
+                .unwrap();
+
+            let expected = [
+                OcspCertStatus::GOOD,
+                OcspCertStatus::REVOKED,
+                OcspCertStatus::UNKNOWN,
+            ];
+            for (certificate, expected) in certificates.iter().zip(expected) {
+                let id = OcspCertId::from_cert(MessageDigest::sha1(), certificate, &ca).unwrap();
+                let status = basic.find_status(&id).unwrap();
+                assert_eq!(status.status, expected);
+                status.check_validity(60, None).unwrap();
+                assert_eq!(
+                    status.revocation_time.is_some(),
+                    expected == OcspCertStatus::REVOKED
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn create_delegated_response() {
+        let now = OffsetDateTime::now_utc();
+        let ca_key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let ca = create_new_ca(&ca_key).unwrap();
+        let mut ocsp_signing = ExtendedKeyUsage::new();
+        ocsp_signing.other("OCSPSigning");
+        let (responder, responder_key) = issue(&ca, &ca_key, "FF01", Some(&mut ocsp_signing));
+        let (certificate, _) = issue(&ca, &ca_key, "0A", None);
+        let der = request_der(MessageDigest::sha1(), &ca, &[&certificate]);
+        let request = OcspRequest::from_der(der.as_slice()).unwrap();
+
+        let response = create_response(
+            &request,
+            &[CertificateStatus::Good],
+            &responder,
+            &responder_key,
+            true,
+            now,
+            now + Duration::hours(1),
+        )
+        .unwrap();
+        let response = OcspResponse::from_der(response.as_slice()).unwrap();
+
+        let basic = response.basic().unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(ca.clone()).unwrap();
+        // The responder certificate is embedded in the response.
+        basic
+            .verify(
+                Stack::new().unwrap().as_ref(),
+                &store.build(),
+                OcspFlag::empty(),
+            )
+            .unwrap();
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &certificate, &ca).unwrap();
+        assert_eq!(basic.find_status(&id).unwrap().status, OcspCertStatus::GOOD);
+    }
+
+    #[test]
+    fn create_error_response() {
+        let response = error_response(OcspResponseStatus::MalformedRequest);
+        let response = OcspResponse::from_der(response.as_slice()).unwrap();
+
+        assert_eq!(response.status(), OpensslResponseStatus::MALFORMED_REQUEST);
+        assert!(response.basic().is_err());
+    }
+}
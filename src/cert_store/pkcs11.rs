@@ -0,0 +1,414 @@
+use std::error::Error;
+use std::fmt::{self, Debug, Formatter};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use log::{debug, info};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::rsa::Rsa;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::cert_store::der;
+use crate::cert_store::signer::Signer;
+use crate::cert_store::utils::signature_digest;
+
+/// The scheme of PKCS#11 URIs (RFC 7512) that reference keys in a token.
+pub const PKCS11_URI_PREFIX: &str = "pkcs11:";
+
+/// A PKCS#11 token (e.g. an HSM or SoftHSM) that holds the CA keys. The keys
+/// must be generated in the token beforehand and never leave it.
+pub struct Pkcs11Token {
+    label: String,
+    /// The label of the key that is used for new CAs.
+    key_label: String,
+    /// PKCS#11 sessions must not be used concurrently.
+    session: Mutex<Session>,
+}
+
+impl Debug for Pkcs11Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs11Token")
+            .field("label", &self.label)
+            .field("key_label", &self.key_label)
+            .finish()
+    }
+}
+
+impl Pkcs11Token {
+    /// Load the PKCS#11 module, open a session on the token with the
+    /// given label and log in as user with the PIN.
+    pub fn open(
+        module: &Path,
+        label: &str,
+        pin: &str,
+        key_label: String,
+    ) -> Result<Self, Box<dyn Error>> {
+        let pkcs11 = Pkcs11::new(module)?;
+        pkcs11.initialize(CInitializeArgs::OsThreads)?;
+
+        let mut slot = None;
+        for candidate in pkcs11.get_slots_with_token()? {
+            if pkcs11.get_token_info(candidate)?.label() == label {
+                slot = Some(candidate);
+                break;
+            }
+        }
+        let slot = slot.ok_or_else(|| format!("The PKCS#11 token '{}' does not exist.", label))?;
+
+        let session = pkcs11.open_ro_session(slot)?;
+        session.login(UserType::User, Some(&AuthPin::new(pin.to_string())))?;
+        info!("Use the PKCS#11 token '{}'.", label);
+
+        Ok(Self {
+            label: label.to_string(),
+            key_label,
+            session: Mutex::new(session),
+        })
+    }
+
+    /// Return the key that is used for new CAs.
+    pub fn key(self: &Arc<Self>) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
+        self.find_key(self.key_label.as_str())
+    }
+
+    /// Return the key with the given label for another CA (e.g. an intermediate CA).
+    /// The label must differ from the label of the key for new CAs, thus both CAs
+    /// never share a key.
+    pub fn labelled_key(self: &Arc<Self>, label: &str) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
+        if label == self.key_label {
+            return Err(format!(
+                "The PKCS#11 key '{}' is used for new CAs, use another key label.",
+                label
+            )
+            .into());
+        }
+        self.find_key(label)
+    }
+
+    /// Return the key that is referenced by the PKCS#11 URI
+    /// (e.g. `pkcs11:token=pki;object=ca;type=private`).
+    pub fn resolve(self: &Arc<Self>, uri: &str) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
+        let (token, object) = parse_uri(uri)?;
+        if let Some(token) = token.filter(|token| *token != self.label) {
+            return Err(format!(
+                "The CA key is stored in the PKCS#11 token '{}', but the token '{}' is configured.",
+                token, self.label
+            )
+            .into());
+        }
+        match object {
+            Some(object) => self.find_key(&object),
+            None => Err(format!("The PKCS#11 URI '{}' does not reference a key.", uri).into()),
+        }
+    }
+
+    fn find_key(self: &Arc<Self>, label: &str) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
+        debug!("Load the key '{}' from the PKCS#11 token.", label);
+
+        let session = self.session.lock().unwrap();
+        let find = |class: ObjectClass| -> Result<ObjectHandle, Box<dyn Error>> {
+            let template = [
+                Attribute::Class(class),
+                Attribute::Label(label.as_bytes().to_vec()),
+            ];
+            match session.find_objects(&template)?.first() {
+                Some(handle) => Ok(*handle),
+                None => Err(format!(
+                    "The PKCS#11 token '{}' does not contain the key pair '{}'.",
+                    self.label, label
+                )
+                .into()),
+            }
+        };
+        let handle = find(ObjectClass::PRIVATE_KEY)?;
+        let public_key = read_public_key(&session, find(ObjectClass::PUBLIC_KEY)?)?;
+
+        Ok(Arc::new(Pkcs11Key {
+            token: self.clone(),
+            label: label.to_string(),
+            handle,
+            public_key,
+        }))
+    }
+}
+
+/// Parse the token and object (i.e. key label) attributes of the PKCS#11 URI.
+fn parse_uri(uri: &str) -> Result<(Option<String>, Option<String>), Box<dyn Error>> {
+    let path = uri
+        .strip_prefix(PKCS11_URI_PREFIX)
+        .and_then(|uri| uri.split('?').next())
+        .ok_or_else(|| format!("Invalid PKCS#11 URI '{}'.", uri))?;
+
+    let mut token = None;
+    let mut object = None;
+    for attribute in path.split(';') {
+        match attribute.split_once('=') {
+            Some(("token", value)) => {
+                token = Some(percent_decode_str(value).decode_utf8()?.into_owned())
+            }
+            Some(("object", value)) => {
+                object = Some(percent_decode_str(value).decode_utf8()?.into_owned())
+            }
+            _ => {}
+        }
+    }
+    Ok((token, object))
+}
+
+/// Read the public key from the attributes of the public key object.
+fn read_public_key(
+    session: &Session,
+    handle: ObjectHandle,
+) -> Result<PKey<Public>, Box<dyn Error>> {
+    let attributes = session.get_attributes(
+        handle,
+        &[
+            AttributeType::KeyType,
+            AttributeType::Modulus,
+            AttributeType::PublicExponent,
+            AttributeType::EcParams,
+            AttributeType::EcPoint,
+        ],
+    )?;
+    public_key(attributes)
+}
+
+/// Build the public key of the attributes of a public key object.
+fn public_key(attributes: Vec<Attribute>) -> Result<PKey<Public>, Box<dyn Error>> {
+    let mut key_type = None;
+    let (mut modulus, mut exponent) = (None, None);
+    let (mut ec_params, mut ec_point) = (None, None);
+    for attribute in attributes {
+        match attribute {
+            Attribute::KeyType(value) => key_type = Some(value),
+            Attribute::Modulus(value) => modulus = Some(value),
+            Attribute::PublicExponent(value) => exponent = Some(value),
+            Attribute::EcParams(value) => ec_params = Some(value),
+            Attribute::EcPoint(value) => ec_point = Some(value),
+            _ => {}
+        }
+    }
+
+    match (key_type, modulus, exponent, ec_params, ec_point) {
+        (Some(KeyType::RSA), Some(modulus), Some(exponent), _, _) => {
+            let rsa = Rsa::from_public_components(
+                BigNum::from_slice(modulus.as_slice())?,
+                BigNum::from_slice(exponent.as_slice())?,
+            )?;
+            Ok(PKey::from_rsa(rsa)?)
+        }
+        (Some(KeyType::EC), _, _, Some(params), Some(point)) => {
+            let curve = if params == der::oid(der::OID_CURVE_P256) {
+                Nid::X9_62_PRIME256V1
+            } else if params == der::oid(der::OID_CURVE_P384) {
+                Nid::SECP384R1
+            } else {
+                return Err("Only the curves P-256 and P-384 are supported.".into());
+            };
+            let group = EcGroup::from_curve_name(curve)?;
+            // The point is wrapped in an OCTET STRING.
+            let point = der::expect(point.as_slice(), der::TAG_OCTET_STRING)?;
+            let mut context = BigNumContext::new()?;
+            let point = EcPoint::from_bytes(&group, point.content, &mut context)?;
+            Ok(PKey::from_ec_key(EcKey::from_public_key(&group, &point)?)?)
+        }
+        _ => Err("Only RSA and ECDSA keys are supported in PKCS#11 tokens.".into()),
+    }
+}
+
+/// A private key in a PKCS#11 token.
+pub struct Pkcs11Key {
+    token: Arc<Pkcs11Token>,
+    label: String,
+    handle: ObjectHandle,
+    public_key: PKey<Public>,
+}
+
+impl Debug for Pkcs11Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs11Key")
+            .field("token", &self.token.label)
+            .field("label", &self.label)
+            .finish()
+    }
+}
+
+impl Signer for Pkcs11Key {
+    fn public_key(&self) -> Result<PKey<Public>, Box<dyn Error>> {
+        Ok(self.public_key.clone())
+    }
+
+    fn signature_algorithm(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        der::signature_algorithm(self.public_key.as_ref())
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let session = self.token.session.lock().unwrap();
+        match self.public_key.id() {
+            Id::RSA => Ok(session.sign(&Mechanism::Sha256RsaPkcs, self.handle, data)?),
+            Id::EC => {
+                let mechanism = match signature_digest(&self.public_key) == MessageDigest::sha384()
+                {
+                    true => Mechanism::EcdsaSha384,
+                    false => Mechanism::EcdsaSha256,
+                };
+                let signature = session.sign(&mechanism, self.handle, data)?;
+                ecdsa_signature(signature.as_slice())
+            }
+            _ => Err("Only RSA and ECDSA keys are supported in PKCS#11 tokens.".into()),
+        }
+    }
+
+    fn reference(&self) -> Option<String> {
+        Some(format!(
+            "{}token={};object={};type=private",
+            PKCS11_URI_PREFIX,
+            utf8_percent_encode(self.token.label.as_str(), NON_ALPHANUMERIC),
+            utf8_percent_encode(self.label.as_str(), NON_ALPHANUMERIC)
+        ))
+    }
+}
+
+/// PKCS#11 returns the concatenation of r and s,
+/// X.509 requires a DER encoded ECDSA-Sig-Value.
+fn ecdsa_signature(signature: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if signature.is_empty() || signature.len() % 2 != 0 {
+        return Err("Invalid ECDSA signature of the PKCS#11 token.".into());
+    }
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let signature =
+        EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?;
+    Ok(signature.to_der()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use cryptoki::object::{Attribute, KeyType};
+    use openssl::bn::BigNumContext;
+    use openssl::ec::{EcGroup, EcKey, PointConversionForm};
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+
+    use crate::cert_store::der;
+    use crate::cert_store::pkcs11::{ecdsa_signature, parse_uri, public_key, Pkcs11Token};
+    use crate::cert_store::utils::create_new_ca;
+
+    #[test]
+    fn parse_the_key_of_the_uri() {
+        assert_eq!(
+            parse_uri("pkcs11:token=pki;object=wirepact%20ca;type=private").unwrap(),
+            (Some("pki".to_string()), Some("wirepact ca".to_string()))
+        );
+        assert_eq!(
+            parse_uri("pkcs11:object=ca;type=private?pin-value=1234").unwrap(),
+            (None, Some("ca".to_string()))
+        );
+        assert_eq!(parse_uri("pkcs11:type=private").unwrap(), (None, None));
+        assert!(parse_uri("file:ca.key").is_err());
+    }
+
+    #[test]
+    fn read_the_public_key_of_the_attributes() {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let ec_key = EcKey::generate(&group).unwrap();
+        let point = ec_key
+            .public_key()
+            .to_bytes(
+                &group,
+                PointConversionForm::UNCOMPRESSED,
+                &mut BigNumContext::new().unwrap(),
+            )
+            .unwrap();
+        let ec = public_key(vec![
+            Attribute::KeyType(KeyType::EC),
+            Attribute::EcParams(der::oid(der::OID_CURVE_P384)),
+            Attribute::EcPoint(der::octet_string(point.as_slice())),
+        ])
+        .unwrap();
+        assert!(ec.public_eq(PKey::from_ec_key(ec_key).unwrap().as_ref()));
+
+        let rsa_key = Rsa::generate(2048).unwrap();
+        let rsa = public_key(vec![
+            Attribute::KeyType(KeyType::RSA),
+            Attribute::Modulus(rsa_key.n().to_vec()),
+            Attribute::PublicExponent(rsa_key.e().to_vec()),
+        ])
+        .unwrap();
+        assert!(rsa.public_eq(PKey::from_rsa(rsa_key).unwrap().as_ref()));
+
+        // The curve P-521 is not supported.
+        assert!(public_key(vec![
+            Attribute::KeyType(KeyType::EC),
+            Attribute::EcParams(der::oid(&[1, 3, 132, 0, 35])),
+            Attribute::EcPoint(der::octet_string(point.as_slice())),
+        ])
+        .is_err());
+        assert!(public_key(vec![Attribute::KeyType(KeyType::EC)]).is_err());
+    }
+
+    #[test]
+    fn convert_the_ecdsa_signature() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let digest = [0x2a; 32];
+        let signature = EcdsaSig::sign(&digest, &key).unwrap();
+        // The token pads r and s to the size of the curve.
+        let mut concatenated = signature.r().to_vec_padded(32).unwrap();
+        concatenated.extend(signature.s().to_vec_padded(32).unwrap());
+
+        let der = ecdsa_signature(concatenated.as_slice()).unwrap();
+
+        assert_eq!(der, signature.to_der().unwrap());
+        assert!(EcdsaSig::from_der(der.as_slice())
+            .unwrap()
+            .verify(&digest, &key)
+            .unwrap());
+        assert!(ecdsa_signature(&concatenated[1..]).is_err());
+        assert!(ecdsa_signature(&[]).is_err());
+    }
+
+    /// Needs a token with a key pair (see the README), e.g. `PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so
+    /// PKCS11_TOKEN=pki PKCS11_PIN=1234 cargo test sign_with_the_key_of_the_token -- --ignored`.
+    #[test]
+    #[ignore]
+    fn sign_with_the_key_of_the_token() {
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+        let key_label = env("PKCS11_KEY_LABEL", "wirepact-pki-ca");
+        let token = Arc::new(
+            Pkcs11Token::open(
+                Path::new(env("PKCS11_MODULE", "/usr/lib/softhsm/libsofthsm2.so").as_str()),
+                env("PKCS11_TOKEN", "pki").as_str(),
+                env("PKCS11_PIN", "1234").as_str(),
+                key_label.clone(),
+            )
+            .unwrap(),
+        );
+
+        let key = token.key().unwrap();
+        let cert = create_new_ca(key.as_ref()).unwrap();
+
+        let public_key = key.public_key().unwrap();
+        assert!(cert.verify(public_key.as_ref()).unwrap());
+        let resolved = token.resolve(key.reference().unwrap().as_str()).unwrap();
+        assert!(resolved
+            .public_key()
+            .unwrap()
+            .public_eq(public_key.as_ref()));
+        assert!(token.labelled_key(key_label.as_str()).is_err());
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+
+use openssl::asn1::Asn1Time;
+use openssl::error::ErrorStack;
+use openssl::pkey::{HasPublic, Id, PKeyRef};
+use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, KeyUsage};
+use openssl::x509::X509Extension;
+use serde::Deserialize;
+use time::{Duration, OffsetDateTime};
+use tokio::fs::read_to_string;
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyUsageFlag {
+    DigitalSignature,
+    NonRepudiation,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement,
+    KeyCertSign,
+    #[serde(rename = "cRLSign")]
+    CrlSign,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExtendedKeyUsageFlag {
+    ServerAuth,
+    ClientAuth,
+    CodeSigning,
+    EmailProtection,
+    TimeStamping,
+    #[serde(rename = "OCSPSigning")]
+    OcspSigning,
+}
+
+/// An issuance profile that defines the validity and the usages
+/// of certificates that are signed by the PKI.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub name: String,
+    pub validity_hours: u32,
+    #[serde(default)]
+    pub key_usages: Vec<KeyUsageFlag>,
+    #[serde(default)]
+    pub extended_key_usages: Vec<ExtendedKeyUsageFlag>,
+    /// If set, the issued certificates are CA certificates
+    /// with the given maximum path length.
+    #[serde(default)]
+    pub max_path_length: Option<u32>,
+    /// The requesters (API key identities or Kubernetes users) that may use
+    /// the profile if it issues CA certificates. Profiles for leaf
+    /// certificates are available to all requesters.
+    #[serde(default)]
+    pub authorized_requesters: Vec<String>,
+    /// A shorter validity that was requested together with the CSR
+    /// (see [Profile::restrict]). Cannot be configured.
+    #[serde(skip)]
+    pub requested_validity: Option<Duration>,
+}
+
+/// The usages and the validity that are requested together with a CSR, e.g. in a
+/// Kubernetes `CertificateSigningRequest` or a cert-manager `CertificateRequest`.
+#[derive(Debug, Clone, Default)]
+pub struct RequestedUsages {
+    /// Usages in the notation of the Kubernetes certificates API (e.g. `server auth`).
+    /// If empty, the usages of the profile are issued.
+    pub usages: Vec<String>,
+    /// The requested validity of the certificate.
+    pub validity: Option<Duration>,
+}
+
+enum Usage {
+    Key(KeyUsageFlag),
+    Extended(ExtendedKeyUsageFlag),
+}
+
+impl FromStr for Usage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "digital signature" => Ok(Usage::Key(KeyUsageFlag::DigitalSignature)),
+            "content commitment" => Ok(Usage::Key(KeyUsageFlag::NonRepudiation)),
+            "key encipherment" => Ok(Usage::Key(KeyUsageFlag::KeyEncipherment)),
+            "data encipherment" => Ok(Usage::Key(KeyUsageFlag::DataEncipherment)),
+            "key agreement" => Ok(Usage::Key(KeyUsageFlag::KeyAgreement)),
+            "cert sign" => Ok(Usage::Key(KeyUsageFlag::KeyCertSign)),
+            "crl sign" => Ok(Usage::Key(KeyUsageFlag::CrlSign)),
+            "server auth" => Ok(Usage::Extended(ExtendedKeyUsageFlag::ServerAuth)),
+            "client auth" => Ok(Usage::Extended(ExtendedKeyUsageFlag::ClientAuth)),
+            "code signing" => Ok(Usage::Extended(ExtendedKeyUsageFlag::CodeSigning)),
+            "email protection" => Ok(Usage::Extended(ExtendedKeyUsageFlag::EmailProtection)),
+            "timestamping" => Ok(Usage::Extended(ExtendedKeyUsageFlag::TimeStamping)),
+            "ocsp signing" => Ok(Usage::Extended(ExtendedKeyUsageFlag::OcspSigning)),
+            _ => Err(format!("The usage '{}' is not supported.", s)),
+        }
+    }
+}
+
+impl Profile {
+    /// Whether the profile issues certificates that can sign other certificates.
+    pub fn issues_ca(&self) -> bool {
+        self.max_path_length.is_some() || self.key_usages.contains(&KeyUsageFlag::KeyCertSign)
+    }
+
+    /// Whether the requester may sign CSRs with the profile.
+    pub fn authorizes(&self, requester: &str) -> bool {
+        !self.issues_ca()
+            || self
+                .authorized_requesters
+                .iter()
+                .any(|authorized| authorized == requester)
+    }
+
+    /// Restrict the profile to the requested usages and validity. All requested usages
+    /// must be part of the profile, otherwise the request is rejected. A validity that
+    /// exceeds the validity of the profile is shortened to it.
+    pub fn restrict(&self, requested: &RequestedUsages) -> Result<Profile, String> {
+        let mut profile = self.clone();
+        if !requested.usages.is_empty() {
+            profile.key_usages.clear();
+            profile.extended_key_usages.clear();
+        }
+        for usage in requested.usages.iter() {
+            match usage.parse::<Usage>()? {
+                Usage::Key(flag) if self.key_usages.contains(&flag) => {
+                    profile.key_usages.push(flag)
+                }
+                Usage::Extended(flag) if self.extended_key_usages.contains(&flag) => {
+                    profile.extended_key_usages.push(flag)
+                }
+                _ => {
+                    return Err(format!(
+                        "The usage '{}' is not allowed by the profile '{}'.",
+                        usage, self.name
+                    ))
+                }
+            }
+        }
+
+        if let Some(validity) = requested.validity {
+            if !validity.is_positive() {
+                return Err("The requested validity must be positive.".to_string());
+            }
+            if validity < Duration::hours(i64::from(self.validity_hours)) {
+                profile.requested_validity = Some(validity);
+            }
+        }
+        Ok(profile)
+    }
+
+    pub fn not_after(&self) -> Result<Asn1Time, Box<dyn Error>> {
+        let validity = self
+            .requested_validity
+            .unwrap_or_else(|| Duration::hours(i64::from(self.validity_hours)));
+        let not_after = OffsetDateTime::now_utc() + validity;
+        Ok(Asn1Time::from_unix(not_after.unix_timestamp())?)
+    }
+
+    /// Return the extensions of a certificate for the given public key. The key usage
+    /// `keyEncipherment` (key transport) only applies to RSA keys, thus it is omitted
+    /// for other keys.
+    pub fn extensions<T: HasPublic>(
+        &self,
+        key: &PKeyRef<T>,
+    ) -> Result<Vec<X509Extension>, ErrorStack> {
+        let mut extensions = Vec::new();
+
+        let mut basic_constraints = BasicConstraints::new();
+        if let Some(path_length) = self.max_path_length {
+            basic_constraints.critical().ca().pathlen(path_length);
+        }
+        extensions.push(basic_constraints.build()?);
+
+        if !self.key_usages.is_empty() {
+            let mut key_usage = KeyUsage::new();
+            key_usage.critical();
+            for usage in self.key_usages.iter() {
+                match usage {
+                    KeyUsageFlag::DigitalSignature => key_usage.digital_signature(),
+                    KeyUsageFlag::NonRepudiation => key_usage.non_repudiation(),
+                    KeyUsageFlag::KeyEncipherment if key.id() != Id::RSA => continue,
+                    KeyUsageFlag::KeyEncipherment => key_usage.key_encipherment(),
+                    KeyUsageFlag::DataEncipherment => key_usage.data_encipherment(),
+                    KeyUsageFlag::KeyAgreement => key_usage.key_agreement(),
+                    KeyUsageFlag::KeyCertSign => key_usage.key_cert_sign(),
+                    KeyUsageFlag::CrlSign => key_usage.crl_sign(),
+                };
+            }
+            extensions.push(key_usage.build()?);
+        }
+
+        if !self.extended_key_usages.is_empty() {
+            let mut extended_key_usage = ExtendedKeyUsage::new();
+            for usage in self.extended_key_usages.iter() {
+                match usage {
+                    ExtendedKeyUsageFlag::ServerAuth => extended_key_usage.server_auth(),
+                    ExtendedKeyUsageFlag::ClientAuth => extended_key_usage.client_auth(),
+                    ExtendedKeyUsageFlag::CodeSigning => extended_key_usage.code_signing(),
+                    ExtendedKeyUsageFlag::EmailProtection => extended_key_usage.email_protection(),
+                    ExtendedKeyUsageFlag::TimeStamping => extended_key_usage.time_stamping(),
+                    ExtendedKeyUsageFlag::OcspSigning => extended_key_usage.other("OCSPSigning"),
+                };
+            }
+            extensions.push(extended_key_usage.build()?);
+        }
+
+        Ok(extensions)
+    }
+}
+
+/// The set of issuance profiles that are available to requesters.
+#[derive(Debug, Clone)]
+pub struct Profiles {
+    profiles: HashMap<String, Profile>,
+    default_profile: String,
+}
+
+impl Profiles {
+    /// Create the built-in profiles (`default`, `server`, `client` and `identity-signing`)
+    /// with the given validity. If a profile file is given, the profiles in the
+    /// file are added and replace built-in profiles with the same name.
+    pub async fn load(
+        file: Option<&Path>,
+        validity_hours: u32,
+        default_profile: String,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut profiles = built_in_profiles(validity_hours);
+        if let Some(file) = file {
+            let content = read_to_string(file).await?;
+            let configured: Vec<Profile> = serde_yaml::from_str(content.as_str())?;
+            profiles.extend(configured);
+        }
+
+        Self::new(profiles, default_profile)
+    }
+
+    /// Create the set of the given profiles. Later profiles replace
+    /// earlier profiles with the same name.
+    pub fn new(profiles: Vec<Profile>, default_profile: String) -> Result<Self, Box<dyn Error>> {
+        let profiles: HashMap<String, Profile> = profiles
+            .into_iter()
+            .map(|profile| (profile.name.clone(), profile))
+            .collect();
+
+        if !profiles.contains_key(default_profile.as_str()) {
+            return Err(
+                format!("The default profile '{}' does not exist.", default_profile).into(),
+            );
+        }
+
+        Ok(Self {
+            profiles,
+            default_profile,
+        })
+    }
+
+    /// Return the profile with the given name. An empty name
+    /// selects the default profile.
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        match name {
+            "" => self.profiles.get(self.default_profile.as_str()),
+            name => self.profiles.get(name),
+        }
+    }
+}
+
+fn built_in_profiles(validity_hours: u32) -> Vec<Profile> {
+    vec![
+        Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            validity_hours,
+            key_usages: vec![
+                KeyUsageFlag::NonRepudiation,
+                KeyUsageFlag::DigitalSignature,
+                KeyUsageFlag::KeyEncipherment,
+            ],
+            extended_key_usages: vec![
+                ExtendedKeyUsageFlag::ClientAuth,
+                ExtendedKeyUsageFlag::ServerAuth,
+            ],
+            max_path_length: None,
+            authorized_requesters: Vec::new(),
+            requested_validity: None,
+        },
+        Profile {
+            name: "server".to_string(),
+            validity_hours,
+            key_usages: vec![
+                KeyUsageFlag::DigitalSignature,
+                KeyUsageFlag::KeyEncipherment,
+            ],
+            extended_key_usages: vec![ExtendedKeyUsageFlag::ServerAuth],
+            max_path_length: None,
+            authorized_requesters: Vec::new(),
+            requested_validity: None,
+        },
+        Profile {
+            name: "client".to_string(),
+            validity_hours,
+            key_usages: vec![KeyUsageFlag::DigitalSignature],
+            extended_key_usages: vec![ExtendedKeyUsageFlag::ClientAuth],
+            max_path_length: None,
+            authorized_requesters: Vec::new(),
+            requested_validity: None,
+        },
+        Profile {
+            name: "identity-signing".to_string(),
+            validity_hours,
+            key_usages: vec![KeyUsageFlag::DigitalSignature, KeyUsageFlag::NonRepudiation],
+            extended_key_usages: Vec::new(),
+            max_path_length: None,
+            authorized_requesters: Vec::new(),
+            requested_validity: None,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::x509::X509;
+    use time::Duration;
+
+    use crate::cert_store::utils::{create_new_key, signature_digest, KeyAlgorithm};
+
+    use crate::cert_store::profile::{
+        ExtendedKeyUsageFlag, KeyUsageFlag, Profile, Profiles, RequestedUsages, DEFAULT_PROFILE,
+    };
+
+    fn profile(yaml: &str) -> Profile {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn certificate_text(profile: &Profile, algorithm: KeyAlgorithm) -> String {
+        let key = create_new_key(algorithm).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_pubkey(key.as_ref()).unwrap();
+        for extension in profile.extensions(key.as_ref()).unwrap() {
+            builder.append_extension(extension).unwrap();
+        }
+        builder.sign(key.as_ref(), signature_digest(&key)).unwrap();
+        String::from_utf8(builder.build().to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn built_in_profiles_issue_leaf_certificates() {
+        let profiles = Profiles::load(None, 24, DEFAULT_PROFILE.to_string())
+            .await
+            .unwrap();
+
+        for name in ["", "default", "server", "client", "identity-signing"] {
+            let profile = profiles.get(name).unwrap();
+            assert!(!profile.issues_ca(), "profile '{}'", name);
+            assert!(profile.authorizes("anonymous"), "profile '{}'", name);
+        }
+        assert_eq!(profiles.get("").unwrap().name, DEFAULT_PROFILE);
+        assert!(profiles.get("sub-ca").is_none());
+    }
+
+    #[test]
+    fn ca_profiles_require_authorized_requesters() {
+        let path_length = profile(
+            "{ name: sub-ca, validityHours: 24, maxPathLength: 0, authorizedRequesters: [ops] }",
+        );
+        let key_cert_sign =
+            profile("{ name: signer, validityHours: 24, keyUsages: [keyCertSign] }");
+
+        assert!(path_length.issues_ca());
+        assert!(path_length.authorizes("ops"));
+        assert!(!path_length.authorizes("anonymous"));
+        assert!(!path_length.authorizes("Ops"));
+        assert!(key_cert_sign.issues_ca());
+        assert!(!key_cert_sign.authorizes("anonymous"));
+    }
+
+    #[test]
+    fn ca_profiles_set_ca_basic_constraints() {
+        let leaf = certificate_text(
+            &profile("{ name: leaf, validityHours: 24 }"),
+            KeyAlgorithm::EcdsaP256,
+        );
+        let ca = certificate_text(
+            &profile("{ name: sub-ca, validityHours: 24, maxPathLength: 1 }"),
+            KeyAlgorithm::EcdsaP256,
+        );
+
+        assert!(leaf.contains("CA:FALSE"));
+        assert!(ca.contains("CA:TRUE, pathlen:1"));
+    }
+
+    #[tokio::test]
+    async fn key_encipherment_only_for_rsa_keys() {
+        let profiles = Profiles::load(None, 24, DEFAULT_PROFILE.to_string())
+            .await
+            .unwrap();
+        let profile = profiles.get("").unwrap();
+
+        let rsa = certificate_text(profile, KeyAlgorithm::Rsa2048);
+        assert!(rsa.contains("Digital Signature, Non Repudiation, Key Encipherment"));
+        for algorithm in [
+            KeyAlgorithm::EcdsaP256,
+            KeyAlgorithm::EcdsaP384,
+            KeyAlgorithm::Ed25519,
+        ] {
+            let text = certificate_text(profile, algorithm);
+            assert!(text.contains("Digital Signature, Non Repudiation"));
+            assert!(!text.contains("Key Encipherment"), "{:?}", algorithm);
+        }
+    }
+
+    #[tokio::test]
+    async fn restrict_to_the_requested_usages() {
+        let profiles = Profiles::load(None, 24, DEFAULT_PROFILE.to_string())
+            .await
+            .unwrap();
+        let profile = profiles.get("").unwrap();
+        let requested = |usages: &[&str], validity: Option<Duration>| RequestedUsages {
+            usages: usages.iter().map(|usage| usage.to_string()).collect(),
+            validity,
+        };
+
+        let restricted = profile
+            .restrict(&requested(&["digital signature", "client auth"], None))
+            .unwrap();
+        assert_eq!(restricted.key_usages, vec![KeyUsageFlag::DigitalSignature]);
+        assert_eq!(
+            restricted.extended_key_usages,
+            vec![ExtendedKeyUsageFlag::ClientAuth]
+        );
+        assert_eq!(restricted.requested_validity, None);
+
+        let unchanged = profile.restrict(&RequestedUsages::default()).unwrap();
+        assert_eq!(unchanged.key_usages, profile.key_usages);
+        assert_eq!(unchanged.extended_key_usages, profile.extended_key_usages);
+
+        assert!(profile.restrict(&requested(&["cert sign"], None)).is_err());
+        assert!(profile
+            .restrict(&requested(&["code signing"], None))
+            .is_err());
+        assert!(profile.restrict(&requested(&["any"], None)).is_err());
+    }
+
+    #[test]
+    fn restrict_to_a_shorter_validity() {
+        let profile = profile("{ name: leaf, validityHours: 24 }");
+        let requested = |validity: Duration| RequestedUsages {
+            usages: Vec::new(),
+            validity: Some(validity),
+        };
+        let not_after = |profile: &Profile| {
+            let now = Asn1Time::days_from_now(0).unwrap();
+            now.diff(profile.not_after().unwrap().as_ref()).unwrap()
+        };
+
+        let shorter = profile.restrict(&requested(Duration::minutes(10))).unwrap();
+        assert_eq!(shorter.requested_validity, Some(Duration::minutes(10)));
+        let diff = not_after(&shorter);
+        assert_eq!((diff.days, diff.secs / 60), (0, 10));
+
+        let longer = profile.restrict(&requested(Duration::days(90))).unwrap();
+        assert_eq!(longer.requested_validity, None);
+        assert_eq!(not_after(&longer).days, 1);
+
+        assert!(profile.restrict(&requested(Duration::ZERO)).is_err());
+    }
+
+    #[test]
+    fn later_profiles_replace_earlier_ones() {
+        let profiles = Profiles::new(
+            vec![
+                profile("{ name: server, validityHours: 24 }"),
+                profile("{ name: server, validityHours: 1 }"),
+            ],
+            "server".to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(profiles.get("").unwrap().validity_hours, 1);
+        assert!(Profiles::new(Vec::new(), "server".to_string()).is_err());
+    }
+}
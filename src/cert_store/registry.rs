@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::cert_store::kubernetes_store::{KubernetesContext, KubernetesStoreConfig};
+use crate::cert_store::local_store::LocalStoreConfig;
+use crate::cert_store::memory_store::InMemoryStoreConfig;
+use crate::cert_store::signer::KeyStorage;
+use crate::cert_store::sqlite_store::SqliteStoreConfig;
+use crate::cert_store::store::CertificateStore;
+use crate::cert_store::utils::KeyAlgorithm;
+use crate::leader_election::LeaderElection;
+
+pub const KUBERNETES_SCHEME: &str = "k8s";
+pub const FILE_SCHEME: &str = "file";
+pub const SQLITE_SCHEME: &str = "sqlite";
+pub const MEMORY_SCHEME: &str = "memory";
+
+pub const DEFAULT_STORE: &str = "k8s:///wirepact-pki-ca";
+pub const MEMORY_STORE: &str = "memory://";
+
+/// The URL of a certificate store in the format `<scheme>://<location>`
+/// (e.g. `k8s://pki/wirepact-pki-ca` or `file:///var/lib/pki`).
+/// The scheme selects the store, the location is interpreted by the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreUrl {
+    scheme: String,
+    location: String,
+}
+
+impl StoreUrl {
+    pub fn scheme(&self) -> &str {
+        self.scheme.as_str()
+    }
+
+    pub fn location(&self) -> &str {
+        self.location.as_str()
+    }
+}
+
+impl FromStr for StoreUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            Some((scheme, location))
+                if !scheme.is_empty()
+                    && scheme
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) =>
+            {
+                Ok(Self {
+                    scheme: scheme.to_ascii_lowercase(),
+                    location: location.to_string(),
+                })
+            }
+            _ => Err("Stores must be in the format '<scheme>://<location>'.".to_string()),
+        }
+    }
+}
+
+impl Display for StoreUrl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.location)
+    }
+}
+
+/// The dependencies of the PKI that are shared with the stores.
+#[derive(Clone)]
+pub struct StoreContext {
+    /// The algorithm of the key if a store creates a new CA.
+    pub key_algorithm: KeyAlgorithm,
+    pub key_storage: KeyStorage,
+    /// The client for the Kubernetes API, if the PKI uses it.
+    pub kubernetes: Option<KubernetesContext>,
+    pub leader_election: Arc<LeaderElection>,
+}
+
+/// The typed configuration of a store that is parsed from the store URL
+/// and builds the store. Custom stores implement this trait and are
+/// registered with their scheme in the [StoreRegistry].
+pub trait StoreBuilder: Sized {
+    /// Parse the configuration from the store URL.
+    fn from_url(url: &StoreUrl) -> Result<Self, Box<dyn Error>>;
+
+    /// Build the (not yet initialized) store.
+    fn build(self, context: &StoreContext) -> Result<Box<dyn CertificateStore>, Box<dyn Error>>;
+}
+
+type BuildFn = fn(&StoreUrl, &StoreContext) -> Result<Box<dyn CertificateStore>, Box<dyn Error>>;
+
+/// The stores that are available by the scheme of their URL.
+pub struct StoreRegistry {
+    builders: BTreeMap<String, BuildFn>,
+}
+
+impl Default for StoreRegistry {
+    /// Create the registry with the built-in stores.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register::<KubernetesStoreConfig>(KUBERNETES_SCHEME);
+        registry.register::<LocalStoreConfig>(FILE_SCHEME);
+        registry.register::<SqliteStoreConfig>(SQLITE_SCHEME);
+        registry.register::<InMemoryStoreConfig>(MEMORY_SCHEME);
+        registry
+    }
+}
+
+impl StoreRegistry {
+    /// Create a registry without any store.
+    pub fn empty() -> Self {
+        Self {
+            builders: BTreeMap::new(),
+        }
+    }
+
+    /// Register the store for the scheme. A store that was
+    /// registered for the scheme before is replaced.
+    pub fn register<B: StoreBuilder>(&mut self, scheme: &str) {
+        self.builders
+            .insert(scheme.to_ascii_lowercase(), |url, context| {
+                B::from_url(url)?.build(context)
+            });
+    }
+
+    pub fn schemes(&self) -> impl Iterator<Item = &str> {
+        self.builders.keys().map(|scheme| scheme.as_str())
+    }
+
+    /// Build the store that is registered for the scheme of the URL.
+    pub fn build(
+        &self,
+        url: &StoreUrl,
+        context: &StoreContext,
+    ) -> Result<Box<dyn CertificateStore>, Box<dyn Error>> {
+        match self.builders.get(url.scheme()) {
+            Some(build) => build(url, context),
+            None => Err(format!(
+                "Unknown store '{}', the supported schemes are: {}.",
+                url,
+                self.schemes().collect::<Vec<_>>().join(", ")
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::cert_store::memory_store::InMemoryStoreConfig;
+    use crate::cert_store::registry::{StoreContext, StoreRegistry, StoreUrl};
+    use crate::cert_store::signer::KeyStorage;
+    use crate::cert_store::utils::KeyAlgorithm;
+    use crate::leader_election::LeaderElection;
+
+    fn context() -> StoreContext {
+        StoreContext {
+            key_algorithm: KeyAlgorithm::EcdsaP256,
+            key_storage: KeyStorage::default(),
+            kubernetes: None,
+            leader_election: Arc::new(LeaderElection::single()),
+        }
+    }
+
+    fn build(url: &str) -> Result<(), String> {
+        let url: StoreUrl = url.parse()?;
+        StoreRegistry::default()
+            .build(&url, &context())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parse_store_urls() {
+        let url: StoreUrl = "SQLite:///var/lib/pki.db".parse().unwrap();
+        assert_eq!(url.scheme(), "sqlite");
+        assert_eq!(url.location(), "/var/lib/pki.db");
+        assert_eq!(url.to_string(), "sqlite:///var/lib/pki.db");
+
+        let url: StoreUrl = "memory://".parse().unwrap();
+        assert_eq!(url.location(), "");
+
+        for invalid in ["", "/var/lib/pki", "://pki", "k8s:/pki", "a b://pki"] {
+            assert!(invalid.parse::<StoreUrl>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn build_the_store_of_the_scheme() {
+        build("memory://").unwrap();
+        build("file://./ca").unwrap();
+        build("sqlite:///var/lib/pki.db").unwrap();
+
+        let unknown = build("s3://bucket/pki").unwrap_err();
+        assert!(unknown.contains("file, k8s, memory, sqlite"), "{}", unknown);
+    }
+
+    #[test]
+    fn reject_invalid_store_locations() {
+        for invalid in [
+            "file://",
+            "sqlite://",
+            "memory://pki",
+            "k8s://",
+            "k8s://pki/",
+            "k8s://pki/secret/key",
+        ] {
+            assert!(build(invalid).is_err(), "{}", invalid);
+        }
+        // The Kubernetes store requires the client for the Kubernetes API.
+        assert!(build("k8s:///wirepact-pki-ca").is_err());
+    }
+
+    #[test]
+    fn register_custom_stores() {
+        let mut registry = StoreRegistry::empty();
+        assert!(registry
+            .build(&"memory://".parse().unwrap(), &context())
+            .is_err());
+
+        registry.register::<InMemoryStoreConfig>("Test");
+        assert_eq!(registry.schemes().collect::<Vec<_>>(), vec!["test"]);
+        assert!(registry
+            .build(&"test://".parse().unwrap(), &context())
+            .is_ok());
+    }
+}
@@ -0,0 +1,338 @@
+use std::error::Error;
+
+use openssl::asn1::{Asn1Object, Asn1OctetString};
+use openssl::bn::BigNum;
+use openssl::x509::{X509Crl, X509Extension, X509Ref};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::cert_store::der;
+use crate::cert_store::signer::Signer;
+
+/// The reason for the revocation of a certificate as defined in RFC 5280.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RevocationReason {
+    Unspecified,
+    KeyCompromise,
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    CertificateHold,
+    PrivilegeWithdrawn,
+    AaCompromise,
+}
+
+impl RevocationReason {
+    pub fn code(&self) -> u8 {
+        match self {
+            RevocationReason::Unspecified => 0,
+            RevocationReason::KeyCompromise => 1,
+            RevocationReason::CaCompromise => 2,
+            RevocationReason::AffiliationChanged => 3,
+            RevocationReason::Superseded => 4,
+            RevocationReason::CessationOfOperation => 5,
+            RevocationReason::CertificateHold => 6,
+            RevocationReason::PrivilegeWithdrawn => 9,
+            RevocationReason::AaCompromise => 10,
+        }
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(RevocationReason::Unspecified),
+            1 => Some(RevocationReason::KeyCompromise),
+            2 => Some(RevocationReason::CaCompromise),
+            3 => Some(RevocationReason::AffiliationChanged),
+            4 => Some(RevocationReason::Superseded),
+            5 => Some(RevocationReason::CessationOfOperation),
+            6 => Some(RevocationReason::CertificateHold),
+            9 => Some(RevocationReason::PrivilegeWithdrawn),
+            10 => Some(RevocationReason::AaCompromise),
+            _ => None,
+        }
+    }
+}
+
+/// A certificate that was revoked by the PKI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokedCertificate {
+    /// The serial number of the certificate in uppercase hex notation.
+    pub serial_number: String,
+    /// Unix timestamp of the revocation.
+    pub revoked_at: i64,
+    pub reason: RevocationReason,
+}
+
+/// Parse a hex encoded serial number and return it in the canonical
+/// (uppercase, no leading zeros) notation that is used by the stores.
+pub fn normalize_serial_number(serial_number: &str) -> Result<String, Box<dyn Error>> {
+    let serial_number = serial_number.trim().replace(':', "");
+    if serial_number.is_empty() || !serial_number.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("The serial number must be hex encoded.".into());
+    }
+
+    let serial_number = BigNum::from_hex_str(serial_number.as_str())?;
+    Ok(serial_number.to_hex_str()?.to_string())
+}
+
+/// Return the CRL number for a CRL that is issued at `this_update`. It is
+/// derived from the time, but is always higher than the number of the
+/// previous CRL (e.g. if the clock went backwards or two CRLs were
+/// generated within the same second).
+pub fn next_crl_number(previous: Option<u64>, this_update: OffsetDateTime) -> u64 {
+    let number = this_update.unix_timestamp().max(0) as u64;
+    match previous {
+        Some(previous) if previous >= number => previous + 1,
+        _ => number,
+    }
+}
+
+/// Return the CRL number of the DER encoded CRL (if it contains one).
+pub fn crl_number(crl: &[u8]) -> Result<Option<u64>, Box<dyn Error>> {
+    let value = match der::crl_extension(crl, der::OID_CRL_NUMBER)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let number = der::expect(value, der::TAG_INTEGER)?;
+    let magnitude = match number.content {
+        [0, rest @ ..] => rest,
+        content => content,
+    };
+    if magnitude.len() > 8 {
+        return Err("The CRL number is too large.".into());
+    }
+    Ok(Some(
+        magnitude
+            .iter()
+            .fold(0u64, |acc, byte| (acc << 8) | *byte as u64),
+    ))
+}
+
+/// Create a CRL (version 2) that is signed by the given CA and contains
+/// all revoked certificates. The CRL number must be higher than the
+/// number of any earlier CRL of the CA (see [next_crl_number]).
+pub fn create_crl(
+    ca_cert: &X509Ref,
+    ca_key: &dyn Signer,
+    revoked: &[RevokedCertificate],
+    crl_number: u64,
+    this_update: OffsetDateTime,
+    next_update: OffsetDateTime,
+) -> Result<X509Crl, Box<dyn Error>> {
+    let mut entries = Vec::new();
+    for certificate in revoked.iter() {
+        let serial_number = BigNum::from_hex_str(certificate.serial_number.as_str())?;
+        let mut entry = vec![
+            der::integer(serial_number.to_vec().as_slice()),
+            der::time(OffsetDateTime::from_unix_timestamp(certificate.revoked_at)?),
+        ];
+        if certificate.reason != RevocationReason::Unspecified {
+            entry.push(der::sequence(&[der::extension(
+                der::OID_CRL_REASON,
+                false,
+                der::enumerated(certificate.reason.code()).as_slice(),
+            )]));
+        }
+        entries.push(der::sequence(entry.as_slice()));
+    }
+
+    let mut extensions = vec![der::extension(
+        der::OID_CRL_NUMBER,
+        false,
+        der::small_integer(crl_number).as_slice(),
+    )];
+    if let Some(key_id) = ca_cert.subject_key_id() {
+        extensions.push(der::extension(
+            der::OID_AUTHORITY_KEY_IDENTIFIER,
+            false,
+            der::sequence(&[der::context(0, false, key_id.as_slice())]).as_slice(),
+        ));
+    }
+
+    let mut tbs = vec![
+        der::small_integer(1),
+        ca_key.signature_algorithm()?,
+        ca_cert.subject_name().to_der()?,
+        der::time(this_update),
+        der::time(next_update),
+    ];
+    if !entries.is_empty() {
+        tbs.push(der::sequence(entries.as_slice()));
+    }
+    tbs.push(der::context(
+        0,
+        true,
+        der::sequence(extensions.as_slice()).as_slice(),
+    ));
+
+    let crl = der::sign(ca_key, der::sequence(tbs.as_slice()).as_slice())?;
+    Ok(X509Crl::from_der(crl.as_slice())?)
+}
+
+/// Create the CRL distribution points extension that points to the given URL.
+pub fn crl_distribution_points(url: &str) -> Result<X509Extension, Box<dyn Error>> {
+    let full_name = der::context(0, true, der::uri_name(url).as_slice());
+    let distribution_point = der::sequence(&[der::context(0, true, full_name.as_slice())]);
+    let value = der::sequence(&[distribution_point]);
+
+    Ok(X509Extension::new_from_der(
+        Asn1Object::from_str("2.5.29.31")?.as_ref(),
+        false,
+        Asn1OctetString::new_from_bytes(value.as_slice())?.as_ref(),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Integer;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::{CrlStatus, ReasonCode, X509Crl, X509};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::cert_store::revocation::{
+        create_crl, crl_distribution_points, crl_number, next_crl_number, RevocationReason,
+        RevokedCertificate,
+    };
+    use crate::cert_store::utils::{create_new_ca, create_new_key, KeyAlgorithm};
+
+    fn revoked(serial_number: &str, reason: RevocationReason) -> RevokedCertificate {
+        RevokedCertificate {
+            serial_number: serial_number.to_string(),
+            revoked_at: 1_650_000_000,
+            reason,
+        }
+    }
+
+    fn status<'a>(crl: &'a X509Crl, serial_number: &str) -> CrlStatus<'a> {
+        let serial_number = BigNum::from_hex_str(serial_number).unwrap();
+        crl.get_by_serial(
+            Asn1Integer::from_bn(serial_number.as_ref())
+                .unwrap()
+                .as_ref(),
+        )
+    }
+
+    #[test]
+    fn crl_is_signed_by_the_ca() {
+        let now = OffsetDateTime::now_utc();
+        for algorithm in [
+            KeyAlgorithm::Rsa2048,
+            KeyAlgorithm::EcdsaP256,
+            KeyAlgorithm::EcdsaP384,
+            KeyAlgorithm::Ed25519,
+        ] {
+            let key = create_new_key(algorithm).unwrap();
+            let ca = create_new_ca(&key).unwrap();
+            let other_key = create_new_key(algorithm).unwrap();
+
+            let crl = create_crl(&ca, &key, &[], 1, now, now + Duration::hours(1)).unwrap();
+            let crl = X509Crl::from_der(crl.to_der().unwrap().as_slice()).unwrap();
+
+            assert!(crl.verify(ca.public_key().unwrap().as_ref()).unwrap());
+            assert!(!crl.verify(other_key.as_ref()).unwrap_or(false));
+            assert_eq!(
+                crl.issuer_name().to_der().unwrap(),
+                ca.subject_name().to_der().unwrap()
+            );
+            assert!(crl.get_revoked().is_none());
+            assert!(crl.next_update().is_some());
+        }
+    }
+
+    #[test]
+    fn crl_contains_serials_and_reasons() {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let ca = create_new_ca(&key).unwrap();
+        let now = OffsetDateTime::now_utc();
+        let revoked = [
+            revoked("1A2B", RevocationReason::Unspecified),
+            // The high bit requires a leading zero in the DER integer.
+            revoked("FF00000000000001", RevocationReason::KeyCompromise),
+            revoked("7C", RevocationReason::PrivilegeWithdrawn),
+        ];
+
+        let crl = create_crl(&ca, &key, &revoked, 1, now, now + Duration::hours(1)).unwrap();
+        let crl = X509Crl::from_pem(crl.to_pem().unwrap().as_slice()).unwrap();
+
+        assert!(crl.verify(ca.public_key().unwrap().as_ref()).unwrap());
+        assert_eq!(crl.get_revoked().unwrap().len(), 3);
+        for certificate in revoked.iter() {
+            let entry = match status(&crl, certificate.serial_number.as_str()) {
+                CrlStatus::Revoked(entry) => entry,
+                _ => panic!("{} is not revoked", certificate.serial_number),
+            };
+            let serial_number = entry.serial_number().to_bn().unwrap().to_hex_str().unwrap();
+            assert_eq!(serial_number.to_string(), certificate.serial_number);
+
+            let reason = entry
+                .extension::<ReasonCode>()
+                .unwrap()
+                .map(|(_, reason)| reason.get_i64().unwrap());
+            match certificate.reason {
+                RevocationReason::Unspecified => assert_eq!(reason, None),
+                reason_code => assert_eq!(reason, Some(i64::from(reason_code.code()))),
+            }
+        }
+        assert!(matches!(status(&crl, "1A2C"), CrlStatus::NotRevoked));
+    }
+
+    #[test]
+    fn crl_numbers_increase() {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let ca = create_new_ca(&key).unwrap();
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+
+        let first = next_crl_number(None, now);
+        assert_eq!(first, 1_700_000_000);
+        // Within the same second or after the clock went backwards.
+        let second = next_crl_number(Some(first), now);
+        assert_eq!(second, first + 1);
+        assert_eq!(
+            next_crl_number(Some(second), now - Duration::minutes(5)),
+            second + 1
+        );
+        assert_eq!(
+            next_crl_number(Some(second), now + Duration::seconds(10)),
+            1_700_000_010
+        );
+
+        for number in [1, 0x80, first, u64::MAX] {
+            let crl = create_crl(&ca, &key, &[], number, now, now + Duration::hours(1)).unwrap();
+            assert_eq!(
+                crl_number(crl.to_der().unwrap().as_slice()).unwrap(),
+                Some(number)
+            );
+        }
+    }
+
+    #[test]
+    fn reason_codes_round_trip() {
+        for code in 0..=10 {
+            match RevocationReason::from_code(code) {
+                Some(reason) => assert_eq!(i32::from(reason.code()), code),
+                None => assert!(code == 7 || code == 8),
+            }
+        }
+    }
+
+    #[test]
+    fn certificate_points_to_the_crl() {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_pubkey(key.as_ref()).unwrap();
+        builder
+            .append_extension(crl_distribution_points("http://pki.example/crl").unwrap())
+            .unwrap();
+        builder.sign(key.as_ref(), MessageDigest::sha256()).unwrap();
+        let certificate = builder.build();
+
+        let text = String::from_utf8(certificate.to_text().unwrap()).unwrap();
+        assert!(text.contains("X509v3 CRL Distribution Points"));
+        assert!(text.contains("URI:http://pki.example/crl"));
+    }
+}
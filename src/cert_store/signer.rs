@@ -0,0 +1,258 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::{Arc, OnceLock};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::sign::Signer as OpensslSigner;
+use openssl::x509::{X509Builder, X509Req, X509ReqBuilder, X509};
+
+use crate::cert_store::der;
+use crate::cert_store::key_encryption::KeyEncryption;
+use crate::cert_store::pkcs11::{Pkcs11Token, PKCS11_URI_PREFIX};
+use crate::cert_store::utils::{create_new_key, signature_digest, KeyAlgorithm};
+
+/// Signs certificates, CRLs and OCSP responses with the private key of a CA.
+/// The key is either held in memory or in a PKCS#11 token, where it never leaves the token.
+pub trait Signer: Debug + Send + Sync {
+    /// Return the public key of the signing key.
+    fn public_key(&self) -> Result<PKey<Public>, Box<dyn Error>>;
+
+    /// Return the DER encoded algorithm identifier of the signatures.
+    fn signature_algorithm(&self) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// Sign the data and return the signature value (as contained in the BIT STRING
+    /// of X.509 structures, i.e. DER encoded for ECDSA).
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// Return the private key if it is held in memory.
+    fn private_key(&self) -> Option<&PKey<Private>> {
+        None
+    }
+
+    /// Return the reference of a key that is not held in memory (e.g. a PKCS#11 URI).
+    fn reference(&self) -> Option<String> {
+        None
+    }
+}
+
+impl Signer for PKey<Private> {
+    fn public_key(&self) -> Result<PKey<Public>, Box<dyn Error>> {
+        Ok(PKey::public_key_from_der(
+            self.public_key_to_der()?.as_slice(),
+        )?)
+    }
+
+    fn signature_algorithm(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        der::signature_algorithm(self.as_ref())
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut signer = match self.id() {
+            Id::ED25519 => OpensslSigner::new_without_digest(self)?,
+            _ => OpensslSigner::new(signature_digest(self), self)?,
+        };
+        Ok(signer.sign_oneshot_to_vec(data)?)
+    }
+
+    fn private_key(&self) -> Option<&PKey<Private>> {
+        Some(self)
+    }
+}
+
+/// Encodes the CA keys for the storage and decodes them again. Keys in memory are
+/// stored as (optionally encrypted) PEM, keys in a PKCS#11 token as reference.
+#[derive(Debug, Clone, Default)]
+pub struct KeyStorage {
+    pub encryption: KeyEncryption,
+    pub token: Option<Arc<Pkcs11Token>>,
+}
+
+impl KeyStorage {
+    /// Return the key for a new CA: the key in the PKCS#11 token
+    /// if a token is configured, a new key in memory otherwise.
+    pub fn create_key(&self, algorithm: KeyAlgorithm) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
+        match self.token.as_ref() {
+            Some(token) => token.key(),
+            None => Ok(Arc::new(create_new_key(algorithm)?)),
+        }
+    }
+
+    /// Return the key for a CA besides the CAs of the store (e.g. an intermediate CA):
+    /// the key with the given label in the PKCS#11 token if a token is configured,
+    /// a new key in memory otherwise.
+    pub fn create_labelled_key(
+        &self,
+        algorithm: KeyAlgorithm,
+        label: &str,
+    ) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
+        match self.token.as_ref() {
+            Some(token) => token.labelled_key(label),
+            None => Ok(Arc::new(create_new_key(algorithm)?)),
+        }
+    }
+
+    pub fn encode(&self, key: &dyn Signer) -> Result<Vec<u8>, Box<dyn Error>> {
+        match (key.private_key(), key.reference()) {
+            (Some(key), _) => self.encryption.encrypt(key.as_ref()),
+            (None, Some(reference)) => Ok(reference.into_bytes()),
+            (None, None) => Err("The CA key can neither be exported nor referenced.".into()),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Arc<dyn Signer>, Box<dyn Error>> {
+        if !data.starts_with(PKCS11_URI_PREFIX.as_bytes()) {
+            return Ok(Arc::new(self.encryption.decrypt(data)?));
+        }
+
+        match self.token.as_ref() {
+            Some(token) => token.resolve(std::str::from_utf8(data)?.trim()),
+            None => {
+                Err("The CA key is stored in a PKCS#11 token, but no token is configured.".into())
+            }
+        }
+    }
+}
+
+/// Sign the certificate with the signer.
+pub fn sign_certificate(
+    mut builder: X509Builder,
+    signer: &dyn Signer,
+) -> Result<X509, Box<dyn Error>> {
+    if let Some(key) = signer.private_key() {
+        builder.sign(key, signature_digest(key))?;
+        return Ok(builder.build());
+    }
+
+    let (placeholder, digest) = placeholder_key(signer)?;
+    builder.sign(placeholder, digest)?;
+    let cert = replace_signature(builder.build().to_der()?.as_slice(), signer)?;
+    Ok(X509::from_der(cert.as_slice())?)
+}
+
+/// Sign the certificate signing request with the signer.
+pub fn sign_request(
+    mut builder: X509ReqBuilder,
+    signer: &dyn Signer,
+) -> Result<X509Req, Box<dyn Error>> {
+    if let Some(key) = signer.private_key() {
+        builder.sign(key, signature_digest(key))?;
+        return Ok(builder.build());
+    }
+
+    let (placeholder, digest) = placeholder_key(signer)?;
+    builder.sign(placeholder, digest)?;
+    let request = replace_signature(builder.build().to_der()?.as_slice(), signer)?;
+    Ok(X509Req::from_der(request.as_slice())?)
+}
+
+/// OpenSSL can only sign with keys in memory. Thus, keys that are not held in memory
+/// sign the structure after OpenSSL signed it with a placeholder key of the same type.
+/// The placeholder keys are created once, since their signatures are replaced anyway.
+fn placeholder_key(
+    signer: &dyn Signer,
+) -> Result<(&'static PKey<Private>, MessageDigest), Box<dyn Error>> {
+    static RSA: OnceLock<PKey<Private>> = OnceLock::new();
+    static EC: OnceLock<PKey<Private>> = OnceLock::new();
+    static ED25519: OnceLock<PKey<Private>> = OnceLock::new();
+
+    let public_key = signer.public_key()?;
+    let (placeholder, algorithm) = match public_key.id() {
+        Id::RSA => (&RSA, KeyAlgorithm::Rsa2048),
+        Id::EC => (&EC, KeyAlgorithm::EcdsaP256),
+        Id::ED25519 => (&ED25519, KeyAlgorithm::Ed25519),
+        _ => return Err("Unsupported key type for signatures.".into()),
+    };
+    let key = match placeholder.get() {
+        Some(key) => key,
+        None => {
+            let key = create_new_key(algorithm)?;
+            placeholder.get_or_init(|| key)
+        }
+    };
+    Ok((key, signature_digest(&public_key)))
+}
+
+/// Replace the signature of the signed structure (`SEQUENCE { data, algorithm, signature }`).
+fn replace_signature(signed: &[u8], signer: &dyn Signer) -> Result<Vec<u8>, Box<dyn Error>> {
+    let element = der::expect(signed, der::TAG_SEQUENCE)?;
+    let fields = der::read_all(element.content)?;
+    if fields.len() != 3 {
+        return Err("Invalid signed structure.".into());
+    }
+
+    let algorithm = signer.signature_algorithm()?;
+    if fields[1].raw != algorithm.as_slice() {
+        return Err("The signature algorithm does not match the algorithm of the key.".into());
+    }
+
+    der::sign(signer, fields[0].raw)
+}
+
+/// A signer that delegates to a key in memory, but does not expose it (like a key
+/// in a PKCS#11 token). Used to test the signing of keys that are not held in memory.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ExternalKey(pub PKey<Private>);
+
+#[cfg(test)]
+impl Signer for ExternalKey {
+    fn public_key(&self) -> Result<PKey<Public>, Box<dyn Error>> {
+        self.0.public_key()
+    }
+
+    fn signature_algorithm(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.0.signature_algorithm()
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Signer::sign(&self.0, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::nid::Nid;
+    use openssl::x509::{X509Name, X509ReqBuilder};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::cert_store::revocation::{create_crl, RevocationReason, RevokedCertificate};
+    use crate::cert_store::signer::{sign_request, ExternalKey, Signer};
+    use crate::cert_store::utils::{create_new_ca, create_new_key, KeyAlgorithm};
+
+    #[test]
+    fn sign_with_keys_that_are_not_in_memory() {
+        for algorithm in [
+            KeyAlgorithm::Rsa2048,
+            KeyAlgorithm::EcdsaP256,
+            KeyAlgorithm::EcdsaP384,
+            KeyAlgorithm::Ed25519,
+        ] {
+            let signer = ExternalKey(create_new_key(algorithm).unwrap());
+            assert!(signer.private_key().is_none());
+            let public_key = signer.public_key().unwrap();
+
+            let cert = create_new_ca(&signer).unwrap();
+            assert!(cert.verify(&public_key).unwrap(), "{:?}", algorithm);
+
+            let mut name = X509Name::builder().unwrap();
+            name.append_entry_by_nid(Nid::COMMONNAME, "external")
+                .unwrap();
+            let mut builder = X509ReqBuilder::new().unwrap();
+            builder.set_subject_name(&name.build()).unwrap();
+            builder.set_pubkey(&public_key).unwrap();
+            let request = sign_request(builder, &signer).unwrap();
+            assert!(request.verify(&public_key).unwrap(), "{:?}", algorithm);
+
+            let now = OffsetDateTime::now_utc();
+            let revoked = [RevokedCertificate {
+                serial_number: "1A".to_string(),
+                revoked_at: now.unix_timestamp(),
+                reason: RevocationReason::KeyCompromise,
+            }];
+            let crl =
+                create_crl(&cert, &signer, &revoked, 1, now, now + Duration::hours(1)).unwrap();
+            assert!(crl.verify(&public_key).unwrap(), "{:?}", algorithm);
+        }
+    }
+}
@@ -0,0 +1,776 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use log::{debug, info};
+use openssl::x509::X509;
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use time::OffsetDateTime;
+
+use crate::cert_store::inventory::{CertificateFilter, CertificateRecord};
+use crate::cert_store::key_encryption::KeyEncryption;
+use crate::cert_store::registry::{StoreBuilder, StoreContext, StoreUrl};
+use crate::cert_store::revocation::{RevocationReason, RevokedCertificate};
+use crate::cert_store::signer::KeyStorage;
+use crate::cert_store::store::{Authorities, CertificateAuthority, CertificateStore};
+use crate::cert_store::utils::{
+    certificates_to_pem, create_new_ca, parse_certificates, KeyAlgorithm,
+};
+
+/// How long a write waits for the lock of another connection to the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// File mode of the database (read and write for the owner only), it contains the CA keys.
+#[cfg(unix)]
+const DATABASE_FILE_MODE: u32 = 0o600;
+
+/// The schema migrations. The schema version (`PRAGMA user_version`) is the
+/// number of applied migrations, thus migrations must only be appended.
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE authorities (
+        -- 0 is the active CA, the retired CAs follow in their order.
+        position INTEGER PRIMARY KEY,
+        cert TEXT NOT NULL,
+        key TEXT NOT NULL,
+        chain TEXT NOT NULL
+    );
+
+    CREATE TABLE issued_certificates (
+        serial_number TEXT PRIMARY KEY,
+        subject TEXT NOT NULL,
+        -- JSON array of the subject alternative names.
+        subject_alt_names TEXT NOT NULL,
+        not_before INTEGER NOT NULL,
+        not_after INTEGER NOT NULL,
+        requester TEXT NOT NULL,
+        profile TEXT NOT NULL
+    );
+
+    CREATE TABLE revoked_certificates (
+        serial_number TEXT PRIMARY KEY,
+        revoked_at INTEGER NOT NULL,
+        -- The reason code of RFC 5280.
+        reason INTEGER NOT NULL
+    );
+
+    CREATE TABLE crl (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        der BLOB NOT NULL
+    );
+"#];
+
+/// The configuration of the SQLite store (`sqlite://<path>`,
+/// e.g. `sqlite:///var/lib/pki/pki.db`).
+#[derive(Debug, Clone)]
+pub struct SqliteStoreConfig {
+    pub path: PathBuf,
+}
+
+impl StoreBuilder for SqliteStoreConfig {
+    fn from_url(url: &StoreUrl) -> Result<Self, Box<dyn Error>> {
+        if url.location().is_empty() {
+            return Err("The SQLite store requires a database path ('sqlite://<path>').".into());
+        }
+
+        Ok(Self {
+            path: PathBuf::from(url.location()),
+        })
+    }
+
+    fn build(self, context: &StoreContext) -> Result<Box<dyn CertificateStore>, Box<dyn Error>> {
+        Ok(Box::new(SqliteStore::new(
+            self.path,
+            context.key_algorithm,
+            context.key_storage.clone(),
+        )))
+    }
+}
+
+/// Stores the PKI in a SQLite database. All changes are written in transactions,
+/// thus the CAs, the inventory and the revocations are always consistent. The
+/// database is accessed on the blocking thread pool, thus a slow query or a database
+/// that is locked by another connection does not block the async runtime.
+#[derive(Debug)]
+pub struct SqliteStore {
+    path: PathBuf,
+    key_algorithm: KeyAlgorithm,
+    key_storage: KeyStorage,
+    connection: Arc<Mutex<Option<Connection>>>,
+    authorities: RwLock<Option<Arc<Authorities>>>,
+}
+
+/// The PEM encoded certificate, key and chain of a CA as stored in the database.
+type AuthorityRow = (String, String, String);
+
+/// Run the blocking operation on the blocking thread pool.
+async fn blocking<T, F>(operation: F) -> Result<T, Box<dyn Error>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    tokio::task::spawn_blocking(operation)
+        .await?
+        .map_err(|e| e as Box<dyn Error>)
+}
+
+impl SqliteStore {
+    pub fn new(path: PathBuf, key_algorithm: KeyAlgorithm, key_storage: KeyStorage) -> Self {
+        Self {
+            path,
+            key_algorithm,
+            key_storage,
+            connection: Arc::new(Mutex::new(None)),
+            authorities: RwLock::new(None),
+        }
+    }
+
+    /// Create the database file that is only accessible by the owner.
+    fn create_database_file(path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(directory) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(directory)?;
+        }
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(DATABASE_FILE_MODE);
+        }
+        options.open(path)?;
+        Ok(())
+    }
+
+    /// Refuse to use a database (with the CA keys) that is accessible by the group or others.
+    #[cfg(unix)]
+    fn check_database_permissions(path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(format!(
+                "The database '{}' holds CA keys and is accessible by other users (mode {:o}), \
+                 restrict it to {:o}.",
+                path.display(),
+                mode & 0o777,
+                DATABASE_FILE_MODE
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check_database_permissions(_path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    /// Open the database (created if it does not exist) and migrate its schema.
+    fn open(path: &Path) -> Result<Connection, Box<dyn Error + Send + Sync>> {
+        match path.exists() {
+            true => Self::check_database_permissions(path)?,
+            false => Self::create_database_file(path)?,
+        }
+        let mut connection = Connection::open(path)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        Self::migrate(&mut connection)?;
+        Ok(connection)
+    }
+
+    /// Apply all migrations that were not applied to the database yet.
+    fn migrate(connection: &mut Connection) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let transaction = connection.transaction()?;
+        let version: i64 = transaction.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let version = usize::try_from(version)?;
+        if version > MIGRATIONS.len() {
+            return Err(format!(
+                "The database schema (version {}) is newer than the PKI (version {}).",
+                version,
+                MIGRATIONS.len()
+            )
+            .into());
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Migrate the database schema to version {}.", index + 1);
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", (index + 1) as i64)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Run the operation with the connection to the database on the blocking thread pool.
+    async fn with_connection<T, F>(&self, operation: F) -> Result<T, Box<dyn Error>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Box<dyn Error + Send + Sync>> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        blocking(move || match connection.lock().unwrap().as_mut() {
+            Some(connection) => operation(connection),
+            None => Err("The database is not initialized.".into()),
+        })
+        .await
+    }
+
+    async fn read_authorities(&self) -> Result<Authorities, Box<dyn Error>> {
+        let rows = self
+            .with_connection(|connection| {
+                let mut statement = connection
+                    .prepare("SELECT cert, key, chain FROM authorities ORDER BY position")?;
+                let rows = statement
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect::<Result<Vec<AuthorityRow>, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+
+        let mut authorities = Vec::new();
+        for (cert, key, chain) in rows {
+            authorities.push(CertificateAuthority {
+                cert: X509::from_pem(cert.as_bytes())?,
+                key: self.key_storage.decode(key.as_bytes())?,
+                chain: parse_certificates(chain.as_bytes())?,
+            });
+        }
+
+        if authorities.is_empty() {
+            return Err("The database does not contain a CA.".into());
+        }
+        let active = authorities.remove(0);
+        Ok(Authorities {
+            active,
+            retired: authorities,
+        })
+    }
+
+    /// Encode the CAs for the database (in memory, the keys may be held in a token).
+    fn authority_rows(
+        &self,
+        authorities: &Authorities,
+    ) -> Result<Vec<AuthorityRow>, Box<dyn Error>> {
+        let mut rows = Vec::new();
+        for authority in authorities.all() {
+            rows.push((
+                String::from_utf8(authority.cert.to_pem()?)?,
+                String::from_utf8(self.key_storage.encode(authority.key.as_ref())?)?,
+                String::from_utf8(certificates_to_pem(authority.chain.as_slice())?)?,
+            ));
+        }
+        Ok(rows)
+    }
+
+    fn write_authorities(
+        transaction: &Transaction,
+        rows: &[AuthorityRow],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        transaction.execute("DELETE FROM authorities", [])?;
+        for (position, (cert, key, chain)) in rows.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO authorities (position, cert, key, chain) VALUES (?1, ?2, ?3, ?4)",
+                params![position as i64, cert, key, chain],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn authority_exists(connection: &Connection) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(connection
+            .query_row("SELECT 1 FROM authorities WHERE position = 0", [], |_| {
+                Ok(())
+            })
+            .optional()?
+            .is_some())
+    }
+
+    fn certificate_record(row: &Row) -> rusqlite::Result<CertificateRecord> {
+        let subject_alt_names: String = row.get(2)?;
+        Ok(CertificateRecord {
+            serial_number: row.get(0)?,
+            subject: row.get(1)?,
+            subject_alt_names: serde_json::from_str(subject_alt_names.as_str()).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, e.into())
+            })?,
+            not_before: row.get(3)?,
+            not_after: row.get(4)?,
+            requester: row.get(5)?,
+            profile: row.get(6)?,
+        })
+    }
+}
+
+const SELECT_CERTIFICATE_RECORD: &str = "SELECT serial_number, subject, subject_alt_names, \
+     not_before, not_after, requester, profile FROM issued_certificates";
+
+#[tonic::async_trait]
+impl CertificateStore for SqliteStore {
+    async fn init(
+        &mut self,
+        bootstrap: Option<CertificateAuthority>,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = self.path.clone();
+        let connection = blocking(move || SqliteStore::open(&path)).await?;
+        *self.connection.lock().unwrap() = Some(connection);
+        info!("Use the SQLite database '{}'.", self.path.display());
+
+        if !self.with_connection(|c| Self::authority_exists(c)).await? {
+            let active = match bootstrap.clone() {
+                Some(authority) => {
+                    info!("CA does not exist, import the given CA.");
+                    authority
+                }
+                None => {
+                    info!("CA does not exist, create new.");
+                    let key = self.key_storage.create_key(self.key_algorithm)?;
+                    CertificateAuthority {
+                        cert: create_new_ca(key.as_ref())?,
+                        key,
+                        chain: Vec::new(),
+                    }
+                }
+            };
+            let rows = self.authority_rows(&Authorities {
+                active,
+                retired: Vec::new(),
+            })?;
+            // Another process may have created the CA in the meantime, it is kept.
+            self.with_connection(move |connection| {
+                let transaction = connection.transaction()?;
+                if !Self::authority_exists(&transaction)? {
+                    Self::write_authorities(&transaction, rows.as_slice())?;
+                }
+                transaction.commit()?;
+                Ok(())
+            })
+            .await?;
+        }
+        let authorities = self.read_authorities().await?;
+
+        if let Some(authority) = bootstrap {
+            authorities.check_import(&authority)?;
+        }
+
+        *self.authorities.get_mut().unwrap() = Some(Arc::new(authorities));
+        debug!("Initialized the SQLite storage.");
+        Ok(())
+    }
+
+    async fn has_authority(&self) -> Result<bool, Box<dyn Error>> {
+        let path = self.path.clone();
+        blocking(move || match path.exists() {
+            true => SqliteStore::authority_exists(&SqliteStore::open(&path)?),
+            false => Ok(false),
+        })
+        .await
+    }
+
+    async fn rewrap_keys(&mut self, encryption: KeyEncryption) -> Result<(), Box<dyn Error>> {
+        if !self.has_authority().await? {
+            return Err(format!(
+                "The SQLite database '{}' does not contain a CA to re-wrap.",
+                self.path.display()
+            )
+            .into());
+        }
+        self.init(None).await?;
+
+        info!("Re-wrap the CA keys in the SQLite database.");
+        self.key_storage.encryption = encryption;
+        self.store_authorities(self.authorities().as_ref().clone())
+            .await
+    }
+
+    fn authorities(&self) -> Arc<Authorities> {
+        self.authorities.read().unwrap().clone().unwrap()
+    }
+
+    async fn store_authorities(&self, authorities: Authorities) -> Result<(), Box<dyn Error>> {
+        debug!("Store CA certificates and keys to the SQLite database.");
+
+        let rows = self.authority_rows(&authorities)?;
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            Self::write_authorities(&transaction, rows.as_slice())?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+
+        *self.authorities.write().unwrap() = Some(Arc::new(authorities));
+        Ok(())
+    }
+
+    async fn revoke_certificate(
+        &self,
+        certificate: RevokedCertificate,
+    ) -> Result<bool, Box<dyn Error>> {
+        debug!("Store revoked certificate to the SQLite database.");
+
+        self.with_connection(move |connection| {
+            let inserted = connection.execute(
+                "INSERT OR IGNORE INTO revoked_certificates (serial_number, revoked_at, reason) \
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    certificate.serial_number,
+                    certificate.revoked_at,
+                    certificate.reason.code(),
+                ],
+            )?;
+            Ok(inserted > 0)
+        })
+        .await
+    }
+
+    async fn revoked_certificates(&self) -> Result<Vec<RevokedCertificate>, Box<dyn Error>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT serial_number, revoked_at, reason FROM revoked_certificates \
+                 ORDER BY revoked_at",
+            )?;
+            let rows = statement.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i32>(2)?,
+                ))
+            })?;
+
+            let mut revoked = Vec::new();
+            for row in rows {
+                let (serial_number, revoked_at, reason) = row?;
+                revoked.push(RevokedCertificate {
+                    serial_number,
+                    revoked_at,
+                    reason: RevocationReason::from_code(reason)
+                        .ok_or_else(|| format!("Unknown revocation reason {}.", reason))?,
+                });
+            }
+            Ok(revoked)
+        })
+        .await
+    }
+
+    async fn record_certificate(&self, record: CertificateRecord) -> Result<(), Box<dyn Error>> {
+        debug!("Store issued certificate to the SQLite database.");
+
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO issued_certificates (serial_number, subject, subject_alt_names, \
+                 not_before, not_after, requester, profile) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    record.serial_number,
+                    record.subject,
+                    serde_json::to_string(&record.subject_alt_names)?,
+                    record.not_before,
+                    record.not_after,
+                    record.requester,
+                    record.profile,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn issued_certificates(&self) -> Result<Vec<CertificateRecord>, Box<dyn Error>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(
+                format!(
+                    "{} ORDER BY not_before, serial_number",
+                    SELECT_CERTIFICATE_RECORD
+                )
+                .as_str(),
+            )?;
+            let records = statement
+                .query_map([], Self::certificate_record)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn issued_certificates_page(
+        &self,
+        filter: &CertificateFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<CertificateRecord>, bool), Box<dyn Error>> {
+        let filter = filter.clone();
+        // Expired certificates are excluded with `not_after >= now` (see `is_expired`).
+        let now = match filter.include_expired {
+            true => i64::MIN,
+            false => OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        let (offset, limit) = (i64::try_from(offset)?, i64::try_from(limit)?);
+        let mut records = self
+            .with_connection(move |connection| {
+                let mut statement = connection.prepare(
+                    format!(
+                        "{} WHERE not_after >= ?1 AND instr(subject, ?2) > 0 \
+                         AND (?3 = '' OR profile = ?3) AND (?4 = '' OR requester = ?4) \
+                         ORDER BY not_before, serial_number LIMIT ?5 OFFSET ?6",
+                        SELECT_CERTIFICATE_RECORD
+                    )
+                    .as_str(),
+                )?;
+                // One more record is loaded to know whether more records match.
+                let records = statement
+                    .query_map(
+                        params![
+                            now,
+                            filter.subject,
+                            filter.profile,
+                            filter.requester,
+                            limit.saturating_add(1),
+                            offset,
+                        ],
+                        Self::certificate_record,
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(records)
+            })
+            .await?;
+
+        let more = records.len() as i64 > limit;
+        records.truncate(limit as usize);
+        Ok((records, more))
+    }
+
+    async fn issued_certificate(
+        &self,
+        serial_number: &str,
+    ) -> Result<Option<CertificateRecord>, Box<dyn Error>> {
+        let serial_number = serial_number.to_string();
+        self.with_connection(move |connection| {
+            Ok(connection
+                .query_row(
+                    format!("{} WHERE serial_number = ?1", SELECT_CERTIFICATE_RECORD).as_str(),
+                    [serial_number],
+                    Self::certificate_record,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn store_crl(&self, crl: &[u8]) -> Result<(), Box<dyn Error>> {
+        debug!("Store CRL to the SQLite database.");
+
+        let crl = crl.to_vec();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO crl (id, der) VALUES (0, ?1) \
+                 ON CONFLICT (id) DO UPDATE SET der = excluded.der",
+                [crl],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn stored_crl(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.with_connection(|connection| {
+            Ok(connection
+                .query_row("SELECT der FROM crl WHERE id = 0", [], |row| row.get(0))
+                .optional()?)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use rusqlite::Connection;
+
+    use crate::cert_store::inventory::{CertificateFilter, CertificateRecord};
+    use crate::cert_store::revocation::{RevocationReason, RevokedCertificate};
+    use crate::cert_store::signer::KeyStorage;
+    use crate::cert_store::sqlite_store::{SqliteStore, MIGRATIONS};
+    use crate::cert_store::store::{Authorities, CertificateAuthority, CertificateStore};
+    use crate::cert_store::utils::{create_new_ca, create_new_key, KeyAlgorithm};
+
+    fn database(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "k8s-pki-sqlite-store-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    async fn open_store(path: &Path) -> SqliteStore {
+        let mut store = SqliteStore::new(
+            path.to_path_buf(),
+            KeyAlgorithm::EcdsaP256,
+            KeyStorage::default(),
+        );
+        store.init(None).await.unwrap();
+        store
+    }
+
+    fn record(serial_number: &str, not_before: i64, profile: &str) -> CertificateRecord {
+        CertificateRecord {
+            serial_number: serial_number.to_string(),
+            subject: format!("CN={}", serial_number),
+            subject_alt_names: vec![format!("{}.example.com", serial_number)],
+            not_before,
+            not_after: i64::MAX,
+            requester: "test".to_string(),
+            profile: profile.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn migrate_the_schema() {
+        let path = database("migrate");
+        open_store(&path).await;
+
+        let version = |path: &Path| -> i64 {
+            Connection::open(path)
+                .unwrap()
+                .query_row("PRAGMA user_version", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(version(&path), MIGRATIONS.len() as i64);
+
+        // Migrations are only applied once.
+        open_store(&path).await;
+        assert_eq!(version(&path), MIGRATIONS.len() as i64);
+
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1)
+            .unwrap();
+        let mut newer =
+            SqliteStore::new(path.clone(), KeyAlgorithm::EcdsaP256, KeyStorage::default());
+        assert!(newer.init(None).await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuse_a_database_accessible_by_others() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = database("permissions");
+        open_store(&path).await;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let mut store =
+            SqliteStore::new(path.clone(), KeyAlgorithm::EcdsaP256, KeyStorage::default());
+        assert!(store.init(None).await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn store_and_load_the_pki() {
+        let path = database("crud");
+        let store = open_store(&path).await;
+
+        let key = create_new_key(KeyAlgorithm::Ed25519).unwrap();
+        let retired = store.authorities().active.clone();
+        store
+            .store_authorities(Authorities {
+                active: CertificateAuthority {
+                    cert: create_new_ca(&key).unwrap(),
+                    key: Arc::new(key),
+                    chain: Vec::new(),
+                },
+                retired: vec![retired],
+            })
+            .await
+            .unwrap();
+
+        let revoked = RevokedCertificate {
+            serial_number: "1A".to_string(),
+            revoked_at: 1,
+            reason: RevocationReason::KeyCompromise,
+        };
+        assert!(store.revoke_certificate(revoked.clone()).await.unwrap());
+        assert!(!store.revoke_certificate(revoked.clone()).await.unwrap());
+        store
+            .record_certificate(record("1A", 1, "a"))
+            .await
+            .unwrap();
+        store.store_crl(b"crl").await.unwrap();
+
+        let reopened = open_store(&path).await;
+        assert!(reopened
+            .authorities()
+            .same_certificates(&store.authorities()));
+        assert_eq!(
+            reopened.revoked_certificates().await.unwrap(),
+            vec![revoked]
+        );
+        assert_eq!(
+            reopened.issued_certificate("1A").await.unwrap(),
+            Some(record("1A", 1, "a"))
+        );
+        assert!(reopened.issued_certificate("1B").await.unwrap().is_none());
+        assert_eq!(reopened.stored_crl().await.unwrap(), Some(b"crl".to_vec()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_pages_of_issued_certificates() {
+        let path = database("pages");
+        let store = open_store(&path).await;
+        for (serial_number, not_before, profile) in [
+            ("01", 3, "a"),
+            ("02", 1, "b"),
+            ("03", 2, "a"),
+            ("04", 4, "a"),
+        ] {
+            store
+                .record_certificate(record(serial_number, not_before, profile))
+                .await
+                .unwrap();
+        }
+        let mut expired = record("05", 0, "a");
+        expired.not_after = 1;
+        store.record_certificate(expired).await.unwrap();
+
+        let serial_numbers = |(records, more): (Vec<CertificateRecord>, bool)| {
+            let serial_numbers: Vec<String> =
+                records.into_iter().map(|r| r.serial_number).collect();
+            (serial_numbers, more)
+        };
+        let filter = CertificateFilter {
+            profile: "a".to_string(),
+            ..CertificateFilter::default()
+        };
+        assert_eq!(
+            serial_numbers(store.issued_certificates_page(&filter, 0, 2).await.unwrap()),
+            (vec!["03".to_string(), "01".to_string()], true)
+        );
+        assert_eq!(
+            serial_numbers(store.issued_certificates_page(&filter, 2, 2).await.unwrap()),
+            (vec!["04".to_string()], false)
+        );
+
+        let filter = CertificateFilter {
+            subject: "CN=0".to_string(),
+            include_expired: true,
+            ..CertificateFilter::default()
+        };
+        let (all, more) = store
+            .issued_certificates_page(&filter, 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 5);
+        assert!(!more);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,60 +1,362 @@
+use std::cmp::Ordering;
 use std::error::Error;
+use std::sync::Arc;
 
 use log::info;
 use openssl::asn1::Asn1Time;
 use openssl::bn::{BigNum, MsbOption};
-use openssl::hash::MessageDigest;
-use openssl::pkey::{PKey, Private};
 use openssl::x509::extension::{
-    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectKeyIdentifier,
+    AuthorityKeyIdentifier, SubjectAlternativeName, SubjectKeyIdentifier,
 };
-use openssl::x509::{X509Req, X509};
+use openssl::x509::{X509Extension, X509NameRef, X509Ref, X509Req, X509};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::cert_store::inventory::{CertificateFilter, CertificateRecord};
+use crate::cert_store::key_encryption::KeyEncryption;
+use crate::cert_store::profile::Profile;
+use crate::cert_store::revocation::RevokedCertificate;
+use crate::cert_store::signer::{sign_certificate, KeyStorage, Signer};
+use crate::cert_store::utils::{certificates_to_pem, is_self_signed, parse_certificates, AltName};
+
+/// A CA certificate together with its private key.
+#[derive(Debug, Clone)]
+pub struct CertificateAuthority {
+    pub cert: X509,
+    pub key: Arc<dyn Signer>,
+    /// The certificates of the issuers of an intermediate CA
+    /// (starting with the direct issuer). Empty for a root CA.
+    pub chain: Vec<X509>,
+}
+
+impl CertificateAuthority {
+    /// Check if the CA is an intermediate CA that is signed by another (offline) CA.
+    pub fn is_intermediate(&self) -> bool {
+        !self.chain.is_empty()
+    }
+}
+
+/// The persisted form of a [CertificateAuthority] (PEM encoded).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredAuthority {
+    cert: String,
+    key: String,
+    #[serde(default)]
+    chain: String,
+}
+
+impl StoredAuthority {
+    /// Encode the CA, the key is encoded with the given key storage.
+    pub fn encode(
+        authority: &CertificateAuthority,
+        key_storage: &KeyStorage,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            cert: String::from_utf8(authority.cert.to_pem()?)?,
+            key: String::from_utf8(key_storage.encode(authority.key.as_ref())?)?,
+            chain: String::from_utf8(certificates_to_pem(authority.chain.as_slice())?)?,
+        })
+    }
+
+    /// Decode the CA, the key is decoded with the given key storage.
+    pub fn decode(&self, key_storage: &KeyStorage) -> Result<CertificateAuthority, Box<dyn Error>> {
+        Ok(CertificateAuthority {
+            cert: X509::from_pem(self.cert.as_bytes())?,
+            key: key_storage.decode(self.key.as_bytes())?,
+            chain: parse_certificates(self.chain.as_bytes())?,
+        })
+    }
+}
+
+/// The certificate authorities of the PKI. The active CA signs all new
+/// certificates, retired CAs are kept (and trusted) after a rotation
+/// until they expire.
+#[derive(Debug, Clone)]
+pub struct Authorities {
+    pub active: CertificateAuthority,
+    pub retired: Vec<CertificateAuthority>,
+}
+
+impl Authorities {
+    /// Return all CAs, starting with the active one.
+    pub fn all(&self) -> impl Iterator<Item = &CertificateAuthority> {
+        std::iter::once(&self.active).chain(self.retired.iter())
+    }
+
+    /// Return the retired CAs in their persisted form.
+    pub fn stored_retired(
+        &self,
+        key_storage: &KeyStorage,
+    ) -> Result<Vec<StoredAuthority>, Box<dyn Error>> {
+        self.retired
+            .iter()
+            .map(|authority| StoredAuthority::encode(authority, key_storage))
+            .collect()
+    }
+
+    /// Whether both contain the same CA certificates and chains (the keys are not compared).
+    pub fn same_certificates(&self, other: &Authorities) -> bool {
+        let certificates = |authorities: &Authorities| -> Vec<X509> {
+            authorities
+                .all()
+                .flat_map(|authority| std::iter::once(&authority.cert).chain(&authority.chain))
+                .cloned()
+                .collect()
+        };
+        certificates(self) == certificates(other)
+    }
+
+    /// Whether the CAs are an update of stored CAs whose active CA is the given one:
+    /// the active CA is kept, retired or renewed with the same key. Otherwise, the
+    /// stored CAs were changed by someone else since these CAs were read.
+    pub fn succeeds(&self, stored_active: &X509Ref) -> Result<bool, Box<dyn Error>> {
+        let stored_key = stored_active.public_key()?;
+        for authority in self.all() {
+            if authority.cert.as_ref() == stored_active
+                || authority.key.public_key()?.public_eq(stored_key.as_ref())
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Check that a CA which shall be imported into a store that already contains
+    /// CAs is one of them, i.e. it was imported before. Another CA is refused.
+    pub fn check_import(&self, authority: &CertificateAuthority) -> Result<(), Box<dyn Error>> {
+        if self
+            .all()
+            .any(|stored| stored.cert.as_ref() == authority.cert.as_ref())
+        {
+            return Ok(());
+        }
+
+        Err(
+            "The store already contains another CA, the given CA cannot be imported. \
+             Remove the import configuration or use an empty store."
+                .into(),
+        )
+    }
+
+    /// Parse the persisted retired CAs.
+    pub fn parse_retired(
+        stored: &[StoredAuthority],
+        key_storage: &KeyStorage,
+    ) -> Result<Vec<CertificateAuthority>, Box<dyn Error>> {
+        stored
+            .iter()
+            .map(|authority| authority.decode(key_storage))
+            .collect()
+    }
+}
 
 #[tonic::async_trait]
 pub trait CertificateStore: Send + Sync {
-    async fn init(&mut self) -> Result<(), Box<dyn Error>>;
-    fn cert(&self) -> &X509;
-    fn key(&self) -> &PKey<Private>;
+    /// Load the CAs of the store. If the store does not contain a CA yet, the
+    /// `bootstrap` CA is persisted, or a new self-signed CA is created if none is given.
+    async fn init(&mut self, bootstrap: Option<CertificateAuthority>)
+        -> Result<(), Box<dyn Error>>;
+
+    /// Whether the store already contains a CA. Can be called before `init`,
+    /// e.g. to install a CA only in an empty store.
+    async fn has_authority(&self) -> Result<bool, Box<dyn Error>>;
+
+    /// Load the existing CAs of the store (instead of `init`), encrypt their keys with
+    /// the given key encryption from now on and persist them again, i.e. re-wrap them
+    /// under a new key-encryption-key. Fails if the store does not contain a CA yet,
+    /// thus a re-wrap never creates a CA.
+    async fn rewrap_keys(&mut self, _encryption: KeyEncryption) -> Result<(), Box<dyn Error>> {
+        Err("The storage does not support the encryption of the CA keys.".into())
+    }
 
-    async fn sign_csr(&self, request: X509Req) -> Result<X509, Box<dyn Error>> {
-        let ca_cert = self.cert();
-        let ca_key = self.key();
+    /// Watch the storage for changes of the CAs by other replicas and load them.
+    /// Returns immediately if the storage cannot be changed by others.
+    async fn watch(self: Arc<Self>) {}
+
+    /// Return a receiver that is notified whenever the CAs were changed by someone
+    /// else and reloaded from the storage. The value counts the reloads.
+    fn reloads(&self) -> watch::Receiver<u64> {
+        watch::channel(0).1
+    }
+
+    /// Return a receiver that is notified whenever another replica revoked a
+    /// certificate, thus the leader can regenerate the CRLs. The value counts
+    /// the changes of the revocations.
+    fn revocations(&self) -> watch::Receiver<u64> {
+        watch::channel(0).1
+    }
+
+    /// Return a snapshot of the certificate authorities of the store. The snapshot
+    /// is shared, thus the keys are not copied for every signing operation.
+    fn authorities(&self) -> Arc<Authorities>;
+
+    /// Persist the given authorities and use them for all further operations.
+    async fn store_authorities(&self, authorities: Authorities) -> Result<(), Box<dyn Error>>;
+
+    /// Return the certificate of the active CA.
+    fn cert(&self) -> X509 {
+        self.authorities().active.cert.clone()
+    }
+
+    /// Return the certificates of all CAs that are currently trusted (the active
+    /// CA and the retired CAs together with the issuers of intermediate CAs).
+    fn trust_bundle(&self) -> Vec<X509> {
+        let mut bundle: Vec<X509> = Vec::new();
+        for authority in self.authorities().all() {
+            for cert in std::iter::once(&authority.cert).chain(authority.chain.iter()) {
+                if !bundle.iter().any(|c| c.as_ref() == cert.as_ref()) {
+                    bundle.push(cert.clone());
+                }
+            }
+        }
+        bundle
+    }
+
+    /// Return the chain of a certificate that was signed by one of the CAs:
+    /// the certificate itself followed by the intermediate CA certificates.
+    /// Root CAs are not part of the chain.
+    fn certificate_chain(&self, cert: &X509) -> Vec<X509> {
+        let mut chain = vec![cert.clone()];
+        let authorities = self.authorities();
+        let authority = authorities.all().find(|authority| {
+            authority
+                .cert
+                .public_key()
+                .and_then(|key| cert.verify(&key))
+                .unwrap_or(false)
+        });
+        if let Some(authority) = authority {
+            chain.extend(
+                std::iter::once(&authority.cert)
+                    .chain(authority.chain.iter())
+                    .filter(|cert| !is_self_signed(cert))
+                    .cloned(),
+            );
+        }
+        chain
+    }
+
+    /// Mark the certificate as revoked. Returns `false` if the
+    /// certificate was already revoked before.
+    async fn revoke_certificate(
+        &self,
+        certificate: RevokedCertificate,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    /// Return all certificates that were revoked by the PKI.
+    async fn revoked_certificates(&self) -> Result<Vec<RevokedCertificate>, Box<dyn Error>>;
+
+    /// Add the certificate to the inventory of issued certificates.
+    async fn record_certificate(&self, record: CertificateRecord) -> Result<(), Box<dyn Error>>;
+
+    /// Return all certificates that were issued by the PKI.
+    async fn issued_certificates(&self) -> Result<Vec<CertificateRecord>, Box<dyn Error>>;
+
+    /// Return at most `limit` issued certificates that match the filter, after skipping
+    /// the first `offset` matching ones, and whether more certificates match.
+    async fn issued_certificates_page(
+        &self,
+        filter: &CertificateFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<CertificateRecord>, bool), Box<dyn Error>> {
+        let mut matching = self
+            .issued_certificates()
+            .await?
+            .into_iter()
+            .filter(|record| filter.matches(record))
+            .skip(offset);
+        let page: Vec<CertificateRecord> = matching.by_ref().take(limit).collect();
+        Ok((page, matching.next().is_some()))
+    }
+
+    /// Return the issued certificate with the given (normalized) serial number.
+    async fn issued_certificate(
+        &self,
+        serial_number: &str,
+    ) -> Result<Option<CertificateRecord>, Box<dyn Error>> {
+        let issued = self.issued_certificates().await?;
+        Ok(issued
+            .into_iter()
+            .find(|record| record.serial_number == serial_number))
+    }
+
+    /// Store the current CRL (DER encoded), thus other replicas can serve it.
+    async fn store_crl(&self, _crl: &[u8]) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Return the CRL that was stored with `store_crl`.
+    /// Returns `None` if the storage does not share the CRL.
+    async fn stored_crl(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    /// Sign the CSR with the CA according to the given profile. The certificate is
+    /// issued for the given subject and subject alternative names (which may differ
+    /// from the ones requested in the CSR). The given extensions are added to the
+    /// certificate in addition to the extensions of the profile.
+    async fn sign_csr(
+        &self,
+        request: X509Req,
+        subject: &X509NameRef,
+        alt_names: &[AltName],
+        profile: &Profile,
+        extensions: &[X509Extension],
+    ) -> Result<X509, Box<dyn Error>> {
+        let CertificateAuthority {
+            cert: ca_cert,
+            key: ca_key,
+            ..
+        } = self.authorities().active.clone();
         let not_before = Asn1Time::days_from_now(0)?;
-        let not_after = Asn1Time::days_from_now(365 * 5)?;
+        let not_after = profile.not_after()?;
+        // Certificates must not outlive their issuer, thus a retired CA can be
+        // removed from the trust bundle as soon as it expires.
+        let not_after = match not_after.compare(ca_cert.not_after())? {
+            Ordering::Greater => ca_cert.not_after(),
+            _ => not_after.as_ref(),
+        };
 
         let mut builder = X509::builder()?;
         builder.set_version(request.version())?;
-        builder.set_subject_name(request.subject_name())?;
+        builder.set_subject_name(subject)?;
         builder.set_pubkey(request.public_key()?.as_ref())?;
         builder.set_not_before(not_before.as_ref())?;
-        builder.set_not_after(not_after.as_ref())?;
-
-        builder.append_extension(BasicConstraints::new().build()?)?;
-        builder.append_extension(
-            KeyUsage::new()
-                .critical()
-                .non_repudiation()
-                .digital_signature()
-                .key_encipherment()
-                .build()?,
-        )?;
-        builder.append_extension(
-            ExtendedKeyUsage::new()
-                .client_auth()
-                .server_auth()
-                .build()?,
-        )?;
+        builder.set_not_after(not_after)?;
+
+        for extension in profile.extensions(request.public_key()?.as_ref())? {
+            builder.append_extension(extension)?;
+        }
+        for extension in extensions.iter() {
+            builder.append_extension2(extension)?;
+        }
 
         let subject_key_identifier =
-            SubjectKeyIdentifier::new().build(&builder.x509v3_context(Some(ca_cert), None))?;
+            SubjectKeyIdentifier::new().build(&builder.x509v3_context(Some(&ca_cert), None))?;
         builder.append_extension(subject_key_identifier)?;
 
         let auth_key_identifier = AuthorityKeyIdentifier::new()
             .keyid(false)
             .issuer(false)
-            .build(&builder.x509v3_context(Some(ca_cert), None))?;
+            .build(&builder.x509v3_context(Some(&ca_cert), None))?;
         builder.append_extension(auth_key_identifier)?;
 
+        if !alt_names.is_empty() {
+            let mut subject_alt_name = SubjectAlternativeName::new();
+            for name in alt_names.iter() {
+                match name {
+                    AltName::Dns(dns) => subject_alt_name.dns(dns),
+                    AltName::Uri(uri) => subject_alt_name.uri(uri),
+                    AltName::Ip(ip) => subject_alt_name.ip(ip.to_string().as_str()),
+                };
+            }
+            let subject_alt_name =
+                subject_alt_name.build(&builder.x509v3_context(Some(&ca_cert), None))?;
+            builder.append_extension(subject_alt_name)?;
+        }
+
         builder.set_issuer_name(ca_cert.subject_name())?;
         let serial_number = {
             let mut serial = BigNum::new()?;
@@ -62,10 +364,59 @@ pub trait CertificateStore: Send + Sync {
             serial.to_asn1_integer()?
         };
         builder.set_serial_number(&serial_number)?;
-        builder.sign(ca_key.as_ref(), MessageDigest::sha256())?;
+        let certificate = sign_certificate(builder, ca_key.as_ref())?;
+
+        info!(
+            "Sign CSR for '{:?}' with profile '{}'.",
+            subject, profile.name
+        );
+
+        Ok(certificate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::cert_store::store::{Authorities, CertificateAuthority};
+    use crate::cert_store::utils::{create_new_ca, create_new_key, KeyAlgorithm};
+
+    fn authority() -> CertificateAuthority {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        CertificateAuthority {
+            cert: create_new_ca(&key).unwrap(),
+            key: Arc::new(key),
+            chain: Vec::new(),
+        }
+    }
 
-        info!("Sign CSR for '{:?}'.", request.subject_name());
+    #[test]
+    fn updates_succeed_the_stored_active_ca() {
+        let stored = authority();
+        let unchanged = Authorities {
+            active: stored.clone(),
+            retired: Vec::new(),
+        };
+        let rotated = Authorities {
+            active: authority(),
+            retired: vec![stored.clone()],
+        };
+        let renewed = Authorities {
+            active: CertificateAuthority {
+                cert: create_new_ca(stored.key.as_ref()).unwrap(),
+                ..stored.clone()
+            },
+            retired: Vec::new(),
+        };
+        let concurrent = Authorities {
+            active: authority(),
+            retired: Vec::new(),
+        };
 
-        Ok(builder.build())
+        assert!(unchanged.succeeds(&stored.cert).unwrap());
+        assert!(rotated.succeeds(&stored.cert).unwrap());
+        assert!(renewed.succeeds(&stored.cert).unwrap());
+        assert!(!concurrent.succeeds(&stored.cert).unwrap());
     }
 }
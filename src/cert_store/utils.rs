@@ -1,29 +1,103 @@
 use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
 
 use openssl::asn1::Asn1Time;
 use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
-use openssl::pkey::{PKey, PKeyRef, Private};
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private};
 use openssl::rsa::Rsa;
 use openssl::x509::extension::{BasicConstraints, KeyUsage, SubjectKeyIdentifier};
-use openssl::x509::{X509Name, X509};
+use openssl::x509::{
+    GeneralNameRef, X509Name, X509NameRef, X509Ref, X509ReqRef, X509VerifyResult, X509,
+};
 
-pub fn create_new_key() -> Result<PKey<Private>, Box<dyn Error>> {
-    let rsa = Rsa::generate(2048)?;
-    let key = PKey::from_rsa(rsa)?;
+use crate::cert_store::der;
+use crate::cert_store::signer::{sign_certificate, Signer};
+
+/// The validity of CA certificates that are created by the PKI.
+pub const CA_VALIDITY_DAYS: u32 = 365 * 5;
+
+/// The algorithm of the keys that are created by the PKI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rsa-2048" => Ok(KeyAlgorithm::Rsa2048),
+            "rsa-3072" => Ok(KeyAlgorithm::Rsa3072),
+            "rsa-4096" => Ok(KeyAlgorithm::Rsa4096),
+            "ecdsa-p256" => Ok(KeyAlgorithm::EcdsaP256),
+            "ecdsa-p384" => Ok(KeyAlgorithm::EcdsaP384),
+            "ed25519" => Ok(KeyAlgorithm::Ed25519),
+            _ => Err(format!(
+                "Unknown key algorithm '{}', supported are rsa-2048, rsa-3072, rsa-4096, \
+                 ecdsa-p256, ecdsa-p384 and ed25519.",
+                s
+            )),
+        }
+    }
+}
+
+pub fn create_new_key(algorithm: KeyAlgorithm) -> Result<PKey<Private>, Box<dyn Error>> {
+    let key = match algorithm {
+        KeyAlgorithm::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?)?,
+        KeyAlgorithm::Rsa3072 => PKey::from_rsa(Rsa::generate(3072)?)?,
+        KeyAlgorithm::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?)?,
+        KeyAlgorithm::EcdsaP256 => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+            PKey::from_ec_key(EcKey::generate(group.as_ref())?)?
+        }
+        KeyAlgorithm::EcdsaP384 => {
+            let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+            PKey::from_ec_key(EcKey::generate(group.as_ref())?)?
+        }
+        KeyAlgorithm::Ed25519 => PKey::generate_ed25519()?,
+    };
 
     Ok(key)
 }
 
-pub fn create_new_ca(key: &PKeyRef<Private>) -> Result<X509, Box<dyn Error>> {
+/// Return the digest that is used for signatures with the given key:
+/// SHA-384 for P-384 keys, none for Ed25519 (which hashes internally)
+/// and SHA-256 for all other keys.
+pub fn signature_digest<T: HasPublic>(key: &PKeyRef<T>) -> MessageDigest {
+    match key.id() {
+        Id::ED25519 => MessageDigest::null(),
+        Id::EC => match key.ec_key().ok().and_then(|key| key.group().curve_name()) {
+            Some(Nid::SECP384R1) => MessageDigest::sha384(),
+            _ => MessageDigest::sha256(),
+        },
+        _ => MessageDigest::sha256(),
+    }
+}
+
+/// The subject of the CAs that are created by the PKI.
+fn ca_name() -> Result<X509Name, Box<dyn Error>> {
     let mut name = X509Name::builder()?;
     name.append_entry_by_nid(Nid::COMMONNAME, "PKI")?;
     name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "WirePact PKI CA")?;
-    let name = name.build();
+    Ok(name.build())
+}
+
+pub fn create_new_ca(key: &dyn Signer) -> Result<X509, Box<dyn Error>> {
+    let name = ca_name()?;
 
     let not_before = Asn1Time::days_from_now(0)?;
-    let not_after = Asn1Time::days_from_now(365 * 5)?;
+    let not_after = Asn1Time::days_from_now(CA_VALIDITY_DAYS)?;
 
     let mut builder = X509::builder()?;
     builder.set_version(2)?;
@@ -38,7 +112,7 @@ pub fn create_new_ca(key: &PKeyRef<Private>) -> Result<X509, Box<dyn Error>> {
 
     builder.set_not_before(not_before.as_ref())?;
     builder.set_not_after(not_after.as_ref())?;
-    builder.set_pubkey(key)?;
+    builder.set_pubkey(key.public_key()?.as_ref())?;
 
     builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
     builder.append_extension(
@@ -53,7 +127,196 @@ pub fn create_new_ca(key: &PKeyRef<Private>) -> Result<X509, Box<dyn Error>> {
         SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?;
     builder.append_extension(subject_key_identifier)?;
 
-    builder.sign(key, MessageDigest::sha256())?;
+    sign_certificate(builder, key)
+}
+
+/// A subject alternative name that was requested in a CSR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AltName {
+    Dns(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+impl Display for AltName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AltName::Dns(name) => write!(f, "DNS:{}", name),
+            AltName::Uri(uri) => write!(f, "URI:{}", uri),
+            AltName::Ip(ip) => write!(f, "IP:{}", ip),
+        }
+    }
+}
+
+/// Extract the subject alternative names that are requested in the
+/// extensions of the given CSR. Only DNS names, URIs and IP addresses
+/// are supported, all other kinds of names result in an error.
+pub fn requested_alt_names(request: &X509ReqRef) -> Result<Vec<AltName>, Box<dyn Error>> {
+    let extensions = match request.extensions() {
+        Ok(extensions) => extensions,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    // OpenSSL does not allow to inspect single extensions of a CSR, thus the
+    // extensions are placed into an (unsigned) certificate to parse the names.
+    let mut builder = X509::builder()?;
+    for extension in extensions.iter() {
+        builder.append_extension2(extension)?;
+    }
+    let names = match builder.build().subject_alt_names() {
+        Some(names) => names,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut result = Vec::new();
+    for name in names.iter() {
+        match to_alt_name(name)? {
+            Some(name) => result.push(name),
+            None => {
+                return Err("Only DNS, URI and IP subject alternative names are supported.".into())
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Return the subject alternative names of the certificate.
+/// Unsupported kinds of names are skipped.
+pub fn certificate_alt_names(cert: &X509Ref) -> Result<Vec<AltName>, Box<dyn Error>> {
+    let mut result = Vec::new();
+    if let Some(names) = cert.subject_alt_names() {
+        for name in names.iter() {
+            if let Some(name) = to_alt_name(name)? {
+                result.push(name);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn to_alt_name(name: &GeneralNameRef) -> Result<Option<AltName>, Box<dyn Error>> {
+    if let Some(dns) = name.dnsname() {
+        Ok(Some(AltName::Dns(dns.to_string())))
+    } else if let Some(uri) = name.uri() {
+        Ok(Some(AltName::Uri(uri.to_string())))
+    } else if let Some(ip) = name.ipaddress() {
+        let ip = match ip.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(ip)?),
+            16 => IpAddr::from(<[u8; 16]>::try_from(ip)?),
+            _ => return Err("Invalid IP address in subject alternative names.".into()),
+        };
+        Ok(Some(AltName::Ip(ip)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Format the name in the usual one line notation (e.g. `CN=demo, O=WirePact`).
+pub fn name_to_string(name: &X509NameRef) -> Result<String, Box<dyn Error>> {
+    let mut parts = Vec::new();
+    for entry in name.entries() {
+        let key = entry.object().nid().short_name()?;
+        let value = entry.data().as_utf8()?;
+        parts.push(format!("{}={}", key, value));
+    }
+
+    Ok(parts.join(", "))
+}
+
+/// Check that the certificate may be used as CA, i.e. it has the basic
+/// constraints `CA:TRUE` and the key usage `keyCertSign`.
+pub fn check_ca_certificate(cert: &X509Ref) -> Result<(), Box<dyn Error>> {
+    let cert = cert.to_der()?;
+
+    let is_ca = match der::certificate_extension(cert.as_slice(), der::OID_BASIC_CONSTRAINTS)? {
+        Some(value) => {
+            let constraints = der::expect(value, der::TAG_SEQUENCE)?;
+            matches!(
+                der::read_all(constraints.content)?.first(),
+                Some(ca) if ca.tag == der::TAG_BOOLEAN && ca.content == [0xff]
+            )
+        }
+        None => false,
+    };
+    if !is_ca {
+        return Err("The certificate is not a CA certificate (basic constraints CA:TRUE).".into());
+    }
+
+    let key_cert_sign = match der::certificate_extension(cert.as_slice(), der::OID_KEY_USAGE)? {
+        // The first byte of the bit string contains the number of unused bits,
+        // keyCertSign is the sixth bit of the usages.
+        Some(value) => der::expect(value, der::TAG_BIT_STRING)?
+            .content
+            .get(1)
+            .map(|usages| usages & 0x04 != 0)
+            .unwrap_or(false),
+        None => false,
+    };
+    if !key_cert_sign {
+        return Err(
+            "The certificate is not allowed to sign certificates (key usage keyCertSign).".into(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Check if the certificate is a self-signed CA that was created by the PKI
+/// (see [create_new_ca]), i.e. it was neither imported nor issued by another CA.
+pub fn is_created_ca(cert: &X509Ref) -> bool {
+    let created_name = ca_name()
+        .and_then(|name| Ok(name_to_string(&name)? == name_to_string(cert.subject_name())?));
+    is_self_signed(cert) && created_name.unwrap_or(false)
+}
+
+/// Check if the certificate is self-signed (i.e. a root CA).
+pub fn is_self_signed(cert: &X509Ref) -> bool {
+    cert.issued(cert) == X509VerifyResult::OK
+        && cert
+            .public_key()
+            .and_then(|key| cert.verify(key.as_ref()))
+            .unwrap_or(false)
+}
+
+/// Parse a list of PEM encoded certificates. Empty data results in an empty list.
+pub fn parse_certificates(pem: &[u8]) -> Result<Vec<X509>, Box<dyn Error>> {
+    if pem.iter().all(|b| b.is_ascii_whitespace()) {
+        return Ok(Vec::new());
+    }
+
+    Ok(X509::stack_from_pem(pem)?)
+}
+
+/// Encode a list of certificates as concatenated PEM.
+pub fn certificates_to_pem(certs: &[X509]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut pem = Vec::new();
+    for cert in certs.iter() {
+        pem.extend(cert.to_pem()?);
+    }
+
+    Ok(pem)
+}
+
+/// Check that every certificate is signed by the next one in the chain.
+pub fn verify_chain(cert: &X509, chain: &[X509]) -> Result<(), Box<dyn Error>> {
+    let mut current = cert;
+    for issuer in chain.iter() {
+        check_ca_certificate(issuer.as_ref())?;
+        if !current.verify(issuer.public_key()?.as_ref())? {
+            return Err(
+                "The chain is not valid, a certificate is not signed by its successor.".into(),
+            );
+        }
+        current = issuer;
+    }
+
+    if let Some((_, issuers)) = chain.split_last() {
+        if issuers.iter().any(|c| is_self_signed(c)) {
+            return Err("Only the last certificate of the chain may be a root CA.".into());
+        }
+    }
 
-    Ok(builder.build())
+    Ok(())
 }
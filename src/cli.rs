@@ -0,0 +1,773 @@
+//! The command line of the PKI, see [run].
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::Parser;
+use log::{info, warn};
+use regex::Regex;
+use tonic::transport::Server;
+
+use crate::ca_rotation::CaRotation;
+use crate::cert_store::import::{authority_from_secret, parse_authority};
+use crate::cert_store::intermediate::{
+    create_intermediate_csr, install_intermediate, intermediate_authority, read_intermediate_key,
+    write_intermediate_key,
+};
+use crate::cert_store::key_encryption::KeyEncryption;
+use crate::cert_store::pkcs11::Pkcs11Token;
+use crate::cert_store::profile::{Profiles, DEFAULT_PROFILE};
+use crate::cert_store::registry::{
+    DEFAULT_STORE, FILE_SCHEME, KUBERNETES_SCHEME, MEMORY_STORE, SQLITE_SCHEME,
+};
+use crate::cert_store::signer::KeyStorage;
+use crate::cert_store::store::CertificateStore;
+use crate::cert_store::utils::{KeyAlgorithm, CA_VALIDITY_DAYS};
+use crate::cert_store::{KubernetesContext, StoreContext, StoreRegistry, StoreUrl};
+use crate::crl::CrlPublisher;
+use crate::csr_controller::CsrController;
+use crate::http_service::{HttpService, PATH_PREFIX};
+use crate::issuer_controller::IssuerController;
+use crate::leader_election::LeaderElection;
+use crate::ocsp::OcspResponder;
+use crate::pki_service::{grpc, ApiKey, IssuerUrls, PkiService};
+use crate::policy::{
+    subject_name, EcCurve, IpRange, IssuancePolicy, KeyPolicy, SanPolicy, SubjectAttribute,
+    SubjectPolicy,
+};
+use crate::trust_distributor::TrustDistributor;
+
+/// The directory of the deprecated local storage (`LOCAL`) if none is given.
+const DEFAULT_LOCAL_DIRECTORY: &str = "./ca";
+
+#[derive(Parser, Debug)]
+#[clap(name = "k8s-pki", version, about, long_about = None)]
+struct Cli {
+    /// The port that the server will listen on.
+    #[clap(short, long, env, default_value = "8080")]
+    port: u16,
+
+    /// The store of the PKI data (CA, key, issued and revoked certificates):
+    /// `k8s://<namespace>/<secret>` (Kubernetes secret, the namespace may be omitted),
+    /// `file://<directory>` (local file system), `sqlite://<path>` (SQLite database)
+    /// or `memory://` (in memory only).
+    #[clap(long, env, default_value = DEFAULT_STORE)]
+    store: StoreUrl,
+
+    /// If set, the PKI is only kept in memory (`memory://` store) and a new CA is
+    /// created on every start. Intended for dev clusters and tests. Must not be
+    /// combined with a store other than the default one.
+    #[clap(long, env)]
+    ephemeral: bool,
+
+    /// The Kubernetes namespace of the PKI (secret, lease and events).
+    /// If omitted, the namespace of the current kubeconfig context, the `POD_NAMESPACE`
+    /// environment variable or the namespace of the service account is used.
+    #[clap(long, env)]
+    namespace: Option<String>,
+
+    /// An API key that is used to secure the endpoints that are exposed.
+    /// If provided, all gRPC calls to the PKI must set the HTTP `Authorization` header
+    /// to this value or the call will be rejected.
+    ///
+    /// This is useful to enable an exposed PKI to the public, but only allow
+    /// services with the pre-shared key to access the PKI.
+    ///
+    /// #### Example
+    /// When the API key is set to `my-secret-key`, the requests with `HTTP Authorization`
+    /// header set to `my-secret-key` will be accepted (no prefix required/allowed).
+    #[clap(long, env)]
+    api_key: Option<String>,
+
+    /// Comma separated list of named API keys in the format `<identity>=<key>`.
+    /// The keys are accepted in addition to the `api_key` and the identity of the
+    /// matching key is recorded as requester of issued certificates.
+    #[clap(long, env, value_delimiter = ',')]
+    api_keys: Vec<ApiKey>,
+
+    /// Comma separated list of DNS suffixes (e.g. `svc.cluster.local`) that
+    /// are allowed as DNS subject alternative names in CSRs.
+    /// If omitted, DNS names are not restricted.
+    #[clap(long, env, value_delimiter = ',')]
+    allowed_dns_suffixes: Vec<String>,
+
+    /// Comma separated list of URI schemes (e.g. `spiffe`) that
+    /// are allowed as URI subject alternative names in CSRs.
+    /// If omitted, URIs are not restricted.
+    #[clap(long, env, value_delimiter = ',')]
+    allowed_uri_schemes: Vec<String>,
+
+    /// Comma separated list of IP ranges in CIDR notation (e.g. `10.0.0.0/8`) that
+    /// are allowed as IP subject alternative names in CSRs.
+    /// If omitted, IP addresses are not restricted.
+    #[clap(long, env, value_delimiter = ',')]
+    allowed_ip_ranges: Vec<IpRange>,
+
+    /// Comma separated list of subject attributes (short names, e.g. `CN,OU`) that
+    /// may be requested in CSRs. If omitted, all attributes are allowed.
+    #[clap(long, env, value_delimiter = ',')]
+    allowed_subject_attributes: Vec<String>,
+
+    /// Comma separated list of subject attributes (short names) that must be requested in CSRs.
+    #[clap(long, env, value_delimiter = ',')]
+    required_subject_attributes: Vec<String>,
+
+    /// Regular expression that the requested common name must match completely.
+    #[clap(long, env)]
+    common_name_pattern: Option<String>,
+
+    /// Comma separated list of subject attributes in the format `<name>=<value>`
+    /// (e.g. `O=WirePact`) that are always set by the PKI and replace requested values.
+    #[clap(long, env, value_delimiter = ',')]
+    forced_subject_attributes: Vec<SubjectAttribute>,
+
+    /// If set, the requested subject and subject alternative names are ignored and the
+    /// certificates are issued with the identity of the caller (name of the API key)
+    /// as common name.
+    #[clap(long, env)]
+    subject_from_identity: bool,
+
+    /// The minimum size (in bits) of RSA keys in CSRs.
+    #[clap(long, env, default_value = "2048")]
+    min_rsa_key_bits: u32,
+
+    /// Comma separated list of elliptic curves that are allowed for EC keys
+    /// in CSRs (P-256, P-384 and/or P-521).
+    #[clap(long, env, value_delimiter = ',', default_value = "P-256,P-384")]
+    allowed_ec_curves: Vec<EcCurve>,
+
+    /// Whether Ed25519 keys are allowed in CSRs.
+    #[clap(long, env, default_value_t = true, action = clap::ArgAction::Set)]
+    allow_ed25519: bool,
+
+    /// The validity (in hours) of certificates that are issued with
+    /// one of the built-in profiles.
+    #[clap(long, env, default_value = "720")]
+    validity_hours: u32,
+
+    /// Path to a YAML file that contains a list of issuance profiles.
+    /// Profiles in the file replace the built-in profiles with the same name.
+    #[clap(long, env)]
+    profiles_file: Option<PathBuf>,
+
+    /// The name of the profile that is used if a CSR does not request a profile.
+    #[clap(long, env, default_value = DEFAULT_PROFILE)]
+    default_profile: String,
+
+    /// The interval (in minutes) in which the CRL is regenerated.
+    /// The CRL announces its next update after two intervals.
+    #[clap(long, env, default_value = "60")]
+    crl_refresh_minutes: u32,
+
+    /// The public URL of the CRL (e.g. `http://pki.wirepact-system/pki/crl`).
+    /// If provided, issued certificates contain a CRL distribution point
+    /// extension with this URL followed by the id of the issuing CA. The PKI
+    /// serves the CRLs on the path `/pki/crl` and `/pki/crl/<id>`.
+    #[clap(long, env)]
+    crl_url: Option<String>,
+
+    /// The path of the OCSP responder on the server (must start with `/pki/`).
+    /// The responder accepts POST requests and GET requests with the
+    /// base64 encoded OCSP request appended to the path.
+    #[clap(long, env, default_value = "/pki/ocsp")]
+    ocsp_path: String,
+
+    /// The public URL of the OCSP responder (e.g. `http://pki.wirepact-system/pki/ocsp`).
+    /// If provided, issued certificates contain an authority information access
+    /// extension with this URL.
+    #[clap(long, env)]
+    ocsp_url: Option<String>,
+
+    /// If set, OCSP responses are signed by a delegated OCSP signing certificate
+    /// (issued and renewed by the CA) instead of the CA key itself.
+    #[clap(long, env)]
+    ocsp_delegated: bool,
+
+    /// Path to a PEM file with an existing CA certificate (optionally followed by its
+    /// chain) that is imported if the store does not contain a CA yet.
+    #[clap(long, env, requires = "import_ca_key")]
+    import_ca_cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key of the CA in `import_ca_cert`.
+    #[clap(long, env, requires = "import_ca_cert")]
+    import_ca_key: Option<PathBuf>,
+
+    /// Name of a Kubernetes TLS secret (`tls.crt` and `tls.key`) in the namespace
+    /// of the PKI with an existing CA that is imported if the store does not contain a CA yet.
+    #[clap(long, env, conflicts_with = "import_ca_cert")]
+    import_ca_secret: Option<String>,
+
+    /// Create a new key for the intermediate CA, write its CSR to the given path and
+    /// exit. The key is written to `intermediate_key`, the subject of the CSR is taken
+    /// from `intermediate_subject`. The CSR must be signed by the (offline) root CA to
+    /// run the PKI as intermediate CA. The store is not accessed.
+    #[clap(long, requires = "intermediate_key", requires = "intermediate_subject")]
+    intermediate_csr: Option<PathBuf>,
+
+    /// Comma separated list of the subject attributes of the CSR of
+    /// `intermediate_csr` (e.g. `CN=WirePact Intermediate CA,O=WirePact`).
+    #[clap(long, value_delimiter = ',')]
+    intermediate_subject: Vec<SubjectAttribute>,
+
+    /// Import the intermediate CA certificate from the given PEM file and exit.
+    /// The file must contain the certificate that was issued for the CSR of
+    /// `intermediate_csr`, followed by its chain (up to the root CA). The key is
+    /// read from `intermediate_key`. If the store does not contain a CA yet, the
+    /// intermediate CA becomes its first CA, otherwise the previous CA is retired.
+    #[clap(
+        long,
+        requires = "intermediate_key",
+        conflicts_with_all = ["import_ca_cert", "import_ca_secret"]
+    )]
+    import_intermediate: Option<PathBuf>,
+
+    /// Path of the key of the intermediate CA. It is written by `intermediate_csr` (the
+    /// file must not exist) and read by `import_intermediate`. The key is encrypted with
+    /// the passphrase of the CA keys, if any.
+    #[clap(long)]
+    intermediate_key: Option<PathBuf>,
+
+    /// Passphrase (key-encryption-key) that encrypts the CA private keys in the storage.
+    /// The keys are stored as encrypted PKCS#8 and only decrypted in memory.
+    /// If omitted, the keys are stored unencrypted.
+    #[clap(long, env, conflicts_with = "ca_key_passphrase_file")]
+    ca_key_passphrase: Option<String>,
+
+    /// Path to a file that contains the passphrase of `ca_key_passphrase`
+    /// (e.g. a mounted Kubernetes secret).
+    #[clap(long, env)]
+    ca_key_passphrase_file: Option<PathBuf>,
+
+    /// Re-wrap the CA private keys in the storage with the passphrase in the given file
+    /// and exit. The keys are decrypted with the current passphrase (if any).
+    #[clap(long)]
+    rewrap_ca_key: Option<PathBuf>,
+
+    /// Path to a PKCS#11 module (e.g. `/usr/lib/softhsm/libsofthsm2.so`). If set, the
+    /// CA key is held in the PKCS#11 token `pkcs11_token` and the storage only contains
+    /// a reference to the key.
+    #[clap(long, env, requires = "pkcs11_token")]
+    pkcs11_module: Option<PathBuf>,
+
+    /// The label of the PKCS#11 token that holds the CA key.
+    #[clap(long, env, requires = "pkcs11_module")]
+    pkcs11_token: Option<String>,
+
+    /// The user PIN of the PKCS#11 token.
+    #[clap(long, env, default_value = "")]
+    pkcs11_pin: String,
+
+    /// The label of the key pair in the PKCS#11 token that is used if
+    /// the storage does not contain a CA yet.
+    #[clap(long, env, default_value = "wirepact-pki-ca")]
+    pkcs11_key_label: String,
+
+    /// The label of the key pair in the PKCS#11 token that is used for the
+    /// CSR of the intermediate CA (see `intermediate_csr`).
+    #[clap(long, env, default_value = "wirepact-pki-intermediate-ca")]
+    pkcs11_intermediate_key_label: String,
+
+    /// The algorithm of the keys that are created by the PKI (CA and delegated
+    /// OCSP signer): rsa-2048, rsa-3072, rsa-4096, ecdsa-p256, ecdsa-p384 or ed25519.
+    #[clap(long, env, default_value = "rsa-2048")]
+    key_algorithm: KeyAlgorithm,
+
+    /// The number of days before the expiry of the CA at which a successor CA is
+    /// created. The previous CA stays in the trust bundle until it expires.
+    #[clap(long, env, default_value = "180")]
+    ca_rotation_days: u32,
+
+    /// If set, the PKI signs approved Kubernetes `CertificateSigningRequest` objects
+    /// (`certificates.k8s.io/v1`) that request this signer name (e.g. `wirepact.io/pki`).
+    #[clap(long, env)]
+    csr_signer_name: Option<String>,
+
+    /// Comma separated list of the profiles that may be selected with the annotation
+    /// `pki.wirepact.io/profile` on `CertificateSigningRequest` objects.
+    /// If omitted, only the default profile is used.
+    #[clap(long, env, value_delimiter = ',')]
+    csr_profiles: Vec<String>,
+
+    /// If set, the PKI acts as cert-manager external issuer and signs approved
+    /// `CertificateRequest` objects that reference a `WirePactIssuer` or `WirePactClusterIssuer`.
+    #[clap(long, env)]
+    cert_manager_issuer: bool,
+
+    /// If set, the CA certificate and the trust bundle are published to a ConfigMap
+    /// with this name in all namespaces that match `trust_bundle_namespace_selector`.
+    #[clap(long, env)]
+    trust_bundle_config_map: Option<String>,
+
+    /// Label selector for the namespaces that receive the trust bundle ConfigMap.
+    #[clap(long, env, default_value = "pki.wirepact.io/trust-bundle=true")]
+    trust_bundle_namespace_selector: String,
+
+    /// If set, the replicas of the PKI elect a leader with a Kubernetes `Lease` of this
+    /// name. Only the leader creates and rotates the CA, publishes the CRL and runs the
+    /// controllers, while all replicas serve requests.
+    #[clap(long, env)]
+    leader_election_lease: Option<String>,
+
+    /// Deprecated, use `store` (`k8s:///<secret>`).
+    #[clap(short, long, env, hide = true)]
+    secret_name: Option<String>,
+
+    /// Deprecated, use `store` (`file://<directory>`).
+    #[clap(short, long, env, hide = true)]
+    local: bool,
+
+    /// Deprecated, use `store` (`file://<directory>`).
+    #[clap(long, env, hide = true, requires = "local")]
+    local_directory: Option<PathBuf>,
+
+    /// Deprecated, use `store` (`sqlite://<path>`).
+    #[clap(long, env, hide = true)]
+    sqlite_database: Option<PathBuf>,
+
+    /// If set, debug log messages are printed as well.
+    #[clap(short, long, env)]
+    debug: bool,
+}
+
+impl Cli {
+    /// Return the URL of the store. The deprecated storage options (`SECRET_NAME`,
+    /// `LOCAL` with `LOCAL_DIRECTORY` and `SQLITE_DATABASE`) are mapped to the
+    /// matching store with a warning. They must not be combined with each other or
+    /// with a store other than the default one.
+    fn store_url(&self) -> Result<StoreUrl, Box<dyn Error>> {
+        let mut deprecated = Vec::new();
+        if let Some(name) = self.secret_name.as_ref() {
+            deprecated.push(("SECRET_NAME", format!("{}:///{}", KUBERNETES_SCHEME, name)));
+        }
+        if self.local {
+            let directory = self
+                .local_directory
+                .as_deref()
+                .unwrap_or(Path::new(DEFAULT_LOCAL_DIRECTORY));
+            deprecated.push((
+                "LOCAL",
+                format!("{}://{}", FILE_SCHEME, directory.display()),
+            ));
+        }
+        if let Some(path) = self.sqlite_database.as_ref() {
+            deprecated.push((
+                "SQLITE_DATABASE",
+                format!("{}://{}", SQLITE_SCHEME, path.display()),
+            ));
+        }
+
+        match deprecated.as_slice() {
+            // The container image sets the default store, thus it is not a conflict.
+            [] if self.ephemeral => match self.store.to_string().as_str() {
+                DEFAULT_STORE | MEMORY_STORE => Ok(MEMORY_STORE.parse()?),
+                store => Err(format!(
+                    "EPHEMERAL cannot be combined with the store '{}', use the store '{}' only.",
+                    store, MEMORY_STORE
+                )
+                .into()),
+            },
+            [] => Ok(self.store.clone()),
+            [(option, url)] if !self.ephemeral && self.store.to_string() == DEFAULT_STORE => {
+                warn!(
+                    "The option {} is deprecated, use the store '{}' instead.",
+                    option, url
+                );
+                Ok(url.parse()?)
+            }
+            _ => Err(format!(
+                "The deprecated option(s) {} cannot be combined with other storage \
+                 options, use the store URL (STORE) only.",
+                deprecated
+                    .iter()
+                    .map(|(option, _)| *option)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into()),
+        }
+    }
+}
+
+/// Run the PKI with the command line arguments (and environment variables) of
+/// the process until it receives a shutdown signal. The store is built by the
+/// registry, thus an embedding binary can provide custom stores.
+pub async fn run(registry: StoreRegistry) -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+
+    // An embedding binary may have installed its own logger.
+    let _ = env_logger::builder()
+        .filter_module(
+            "k8s_pki",
+            match cli.debug {
+                true => log::LevelFilter::Debug,
+                false => log::LevelFilter::Info,
+            },
+        )
+        .try_init();
+
+    let address = format!("0.0.0.0:{}", cli.port);
+
+    if !cli.ocsp_path.starts_with(PATH_PREFIX) {
+        return Err(format!("The OCSP path must start with '{}'.", PATH_PREFIX).into());
+    }
+
+    if cli.ca_rotation_days >= CA_VALIDITY_DAYS {
+        return Err(format!(
+            "The CA rotation threshold must be less than {} days.",
+            CA_VALIDITY_DAYS
+        )
+        .into());
+    }
+
+    let store_url = cli.store_url()?;
+    let kubernetes_storage = store_url.scheme() == KUBERNETES_SCHEME;
+    if !kubernetes_storage && cli.leader_election_lease.is_some() {
+        return Err("Leader election requires the Kubernetes secret storage.".into());
+    }
+
+    info!("Creating and starting server @ {}", address);
+
+    info!("Storing CA and key in the store '{}'", store_url);
+
+    // The client for the Kubernetes API is only created if any part of the PKI uses it.
+    let kubernetes = match kubernetes_storage
+        || cli.import_ca_secret.is_some()
+        || cli.csr_signer_name.is_some()
+        || cli.cert_manager_issuer
+        || cli.trust_bundle_config_map.is_some()
+    {
+        true => Some(KubernetesContext::new(cli.namespace).await?),
+        false => None,
+    };
+
+    let bootstrap = match (
+        kubernetes.as_ref(),
+        cli.import_ca_secret,
+        cli.import_ca_cert,
+        cli.import_ca_key,
+    ) {
+        (Some(kubernetes), Some(secret), _, _) => {
+            Some(authority_from_secret(kubernetes, secret.as_str()).await?)
+        }
+        (_, None, Some(cert), Some(key)) => Some(parse_authority(
+            tokio::fs::read(cert).await?.as_slice(),
+            tokio::fs::read(key).await?.as_slice(),
+        )?),
+        _ => None,
+    };
+
+    let key_encryption = match (cli.ca_key_passphrase, cli.ca_key_passphrase_file) {
+        (Some(passphrase), _) => KeyEncryption::passphrase(passphrase.as_bytes())?,
+        (None, Some(path)) => KeyEncryption::from_file(&path).await?,
+        (None, None) => KeyEncryption::none(),
+    };
+
+    let token = match (cli.pkcs11_module, cli.pkcs11_token) {
+        (Some(module), Some(token)) => Some(Arc::new(Pkcs11Token::open(
+            &module,
+            token.as_str(),
+            cli.pkcs11_pin.as_str(),
+            cli.pkcs11_key_label,
+        )?)),
+        _ => None,
+    };
+
+    let leader_election = Arc::new(match (kubernetes.clone(), cli.leader_election_lease) {
+        (Some(kubernetes), Some(lease)) => LeaderElection::new(kubernetes, lease),
+        _ => LeaderElection::single(),
+    });
+    tokio::spawn(leader_election.clone().run());
+
+    let key_storage = KeyStorage {
+        encryption: key_encryption,
+        token,
+    };
+
+    // The CSR of the intermediate CA does not need a CA in the store.
+    if let (Some(path), Some(key_path)) = (cli.intermediate_csr, cli.intermediate_key.as_ref()) {
+        let subject = subject_name(cli.intermediate_subject.as_slice())?;
+        let key = key_storage.create_labelled_key(
+            cli.key_algorithm,
+            cli.pkcs11_intermediate_key_label.as_str(),
+        )?;
+        let csr = create_intermediate_csr(subject.as_ref(), key.as_ref())?;
+        write_intermediate_key(key_path, key.as_ref(), &key_storage).await?;
+        tokio::fs::write(&path, csr.to_pem()?).await?;
+        info!(
+            "Wrote the CSR of the intermediate CA to '{}' and its key to '{}'.",
+            path.display(),
+            key_path.display()
+        );
+        return Ok(());
+    }
+    let mut store = registry.build(
+        &store_url,
+        &StoreContext {
+            key_algorithm: cli.key_algorithm,
+            key_storage: key_storage.clone(),
+            kubernetes: kubernetes.clone(),
+            leader_election: leader_election.clone(),
+        },
+    )?;
+
+    // The keys are re-wrapped before the store is initialized, which would create a CA.
+    if let Some(path) = cli.rewrap_ca_key {
+        store
+            .rewrap_keys(KeyEncryption::from_file(&path).await?)
+            .await?;
+        info!(
+            "Re-wrapped the CA keys with the passphrase from '{}'.",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    // The intermediate CA is installed instead of the initialization, which would create a CA.
+    if let (Some(path), Some(key_path)) = (cli.import_intermediate, cli.intermediate_key.as_ref()) {
+        let pem = tokio::fs::read(&path).await?;
+        let key = read_intermediate_key(key_path, &key_storage).await?;
+        install_intermediate(store.as_mut(), intermediate_authority(key, pem.as_slice())?).await?;
+        info!("Imported the intermediate CA from '{}'.", path.display());
+        return Ok(());
+    }
+
+    store.init(bootstrap).await?;
+
+    let store: Arc<dyn CertificateStore> = Arc::from(store);
+    tokio::spawn(store.clone().watch());
+
+    let crl_publisher = Arc::new(CrlPublisher::new(
+        store.clone(),
+        leader_election.clone(),
+        time::Duration::minutes(i64::from(cli.crl_refresh_minutes)),
+    ));
+
+    let ca_rotation = Arc::new(CaRotation::new(
+        store.clone(),
+        crl_publisher.clone(),
+        leader_election.clone(),
+        cli.ca_rotation_days,
+        cli.key_algorithm,
+    ));
+    if leader_election.is_leader() {
+        ca_rotation.rotate_if_needed().await?;
+    }
+    tokio::spawn(ca_rotation.run());
+    tokio::spawn(crl_publisher.clone().run());
+
+    if let (Some(kubernetes), Some(name)) = (kubernetes.clone(), cli.trust_bundle_config_map) {
+        let distributor = Arc::new(TrustDistributor::new(
+            kubernetes,
+            store.clone(),
+            leader_election.clone(),
+            name,
+            cli.trust_bundle_namespace_selector,
+        ));
+        tokio::spawn(distributor.run());
+    }
+
+    let ocsp_responder = Arc::new(OcspResponder::new(
+        store.clone(),
+        cli.ocsp_delegated,
+        cli.key_algorithm,
+        time::Duration::minutes(i64::from(cli.crl_refresh_minutes)),
+    ));
+    let policy = IssuancePolicy {
+        san: SanPolicy {
+            dns_suffixes: cli.allowed_dns_suffixes,
+            uri_schemes: cli.allowed_uri_schemes,
+            ip_ranges: cli.allowed_ip_ranges,
+        },
+        key: KeyPolicy {
+            min_rsa_bits: cli.min_rsa_key_bits,
+            allowed_curves: cli.allowed_ec_curves,
+            allow_ed25519: cli.allow_ed25519,
+        },
+        subject: SubjectPolicy {
+            allowed_attributes: cli.allowed_subject_attributes,
+            required_attributes: cli.required_subject_attributes,
+            common_name_pattern: match cli.common_name_pattern {
+                Some(pattern) => Some(Regex::new(format!("^(?:{})$", pattern).as_str())?),
+                None => None,
+            },
+            forced_attributes: cli.forced_subject_attributes,
+            from_identity: cli.subject_from_identity,
+        },
+    };
+    let profiles = Profiles::load(
+        cli.profiles_file.as_deref(),
+        cli.validity_hours,
+        cli.default_profile,
+    )
+    .await?;
+    let mut api_keys = cli.api_keys;
+    if let Some(key) = cli.api_key {
+        api_keys.push(ApiKey {
+            identity: "default".to_string(),
+            key,
+        });
+    }
+
+    let pki_service = Arc::new(PkiService::new(
+        store.clone(),
+        crl_publisher.clone(),
+        api_keys,
+        policy,
+        profiles,
+        IssuerUrls {
+            crl: cli.crl_url,
+            ocsp: cli.ocsp_url,
+        },
+    ));
+
+    if let (Some(kubernetes), Some(signer_name)) = (kubernetes.clone(), cli.csr_signer_name) {
+        let controller = Arc::new(CsrController::new(
+            kubernetes,
+            pki_service.clone(),
+            leader_election.clone(),
+            signer_name,
+            cli.csr_profiles,
+        ));
+        tokio::spawn(controller.run());
+    }
+
+    if let (Some(kubernetes), true) = (kubernetes, cli.cert_manager_issuer) {
+        let controller = Arc::new(IssuerController::new(
+            kubernetes,
+            pki_service.clone(),
+            store,
+            leader_election,
+        ));
+        tokio::spawn(controller.run());
+    }
+
+    #[cfg(windows)]
+    async fn signal() {
+        use tokio::signal::windows::ctrl_c;
+        let mut stream = ctrl_c().unwrap();
+        stream.recv().await;
+        info!("Signal received. Shutting down server.");
+    }
+
+    #[cfg(unix)]
+    async fn signal() {
+        use log::debug;
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut int = signal(SignalKind::interrupt()).unwrap();
+        let mut term = signal(SignalKind::terminate()).unwrap();
+
+        tokio::select! {
+            _ = int.recv() => debug!("SIGINT received."),
+            _ = term.recv() => debug!("SIGTERM received."),
+        }
+
+        info!("Signal received. Shutting down server.");
+    }
+
+    Server::builder()
+        .accept_http1(true)
+        .add_service(tonic_web::enable(
+            grpc::pki_service_server::PkiServiceServer::from_arc(pki_service),
+        ))
+        .add_service(HttpService::new(
+            crl_publisher,
+            ocsp_responder,
+            cli.ocsp_path,
+        ))
+        .serve_with_shutdown(address.parse()?, signal())
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::cli::Cli;
+
+    fn store_url(args: &[&str]) -> Result<String, String> {
+        let cli = Cli::try_parse_from([&["k8s-pki"], args].concat()).map_err(|e| e.to_string())?;
+        cli.store_url()
+            .map(|url| url.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn select_the_store() {
+        assert_eq!(store_url(&[]).unwrap(), "k8s:///wirepact-pki-ca");
+        assert_eq!(
+            store_url(&["--store", "sqlite:///var/lib/pki.db"]).unwrap(),
+            "sqlite:///var/lib/pki.db"
+        );
+        assert_eq!(store_url(&["--ephemeral"]).unwrap(), "memory://");
+        assert!(store_url(&["--store", "/var/lib/pki"]).is_err());
+    }
+
+    #[test]
+    fn map_the_deprecated_storage_options() {
+        assert_eq!(store_url(&["--secret-name", "pki"]).unwrap(), "k8s:///pki");
+        assert_eq!(store_url(&["-s", "pki"]).unwrap(), "k8s:///pki");
+        assert_eq!(store_url(&["-l"]).unwrap(), "file://./ca");
+        assert_eq!(
+            store_url(&["--local", "--local-directory", "/var/lib/pki"]).unwrap(),
+            "file:///var/lib/pki"
+        );
+        assert_eq!(
+            store_url(&["--sqlite-database", "pki.db"]).unwrap(),
+            "sqlite://pki.db"
+        );
+        // The default store may be set explicitly (e.g. by the container image).
+        assert_eq!(
+            store_url(&["--store", "k8s:///wirepact-pki-ca", "--local"]).unwrap(),
+            "file://./ca"
+        );
+    }
+
+    #[test]
+    fn reject_conflicting_deprecated_storage_options() {
+        assert!(store_url(&["--local-directory", "/var/lib/pki"]).is_err());
+        assert!(store_url(&["--local", "--secret-name", "pki"]).is_err());
+        assert!(store_url(&["--store", "memory://", "--secret-name", "pki"]).is_err());
+        assert!(store_url(&["--ephemeral", "--local"]).is_err());
+        assert!(store_url(&["--ephemeral", "--store", "file://./ca"]).is_err());
+        assert_eq!(
+            store_url(&["--ephemeral", "--store", "k8s:///wirepact-pki-ca"]).unwrap(),
+            "memory://"
+        );
+    }
+
+    #[test]
+    fn require_the_options_of_the_intermediate_ca() {
+        let parse = |args: &[&str]| Cli::try_parse_from([&["k8s-pki"], args].concat());
+        assert!(parse(&[
+            "--intermediate-csr",
+            "ca.csr",
+            "--intermediate-key",
+            "ca.key"
+        ])
+        .is_err());
+        let cli = parse(&[
+            "--intermediate-csr",
+            "ca.csr",
+            "--intermediate-key",
+            "ca.key",
+            "--intermediate-subject",
+            "CN=Intermediate CA,O=WirePact",
+        ])
+        .unwrap();
+        assert_eq!(cli.intermediate_subject.len(), 2);
+
+        assert!(parse(&[
+            "--import-intermediate",
+            "chain.pem",
+            "--intermediate-key",
+            "ca.key",
+            "--import-ca-secret",
+            "ca",
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use log::{debug, error, info};
+use openssl::hash::MessageDigest;
+use openssl::x509::{X509Crl, X509Ref};
+use time::{Duration, OffsetDateTime};
+use tokio::sync::RwLock;
+
+use crate::cert_store::revocation::{create_crl, crl_number, next_crl_number};
+use crate::cert_store::store::CertificateStore;
+use crate::leader_election::LeaderElection;
+
+/// The CRLs (PEM encoded) by the id of their CA, as they are shared with
+/// the other replicas through the store.
+type StoredCrls = BTreeMap<String, String>;
+
+/// Return the id of the CA that is used to select its CRL: the hex encoded subject
+/// key identifier or, if the CA has none, the SHA-1 fingerprint of the certificate.
+pub fn issuer_id(cert: &X509Ref) -> Result<String, Box<dyn Error>> {
+    let id = match cert.subject_key_id() {
+        Some(key_id) => key_id.as_slice().to_vec(),
+        None => cert.digest(MessageDigest::sha1())?.to_vec(),
+    };
+    Ok(id.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// The CRLs (DER encoded) by the id of their CA together with the stored
+/// CRLs they were read from, thus they are replaced once the store changes.
+struct CachedCrls {
+    stored: Option<Vec<u8>>,
+    crls: BTreeMap<String, Vec<u8>>,
+}
+
+/// Holds the current CRLs of the PKI and regenerates them periodically
+/// (and whenever a certificate gets revoked). Every CA (the active and the
+/// retired ones) publishes its own CRL, thus certificates of a retired CA can
+/// still be checked. Only the leader regenerates the CRLs, the other replicas
+/// serve the CRLs stored by the leader.
+pub struct CrlPublisher {
+    cert_store: Arc<dyn CertificateStore>,
+    leader_election: Arc<LeaderElection>,
+    refresh_interval: Duration,
+    crls: RwLock<Option<CachedCrls>>,
+}
+
+impl CrlPublisher {
+    pub fn new(
+        cert_store: Arc<dyn CertificateStore>,
+        leader_election: Arc<LeaderElection>,
+        refresh_interval: Duration,
+    ) -> Self {
+        Self {
+            cert_store,
+            leader_election,
+            refresh_interval,
+            crls: RwLock::new(None),
+        }
+    }
+
+    /// Return the current CRL of the active CA in DER encoding.
+    /// If the leader did not generate a CRL yet, it is created.
+    pub async fn crl_der(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let id = issuer_id(&self.cert_store.cert())?;
+        if let Some(crl) = self.crls().await?.remove(id.as_str()) {
+            return Ok(crl);
+        }
+        if !self.leader_election.is_leader() {
+            return Err("The leader did not publish the CRL of the active CA yet.".into());
+        }
+
+        // The CRLs were generated before the CA was rotated.
+        self.regenerate()
+            .await?
+            .remove(id.as_str())
+            .ok_or_else(|| "The CRL of the active CA could not be generated.".into())
+    }
+
+    /// Return the current CRL in DER encoding of the CA with the given id
+    /// (see [issuer_id]). Returns `None` if the PKI has no such CA.
+    pub async fn issuer_crl_der(&self, id: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.crls().await?.remove(id))
+    }
+
+    /// Return the current CRL of the active CA in PEM encoding.
+    pub async fn crl_pem(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let der = self.crl_der().await?;
+        Ok(X509Crl::from_der(der.as_slice())?.to_pem()?)
+    }
+
+    /// Return the cached CRLs as long as the stored CRLs did not change
+    /// (e.g. by another replica), otherwise the stored CRLs are read again.
+    /// If no CRLs were generated yet, the leader creates them.
+    async fn crls(&self) -> Result<BTreeMap<String, Vec<u8>>, Box<dyn Error>> {
+        let stored = self.cert_store.stored_crl().await?;
+        if let Some(cached) = self.crls.read().await.as_ref() {
+            if stored.is_none() || stored == cached.stored {
+                return Ok(cached.crls.clone());
+            }
+        }
+
+        if let Some(stored) = stored {
+            // The error is not `Send`, thus it is converted before awaiting.
+            match parse_stored_crls(stored.as_slice()).map_err(|e| e.to_string()) {
+                Ok(crls) => {
+                    *self.crls.write().await = Some(CachedCrls {
+                        stored: Some(stored),
+                        crls: crls.clone(),
+                    });
+                    return Ok(crls);
+                }
+                Err(e) => debug!("Ignore the stored CRLs: {}", e),
+            }
+        }
+
+        if self.leader_election.is_leader() {
+            return self.regenerate().await;
+        }
+        match self.crls.read().await.as_ref() {
+            Some(cached) => Ok(cached.crls.clone()),
+            None => Err("The leader did not publish the CRLs yet.".into()),
+        }
+    }
+
+    /// Publish a revocation of this replica: the leader regenerates the CRLs,
+    /// the other replicas leave it to the leader (which is notified by the store).
+    pub async fn publish_revocation(&self) -> Result<(), Box<dyn Error>> {
+        if !self.leader_election.is_leader() {
+            debug!("The leader regenerates the CRLs with the revocation.");
+            return Ok(());
+        }
+        self.regenerate().await.map(|_| ())
+    }
+
+    /// Create a new CRL for every CA with all revoked certificates of the store.
+    /// The next update of the CRLs is announced to be after two refresh
+    /// intervals to give clients a grace period. Only the leader regenerates the CRLs.
+    pub async fn regenerate(&self) -> Result<BTreeMap<String, Vec<u8>>, Box<dyn Error>> {
+        if !self.leader_election.is_leader() {
+            return Err("Only the leader regenerates the CRLs.".into());
+        }
+        debug!("Generate CRLs.");
+
+        let revoked = self.cert_store.revoked_certificates().await?;
+        let authorities = self.cert_store.authorities();
+        let this_update = OffsetDateTime::now_utc();
+        let next_update = this_update + self.refresh_interval * 2;
+        let crl_number = next_crl_number(self.previous_crl_number().await?, this_update);
+        let mut crls = BTreeMap::new();
+        let mut stored = StoredCrls::new();
+        // The serial numbers are random and thus unique across the CAs,
+        // therefore every CRL contains all revoked serial numbers.
+        for authority in authorities.all() {
+            let crl = create_crl(
+                &authority.cert,
+                authority.key.as_ref(),
+                revoked.as_slice(),
+                crl_number,
+                this_update,
+                next_update,
+            )?;
+            let id = issuer_id(&authority.cert)?;
+            stored.insert(id.clone(), String::from_utf8(crl.to_pem()?)?);
+            crls.insert(id, crl.to_der()?);
+        }
+
+        let stored = serde_json::to_vec(&stored)?;
+        self.cert_store.store_crl(stored.as_slice()).await?;
+        *self.crls.write().await = Some(CachedCrls {
+            stored: Some(stored),
+            crls: crls.clone(),
+        });
+        info!(
+            "Generated {} CRL(s) with {} revoked certificate(s).",
+            crls.len(),
+            revoked.len()
+        );
+
+        Ok(crls)
+    }
+
+    /// Return the highest CRL number that was published before, by this
+    /// replica or (through the store) by another one.
+    async fn previous_crl_number(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        let mut previous = match self.crls.read().await.as_ref() {
+            Some(cached) => highest_crl_number(&cached.crls)?,
+            None => None,
+        };
+        if let Some(stored) = self.cert_store.stored_crl().await? {
+            match parse_stored_crls(stored.as_slice()) {
+                Ok(crls) => previous = previous.max(highest_crl_number(&crls)?),
+                Err(e) => debug!("Ignore the stored CRLs: {}", e),
+            }
+        }
+        Ok(previous)
+    }
+
+    /// Regenerate the CRLs in the configured refresh interval while this replica is the leader,
+    /// and whenever the CA was reloaded or another replica revoked a certificate.
+    pub async fn run(self: Arc<Self>) {
+        self.leader_election
+            .lead(|| async {
+                let mut interval = tokio::time::interval(self.refresh_interval.unsigned_abs());
+                let mut reloads = self.cert_store.reloads();
+                let mut revocations = self.cert_store.revocations();
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {},
+                        Ok(_) = reloads.changed() => info!("The CA was reloaded, regenerate the CRLs."),
+                        Ok(_) = revocations.changed() => info!("A certificate was revoked, regenerate the CRLs."),
+                    }
+                    if let Err(e) = self.regenerate().await {
+                        error!("Could not generate the CRLs: {}", e);
+                    }
+                }
+            })
+            .await
+    }
+}
+
+fn parse_stored_crls(stored: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, Box<dyn Error>> {
+    let stored: StoredCrls = serde_json::from_slice(stored)?;
+    let mut crls = BTreeMap::new();
+    for (id, pem) in stored {
+        crls.insert(id, X509Crl::from_pem(pem.as_bytes())?.to_der()?);
+    }
+    Ok(crls)
+}
+
+fn highest_crl_number(crls: &BTreeMap<String, Vec<u8>>) -> Result<Option<u64>, Box<dyn Error>> {
+    let mut highest = None;
+    for crl in crls.values() {
+        highest = highest.max(crl_number(crl.as_slice())?);
+    }
+    Ok(highest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use openssl::x509::X509Crl;
+    use time::{Duration, OffsetDateTime};
+
+    use crate::cert_store::memory_store::InMemoryStore;
+    use crate::cert_store::revocation::{crl_number, RevocationReason, RevokedCertificate};
+    use crate::cert_store::store::{Authorities, CertificateAuthority, CertificateStore};
+    use crate::cert_store::utils::{create_new_ca, create_new_key, KeyAlgorithm};
+    use crate::crl::{highest_crl_number, issuer_id, parse_stored_crls, CrlPublisher};
+    use crate::leader_election::LeaderElection;
+
+    fn authority(algorithm: KeyAlgorithm) -> CertificateAuthority {
+        let key = create_new_key(algorithm).unwrap();
+        CertificateAuthority {
+            cert: create_new_ca(&key).unwrap(),
+            key: Arc::new(key),
+            chain: Vec::new(),
+        }
+    }
+
+    async fn publisher() -> (Arc<dyn CertificateStore>, CrlPublisher) {
+        let store: Arc<dyn CertificateStore> = Arc::new(InMemoryStore::with_authority(authority(
+            KeyAlgorithm::EcdsaP256,
+        )));
+        let retired = store.authorities().active.clone();
+        store
+            .store_authorities(Authorities {
+                active: authority(KeyAlgorithm::Ed25519),
+                retired: vec![retired],
+            })
+            .await
+            .unwrap();
+        store
+            .revoke_certificate(RevokedCertificate {
+                serial_number: "1A".to_string(),
+                revoked_at: OffsetDateTime::now_utc().unix_timestamp(),
+                reason: RevocationReason::KeyCompromise,
+            })
+            .await
+            .unwrap();
+        let publisher = CrlPublisher::new(
+            store.clone(),
+            Arc::new(LeaderElection::single()),
+            Duration::hours(1),
+        );
+        (store, publisher)
+    }
+
+    #[tokio::test]
+    async fn publish_a_crl_per_authority() {
+        let (store, publisher) = publisher().await;
+
+        let crls = publisher.regenerate().await.unwrap();
+
+        let authorities = store.authorities();
+        assert_eq!(crls.len(), 2);
+        for authority in authorities.all() {
+            let id = issuer_id(&authority.cert).unwrap();
+            let der = publisher
+                .issuer_crl_der(id.as_str())
+                .await
+                .unwrap()
+                .unwrap();
+            let crl = X509Crl::from_der(der.as_slice()).unwrap();
+            assert!(crl
+                .verify(authority.cert.public_key().unwrap().as_ref())
+                .unwrap());
+            assert_eq!(crl.get_revoked().unwrap().len(), 1);
+        }
+
+        let active = X509Crl::from_der(publisher.crl_der().await.unwrap().as_slice()).unwrap();
+        assert!(active
+            .verify(authorities.active.cert.public_key().unwrap().as_ref())
+            .unwrap());
+        assert!(publisher.issuer_crl_der("00").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn share_the_crls_through_the_store() {
+        let (store, publisher) = publisher().await;
+
+        let crls = publisher.regenerate().await.unwrap();
+
+        let stored = store.stored_crl().await.unwrap();
+        assert_eq!(parse_stored_crls(stored.unwrap().as_slice()).unwrap(), crls);
+        assert!(parse_stored_crls(b"invalid").is_err());
+    }
+
+    #[tokio::test]
+    async fn increase_the_crl_number() {
+        let (store, publisher) = publisher().await;
+        let first = publisher.regenerate().await.unwrap();
+        let first = highest_crl_number(&first).unwrap().unwrap();
+
+        // Another replica continues with the numbers of the stored CRLs.
+        let other = CrlPublisher::new(
+            store.clone(),
+            Arc::new(LeaderElection::single()),
+            Duration::hours(1),
+        );
+        let second = other.regenerate().await.unwrap();
+        let third = publisher.regenerate().await.unwrap();
+
+        for crl in second.values() {
+            assert_eq!(crl_number(crl.as_slice()).unwrap(), Some(first + 1));
+        }
+        for crl in third.values() {
+            assert_eq!(crl_number(crl.as_slice()).unwrap(), Some(first + 2));
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_the_crls_of_the_leader() {
+        let (store, leader) = publisher().await;
+        let follower = CrlPublisher::new(
+            store.clone(),
+            Arc::new(LeaderElection::follower()),
+            Duration::hours(1),
+        );
+        assert!(follower.crl_der().await.is_err());
+        assert!(follower.regenerate().await.is_err());
+        follower.publish_revocation().await.unwrap();
+        assert!(store.stored_crl().await.unwrap().is_none());
+
+        let first = leader.regenerate().await.unwrap();
+        assert_eq!(follower.crls().await.unwrap(), first);
+
+        // The cached CRLs are replaced once the leader publishes new ones.
+        store
+            .revoke_certificate(RevokedCertificate {
+                serial_number: "2B".to_string(),
+                revoked_at: OffsetDateTime::now_utc().unix_timestamp(),
+                reason: RevocationReason::Superseded,
+            })
+            .await
+            .unwrap();
+        leader.publish_revocation().await.unwrap();
+        let second = follower.crls().await.unwrap();
+        assert_ne!(second, first);
+        let crl = X509Crl::from_der(second.values().next().unwrap().as_slice()).unwrap();
+        assert_eq!(crl.get_revoked().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn regenerate_after_rotation() {
+        let (store, publisher) = publisher().await;
+        let before = publisher.crl_der().await.unwrap();
+
+        let mut authorities = store.authorities().as_ref().clone();
+        let active = std::mem::replace(&mut authorities.active, authority(KeyAlgorithm::Rsa2048));
+        authorities.retired.push(active);
+        store.store_authorities(authorities).await.unwrap();
+
+        let after = X509Crl::from_der(publisher.crl_der().await.unwrap().as_slice()).unwrap();
+        assert_ne!(after.to_der().unwrap(), before);
+        assert!(after
+            .verify(store.cert().public_key().unwrap().as_ref())
+            .unwrap());
+    }
+}
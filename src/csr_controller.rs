@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use k8s_openapi::api::certificates::v1::{
+    CertificateSigningRequest, CertificateSigningRequestCondition,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::Utc;
+use k8s_openapi::ByteString;
+use kube::api::{ListParams, Patch, PatchParams};
+use kube::runtime::watcher;
+use kube::Api;
+use log::{debug, error, info, warn};
+use serde_json::json;
+use tonic::Code;
+
+use crate::cert_store::profile::RequestedUsages;
+use crate::cert_store::KubernetesContext;
+use crate::leader_election::LeaderElection;
+use crate::pki_service::PkiService;
+
+/// Annotation on a CSR object that selects the issuance profile. Only the
+/// profiles that are allowed for the controller can be selected.
+pub const PROFILE_ANNOTATION: &str = "pki.wirepact.io/profile";
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Signs approved Kubernetes `CertificateSigningRequest` objects
+/// (`certificates.k8s.io/v1`) that request the configured signer.
+pub struct CsrController {
+    kubernetes: KubernetesContext,
+    pki_service: Arc<PkiService>,
+    leader_election: Arc<LeaderElection>,
+    signer_name: String,
+    /// The profiles that may be selected with the profile annotation
+    /// (in addition to the default profile).
+    profiles: Vec<String>,
+}
+
+impl CsrController {
+    pub fn new(
+        kubernetes: KubernetesContext,
+        pki_service: Arc<PkiService>,
+        leader_election: Arc<LeaderElection>,
+        signer_name: String,
+        profiles: Vec<String>,
+    ) -> Self {
+        Self {
+            kubernetes,
+            pki_service,
+            leader_election,
+            signer_name,
+            profiles,
+        }
+    }
+
+    /// Run the controller while this replica is the leader.
+    pub async fn run(self: Arc<Self>) {
+        self.leader_election.lead(|| self.watch()).await
+    }
+
+    async fn watch(&self) {
+        let client = self.kubernetes.client.clone();
+
+        let api: Api<CertificateSigningRequest> = Api::all(client);
+        let params =
+            ListParams::default().fields(format!("spec.signerName={}", self.signer_name).as_str());
+        info!(
+            "Watching CertificateSigningRequests for the signer '{}'.",
+            self.signer_name
+        );
+
+        let mut events = Box::pin(watcher(api.clone(), params));
+        loop {
+            match events.try_next().await {
+                Ok(Some(event)) => {
+                    for csr in event.into_iter_applied() {
+                        if let Err(e) = self.reconcile(&api, csr).await {
+                            error!("Could not update the CertificateSigningRequest: {}", e);
+                        }
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Error while watching CertificateSigningRequests: {}", e);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn reconcile(
+        &self,
+        api: &Api<CertificateSigningRequest>,
+        csr: CertificateSigningRequest,
+    ) -> Result<(), kube::Error> {
+        if csr.spec.signer_name != self.signer_name {
+            return Ok(());
+        }
+
+        let name = csr.metadata.name.unwrap_or_default();
+        let status = csr.status.unwrap_or_default();
+        if status.certificate.is_some() {
+            return Ok(());
+        }
+
+        let mut conditions = status.conditions.unwrap_or_default();
+        let has_condition = |condition_type: &str| {
+            conditions
+                .iter()
+                .any(|c| c.type_ == condition_type && c.status == "True")
+        };
+        if has_condition("Denied") || has_condition("Failed") {
+            return Ok(());
+        }
+        if !has_condition("Approved") {
+            debug!("CSR '{}' is not approved yet.", name);
+            return Ok(());
+        }
+
+        let requester = csr.spec.username.as_deref().unwrap_or_default();
+        let requested = requested_usages(csr.spec.usages.as_deref(), csr.spec.expiration_seconds);
+
+        let issued = match selected_profile(csr.metadata.annotations.as_ref(), &self.profiles) {
+            Ok(profile) => match self
+                .pki_service
+                .issue_certificate(
+                    csr.spec.request.0.as_slice(),
+                    profile,
+                    &requested,
+                    requester,
+                )
+                .await
+            {
+                Ok(cert) => self
+                    .pki_service
+                    .chain_pem(&cert)
+                    .map_err(|e| (Code::Internal, e.to_string())),
+                Err(status) => Err((status.code(), status.message().to_string())),
+            },
+            Err(message) => Err((Code::PermissionDenied, message)),
+        };
+
+        let patch = match issued {
+            Ok(chain) => {
+                info!("Signed CSR '{}' for '{}'.", name, requester);
+                json!({ "status": { "certificate": ByteString(chain) } })
+            }
+            Err((code, message)) => {
+                warn!("Could not sign CSR '{}': {}", name, message);
+                let now = Time(Utc::now());
+                conditions.push(CertificateSigningRequestCondition {
+                    type_: "Failed".to_string(),
+                    status: "True".to_string(),
+                    reason: Some(failure_reason(code).to_string()),
+                    message: Some(message),
+                    last_transition_time: Some(now.clone()),
+                    last_update_time: Some(now),
+                });
+                json!({ "status": { "conditions": conditions } })
+            }
+        };
+
+        api.patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Return the profile that is selected by the annotations of the CSR. An empty
+/// name selects the default profile, other profiles must be allowed.
+fn selected_profile<'a>(
+    annotations: Option<&'a BTreeMap<String, String>>,
+    allowed: &[String],
+) -> Result<&'a str, String> {
+    let profile = annotations
+        .and_then(|a| a.get(PROFILE_ANNOTATION))
+        .map(String::as_str)
+        .unwrap_or_default();
+    if !profile.is_empty() && !allowed.iter().any(|allowed| allowed == profile) {
+        return Err(format!(
+            "The profile '{}' cannot be selected for CertificateSigningRequests.",
+            profile
+        ));
+    }
+    Ok(profile)
+}
+
+/// Return the usages and the validity (`spec.expirationSeconds`) of the CSR.
+fn requested_usages(usages: Option<&[String]>, expiration_seconds: Option<i32>) -> RequestedUsages {
+    RequestedUsages {
+        usages: usages.unwrap_or_default().to_vec(),
+        validity: expiration_seconds.map(|seconds| time::Duration::seconds(i64::from(seconds))),
+    }
+}
+
+fn failure_reason(code: Code) -> &'static str {
+    match code {
+        Code::InvalidArgument | Code::PermissionDenied => "PolicyViolation",
+        _ => "SigningFailed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use time::Duration;
+
+    use crate::csr_controller::{requested_usages, selected_profile, PROFILE_ANNOTATION};
+
+    fn annotations(profile: &str) -> BTreeMap<String, String> {
+        BTreeMap::from([(PROFILE_ANNOTATION.to_string(), profile.to_string())])
+    }
+
+    #[test]
+    fn select_only_allowed_profiles() {
+        let allowed = vec!["server".to_string()];
+
+        assert_eq!(selected_profile(None, &allowed).unwrap(), "");
+        assert_eq!(selected_profile(Some(&BTreeMap::new()), &[]).unwrap(), "");
+        assert_eq!(
+            selected_profile(Some(&annotations("server")), &allowed).unwrap(),
+            "server"
+        );
+        assert!(selected_profile(Some(&annotations("sub-ca")), &allowed).is_err());
+        assert!(selected_profile(Some(&annotations("server")), &[]).is_err());
+    }
+
+    #[test]
+    fn request_the_usages_and_validity_of_the_spec() {
+        let usages = vec!["digital signature".to_string(), "client auth".to_string()];
+
+        let requested = requested_usages(Some(usages.as_slice()), Some(600));
+        assert_eq!(requested.usages, usages);
+        assert_eq!(requested.validity, Some(Duration::minutes(10)));
+
+        let requested = requested_usages(None, None);
+        assert!(requested.usages.is_empty());
+        assert_eq!(requested.validity, None);
+    }
+}
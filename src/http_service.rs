@@ -0,0 +1,163 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http_body::{Body as _, Full};
+use log::{debug, error};
+use openssl::base64::decode_block;
+use percent_encoding::percent_decode_str;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{header, Method, Request, Response, StatusCode};
+use tonic::codegen::{BoxFuture, Service};
+use tonic::transport::{Body, NamedService};
+
+use crate::crl::CrlPublisher;
+use crate::ocsp::OcspResponder;
+
+/// Path of the CRL (DER encoded) on the plain HTTP endpoint.
+pub const CRL_PATH: &str = "/pki/crl";
+
+/// Prefix of all paths that are served by the [HttpService].
+pub const PATH_PREFIX: &str = "/pki/";
+
+const MAX_OCSP_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Plain HTTP endpoints of the PKI (the CRL and the OCSP responder) that are
+/// served by the same server as the gRPC service. All paths are prefixed with `/pki/`.
+#[derive(Clone)]
+pub struct HttpService {
+    crl_publisher: Arc<CrlPublisher>,
+    ocsp_responder: Arc<OcspResponder>,
+    ocsp_path: Arc<String>,
+}
+
+impl HttpService {
+    pub fn new(
+        crl_publisher: Arc<CrlPublisher>,
+        ocsp_responder: Arc<OcspResponder>,
+        ocsp_path: String,
+    ) -> Self {
+        Self {
+            crl_publisher,
+            ocsp_responder,
+            ocsp_path: Arc::new(ocsp_path.trim_end_matches('/').to_string()),
+        }
+    }
+}
+
+impl NamedService for HttpService {
+    const NAME: &'static str = "pki";
+}
+
+fn response(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(
+            Full::from(body)
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+        .unwrap()
+}
+
+impl Service<Request<Body>> for HttpService {
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let crl_publisher = self.crl_publisher.clone();
+        let ocsp_responder = self.ocsp_responder.clone();
+        let ocsp_path = self.ocsp_path.clone();
+
+        Box::pin(async move {
+            debug!("HTTP {} {}", request.method(), request.uri().path());
+
+            let path = request.uri().path().to_string();
+            if path == ocsp_path.as_str() || path.starts_with(format!("{}/", ocsp_path).as_str()) {
+                let ocsp_request = match read_ocsp_request(request, ocsp_path.as_str()).await {
+                    Ok(ocsp_request) => ocsp_request,
+                    Err(e) => {
+                        debug!("Invalid OCSP HTTP request: {}", e);
+                        return Ok(response(StatusCode::BAD_REQUEST, "text/plain", Vec::new()));
+                    }
+                };
+
+                let ocsp_response = ocsp_responder.respond(ocsp_request.as_slice()).await;
+                return Ok(response(
+                    StatusCode::OK,
+                    "application/ocsp-response",
+                    ocsp_response,
+                ));
+            }
+
+            // The CRL of the active CA or, with the id of a CA appended, the CRL of that CA.
+            let issuer = match path.strip_prefix(CRL_PATH) {
+                Some("") => None,
+                Some(id) if id.len() > 1 && id.starts_with('/') => Some(id[1..].to_string()),
+                _ => return Ok(response(StatusCode::NOT_FOUND, "text/plain", Vec::new())),
+            };
+
+            if request.method() != Method::GET {
+                return Ok(response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "text/plain",
+                    Vec::new(),
+                ));
+            }
+
+            let crl = match issuer {
+                None => crl_publisher.crl_der().await.map(Some),
+                Some(id) => crl_publisher.issuer_crl_der(id.as_str()).await,
+            };
+            let der = match crl {
+                Ok(Some(der)) => der,
+                Ok(None) => return Ok(response(StatusCode::NOT_FOUND, "text/plain", Vec::new())),
+                Err(e) => {
+                    error!("Could not generate the CRL: {}", e);
+                    return Ok(response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "text/plain",
+                        Vec::new(),
+                    ));
+                }
+            };
+
+            Ok(response(StatusCode::OK, "application/pkix-crl", der))
+        })
+    }
+}
+
+/// Read the DER encoded OCSP request from the HTTP request. POST requests
+/// contain the request in the body, GET requests contain the base64 encoded
+/// request in the path (RFC 6960, appendix A.1).
+async fn read_ocsp_request(
+    request: Request<Body>,
+    ocsp_path: &str,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    match *request.method() {
+        Method::POST => {
+            let mut body = request.into_body();
+            let mut data = Vec::new();
+            while let Some(chunk) = body.data().await {
+                data.extend_from_slice(chunk?.as_ref());
+                if data.len() > MAX_OCSP_REQUEST_SIZE {
+                    return Err("The OCSP request is too large.".into());
+                }
+            }
+            Ok(data)
+        }
+        Method::GET => {
+            let encoded = request.uri().path()[ocsp_path.len()..].trim_start_matches('/');
+            let encoded = percent_decode_str(encoded).decode_utf8()?;
+            Ok(decode_block(encoded.as_ref())?)
+        }
+        _ => Err("Only GET and POST are supported for OCSP requests.".into()),
+    }
+}
@@ -0,0 +1,474 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::Utc;
+use k8s_openapi::ByteString;
+use kube::api::{ApiResource, DynamicObject, GroupVersionKind, ListParams, Patch, PatchParams};
+use kube::runtime::watcher;
+use kube::{Api, Client, ResourceExt};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::cert_store::profile::RequestedUsages;
+use crate::cert_store::store::CertificateStore;
+use crate::cert_store::KubernetesContext;
+use crate::leader_election::LeaderElection;
+use crate::pki_service::PkiService;
+
+/// API group of the `WirePactIssuer` and `WirePactClusterIssuer` resources.
+pub const ISSUER_GROUP: &str = "pki.wirepact.io";
+const ISSUER_VERSION: &str = "v1alpha1";
+const ISSUER_KIND: &str = "WirePactIssuer";
+const CLUSTER_ISSUER_KIND: &str = "WirePactClusterIssuer";
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CertificateRequestSpec {
+    request: ByteString,
+    issuer_ref: IssuerRef,
+    username: Option<String>,
+    #[serde(default, rename = "isCA")]
+    is_ca: bool,
+    /// The requested validity as Go duration (e.g. `2160h0m0s`).
+    duration: Option<String>,
+    #[serde(default)]
+    usages: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct IssuerRef {
+    name: String,
+    kind: Option<String>,
+    group: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct CertificateRequestStatus {
+    certificate: Option<ByteString>,
+    #[serde(default)]
+    conditions: Vec<Condition>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Condition {
+    #[serde(rename = "type")]
+    type_: String,
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_transition_time: Option<Time>,
+}
+
+impl Condition {
+    fn is(&self, type_: &str, status: &str) -> bool {
+        self.type_ == type_ && self.status == status
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct IssuerSpec {
+    /// The issuance profile for certificates of this issuer.
+    profile: Option<String>,
+}
+
+fn api_resource(group: &str, version: &str, kind: &str, plural: &str) -> ApiResource {
+    ApiResource::from_gvk_with_plural(&GroupVersionKind::gvk(group, version, kind), plural)
+}
+
+/// Implements the cert-manager external issuer contract: signs approved
+/// cert-manager `CertificateRequest` objects that reference a
+/// `WirePactIssuer` or `WirePactClusterIssuer`.
+pub struct IssuerController {
+    kubernetes: KubernetesContext,
+    pki_service: Arc<PkiService>,
+    cert_store: Arc<dyn CertificateStore>,
+    leader_election: Arc<LeaderElection>,
+}
+
+impl IssuerController {
+    pub fn new(
+        kubernetes: KubernetesContext,
+        pki_service: Arc<PkiService>,
+        cert_store: Arc<dyn CertificateStore>,
+        leader_election: Arc<LeaderElection>,
+    ) -> Self {
+        Self {
+            kubernetes,
+            pki_service,
+            cert_store,
+            leader_election,
+        }
+    }
+
+    /// Run the controller while this replica is the leader.
+    pub async fn run(self: Arc<Self>) {
+        self.leader_election.lead(|| self.watch()).await
+    }
+
+    async fn watch(&self) {
+        let client = self.kubernetes.client.clone();
+
+        let resource = api_resource(
+            "cert-manager.io",
+            "v1",
+            "CertificateRequest",
+            "certificaterequests",
+        );
+        let api: Api<DynamicObject> = Api::all_with(client.clone(), &resource);
+        info!("Watching cert-manager CertificateRequests.");
+
+        let mut events = Box::pin(watcher(api, ListParams::default()));
+        loop {
+            match events.try_next().await {
+                Ok(Some(event)) => {
+                    for request in event.into_iter_applied() {
+                        let name = request.name_any();
+                        if let Err(e) = self.reconcile(&client, &resource, request).await {
+                            error!("Could not update the CertificateRequest '{}': {}", name, e);
+                        }
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Error while watching CertificateRequests: {}", e);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn reconcile(
+        &self,
+        client: &Client,
+        resource: &ApiResource,
+        request: DynamicObject,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let spec: CertificateRequestSpec = serde_json::from_value(request.data["spec"].clone())?;
+        let kind = spec.issuer_ref.kind.as_deref().unwrap_or_default();
+        if spec.issuer_ref.group.as_deref() != Some(ISSUER_GROUP)
+            || (kind != ISSUER_KIND && kind != CLUSTER_ISSUER_KIND)
+        {
+            return Ok(());
+        }
+
+        let name = request.name_any();
+        let namespace = request.namespace().unwrap_or_default();
+        let status: CertificateRequestStatus = match request.data.get("status") {
+            Some(status) => serde_json::from_value(status.clone())?,
+            None => CertificateRequestStatus::default(),
+        };
+        let ready = status.conditions.iter().find(|c| c.type_ == "Ready");
+        let finished = ready.is_some_and(|c| {
+            c.status == "True" || matches!(c.reason.as_deref(), Some("Failed" | "Denied"))
+        });
+        if status.certificate.is_some() || finished {
+            return Ok(());
+        }
+
+        let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), &namespace, resource);
+        if status.conditions.iter().any(|c| c.is("Denied", "True")) {
+            info!("CertificateRequest '{}/{}' was denied.", namespace, name);
+            let patch = ready_patch(
+                status.conditions,
+                "False",
+                "Denied",
+                "The CertificateRequest was denied.".to_string(),
+                true,
+            );
+            if let Some(patch) = patch {
+                api.patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch))
+                    .await?;
+            }
+            return Ok(());
+        }
+        if !status.conditions.iter().any(|c| c.is("Approved", "True")) {
+            debug!(
+                "CertificateRequest '{}/{}' is not approved yet.",
+                namespace, name
+            );
+            return Ok(());
+        }
+
+        let issuer = match self
+            .issuer_spec(client, kind, &spec.issuer_ref.name, &namespace)
+            .await?
+        {
+            Some(issuer) => issuer,
+            None => {
+                let message = format!("The {} '{}' does not exist.", kind, spec.issuer_ref.name);
+                warn!("CertificateRequest '{}/{}': {}", namespace, name, message);
+                // The request is reconciled again when it changes, an unchanged
+                // condition must not be patched, as the patch would trigger a new event.
+                let patch = ready_patch(status.conditions, "False", "Pending", message, false);
+                if let Some(patch) = patch {
+                    api.patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch))
+                        .await?;
+                }
+                return Ok(());
+            }
+        };
+
+        let requester = spec.username.as_deref().unwrap_or_default();
+        let requested = requested_usages(spec.is_ca, &spec.usages, spec.duration.as_deref());
+        let issued = match requested {
+            Ok(requested) => match self
+                .pki_service
+                .issue_certificate(
+                    spec.request.0.as_slice(),
+                    issuer.profile.as_deref().unwrap_or_default(),
+                    &requested,
+                    requester,
+                )
+                .await
+            {
+                Ok(cert) => self.pki_service.chain_pem(&cert).map_err(|e| e.to_string()),
+                Err(status) => Err(status.message().to_string()),
+            },
+            Err(message) => Err(message),
+        };
+
+        let patch = match issued {
+            Ok(chain) => {
+                info!(
+                    "Signed CertificateRequest '{}/{}' for '{}'.",
+                    namespace, name, requester
+                );
+                let authorities = self.cert_store.authorities();
+                let authority = &authorities.active;
+                let ca = authority.chain.last().unwrap_or(&authority.cert).to_pem()?;
+                let mut patch = ready_patch(
+                    status.conditions,
+                    "True",
+                    "Issued",
+                    "Certificate issued by the WirePact PKI.".to_string(),
+                    false,
+                )
+                .unwrap_or_else(|| json!({ "status": {} }));
+                patch["status"]["certificate"] = json!(ByteString(chain));
+                patch["status"]["ca"] = json!(ByteString(ca));
+                Some(patch)
+            }
+            Err(message) => {
+                warn!(
+                    "Could not sign CertificateRequest '{}/{}': {}",
+                    namespace, name, message
+                );
+                ready_patch(status.conditions, "False", "Failed", message, true)
+            }
+        };
+
+        if let Some(patch) = patch {
+            api.patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Fetch the spec of the referenced issuer. Returns `None` if the issuer does not exist.
+    async fn issuer_spec(
+        &self,
+        client: &Client,
+        kind: &str,
+        name: &str,
+        namespace: &str,
+    ) -> Result<Option<IssuerSpec>, Box<dyn Error + Send + Sync>> {
+        let api: Api<DynamicObject> = match kind {
+            CLUSTER_ISSUER_KIND => Api::all_with(
+                client.clone(),
+                &api_resource(
+                    ISSUER_GROUP,
+                    ISSUER_VERSION,
+                    CLUSTER_ISSUER_KIND,
+                    "wirepactclusterissuers",
+                ),
+            ),
+            _ => Api::namespaced_with(
+                client.clone(),
+                namespace,
+                &api_resource(ISSUER_GROUP, ISSUER_VERSION, ISSUER_KIND, "wirepactissuers"),
+            ),
+        };
+
+        match api.get_opt(name).await? {
+            Some(issuer) => match issuer.data.get("spec") {
+                Some(spec) => Ok(Some(serde_json::from_value(spec.clone())?)),
+                None => Ok(Some(IssuerSpec::default())),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// Return the usages and the validity of the certificate request. CA certificates
+/// are not issued to cert-manager, they require a profile that issues CA certificates.
+fn requested_usages(
+    is_ca: bool,
+    usages: &[String],
+    duration: Option<&str>,
+) -> Result<RequestedUsages, String> {
+    if is_ca {
+        return Err("The issuer does not issue CA certificates (isCA).".to_string());
+    }
+
+    Ok(RequestedUsages {
+        usages: usages.to_vec(),
+        validity: duration.map(parse_duration).transpose()?,
+    })
+}
+
+/// Parse a Go duration as written by cert-manager (e.g. `2160h0m0s` or `1h30m`).
+fn parse_duration(duration: &str) -> Result<time::Duration, String> {
+    let invalid = || format!("The duration '{}' is invalid.", duration);
+    let mut rest = duration;
+    let mut total = time::Duration::ZERO;
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(invalid)?;
+        let value: f64 = rest[..number_end].parse().map_err(|_| invalid())?;
+        rest = &rest[number_end..];
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 1e-3,
+            "us" | "µs" => 1e-6,
+            "ns" => 1e-9,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit_end..];
+        total += time::Duration::seconds_f64(value * seconds);
+    }
+    Ok(total)
+}
+
+/// Create a status patch that sets the Ready condition (and the failure time for final
+/// failures). Returns `None` if the Ready condition is unchanged. The transition time
+/// is only updated if the status of the condition changes.
+fn ready_patch(
+    mut conditions: Vec<Condition>,
+    status: &str,
+    reason: &str,
+    message: String,
+    failed: bool,
+) -> Option<serde_json::Value> {
+    let now = Time(Utc::now());
+    let previous = conditions
+        .iter()
+        .position(|c| c.type_ == "Ready")
+        .map(|index| conditions.remove(index));
+    let last_transition_time = match previous {
+        Some(previous)
+            if previous.status == status
+                && previous.reason.as_deref() == Some(reason)
+                && previous.message.as_deref() == Some(message.as_str()) =>
+        {
+            return None
+        }
+        Some(previous) if previous.status == status => previous.last_transition_time,
+        _ => None,
+    };
+    conditions.push(Condition {
+        type_: "Ready".to_string(),
+        status: status.to_string(),
+        reason: Some(reason.to_string()),
+        message: Some(message),
+        last_transition_time: Some(last_transition_time.unwrap_or_else(|| now.clone())),
+    });
+
+    let mut patch = json!({ "status": { "conditions": conditions } });
+    if failed {
+        patch["status"]["failureTime"] = json!(now);
+    }
+    Some(patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use crate::issuer_controller::{parse_duration, ready_patch, requested_usages, Condition};
+
+    fn conditions(patch: &serde_json::Value) -> Vec<Condition> {
+        serde_json::from_value(patch["status"]["conditions"].clone()).unwrap()
+    }
+
+    #[test]
+    fn patch_only_changed_ready_conditions() {
+        let pending = || "The WirePactIssuer 'pki' does not exist.".to_string();
+        let first = ready_patch(Vec::new(), "False", "Pending", pending(), false).unwrap();
+        let transition_time = &first["status"]["conditions"][0]["lastTransitionTime"];
+        assert!(transition_time.is_string());
+
+        assert!(ready_patch(conditions(&first), "False", "Pending", pending(), false).is_none());
+
+        // A new message with the same status keeps the transition time.
+        let changed = ready_patch(
+            conditions(&first),
+            "False",
+            "Pending",
+            "Other".to_string(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            &changed["status"]["conditions"][0]["lastTransitionTime"],
+            transition_time
+        );
+        assert_eq!(changed["status"]["conditions"][0]["message"], "Other");
+        assert!(changed["status"].get("failureTime").is_none());
+
+        let failed = ready_patch(
+            conditions(&first),
+            "False",
+            "Failed",
+            "Invalid".to_string(),
+            true,
+        )
+        .unwrap();
+        assert!(failed["status"]["failureTime"].is_string());
+        assert_eq!(conditions(&failed).len(), 1);
+    }
+
+    #[test]
+    fn parse_go_durations() {
+        assert_eq!(parse_duration("2160h0m0s").unwrap(), Duration::days(90));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::minutes(90));
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::minutes(90));
+        assert_eq!(
+            parse_duration("500ms").unwrap(),
+            Duration::milliseconds(500)
+        );
+        for invalid in ["", "90", "1d", "h", "1h-5m"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn reject_ca_requests() {
+        let usages = vec!["digital signature".to_string()];
+
+        let requested = requested_usages(false, &usages, Some("1h")).unwrap();
+        assert_eq!(requested.usages, usages);
+        assert_eq!(requested.validity, Some(Duration::hours(1)));
+        assert_eq!(requested_usages(false, &[], None).unwrap().validity, None);
+        assert!(requested_usages(true, &usages, None).is_err());
+        assert!(requested_usages(false, &usages, Some("1 day")).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use std::env;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{self, DateTime, Utc};
+use kube::api::{ObjectMeta, PostParams};
+use kube::Api;
+use log::{debug, info, warn};
+use tokio::sync::watch;
+
+use crate::cert_store::KubernetesContext;
+
+const LEASE_DURATION_SECONDS: i32 = 15;
+const RENEW_INTERVAL: Duration = Duration::from_secs(5);
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+const CONFLICT: u16 = 409;
+
+/// Elects a single leader among the replicas of the PKI with a Kubernetes `Lease`.
+/// Only the leader performs background writes (CA generation and rotation,
+/// CRL publishing and the controllers), while all replicas serve requests.
+pub struct LeaderElection {
+    kubernetes: Option<KubernetesContext>,
+    lease_name: String,
+    identity: String,
+    leader: watch::Sender<bool>,
+}
+
+impl LeaderElection {
+    /// Create a leader election with the given lease in the namespace of the PKI.
+    pub fn new(kubernetes: KubernetesContext, lease_name: String) -> Self {
+        let identity = env::var("POD_NAME")
+            .or_else(|_| env::var("HOSTNAME"))
+            .unwrap_or_else(|_| format!("k8s-pki-{}", std::process::id()));
+        Self {
+            kubernetes: Some(kubernetes),
+            lease_name,
+            identity,
+            leader: watch::channel(false).0,
+        }
+    }
+
+    /// Create a leader election for a single replica that is always the leader.
+    pub fn single() -> Self {
+        Self {
+            kubernetes: None,
+            lease_name: String::new(),
+            identity: String::new(),
+            leader: watch::channel(true).0,
+        }
+    }
+
+    /// Create a leader election for a replica that is never the leader.
+    #[cfg(test)]
+    pub fn follower() -> Self {
+        Self {
+            kubernetes: None,
+            lease_name: String::new(),
+            identity: String::new(),
+            leader: watch::channel(false).0,
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    /// Wait until this replica is (`true`) or is not (`false`) the leader.
+    pub async fn wait_for(&self, leader: bool) {
+        let mut receiver = self.leader.subscribe();
+        // The sender lives as long as `self`, thus waiting cannot fail.
+        let _ = receiver.wait_for(|l| *l == leader).await;
+    }
+
+    /// Run the task whenever this replica is the leader. The task is
+    /// cancelled when the leadership is lost and restarted when it is regained.
+    /// A task that ends while this replica is the leader is restarted after a delay.
+    pub async fn lead<F, Fut>(&self, mut task: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            self.wait_for(true).await;
+            tokio::select! {
+                _ = task() => {
+                    warn!("The task of the leader ended, restart it.");
+                    tokio::time::sleep(RESTART_DELAY).await;
+                },
+                _ = self.wait_for(false) => {},
+            }
+        }
+    }
+
+    /// Try to acquire or renew the lease once.
+    pub async fn elect(&self) {
+        let kubernetes = match self.kubernetes.as_ref() {
+            Some(kubernetes) => kubernetes,
+            None => return,
+        };
+
+        let leader = match self.try_acquire(kubernetes).await {
+            Ok(leader) => leader,
+            Err(e) => {
+                warn!(
+                    "Could not acquire or renew the lease '{}': {}",
+                    self.lease_name, e
+                );
+                false
+            }
+        };
+        self.leader.send_if_modified(|current| {
+            if *current == leader {
+                return false;
+            }
+            match leader {
+                true => info!("Became the leader ('{}').", self.identity),
+                false => info!("Lost the leadership ('{}').", self.identity),
+            }
+            *current = leader;
+            true
+        });
+    }
+
+    /// Renew the lease periodically.
+    pub async fn run(self: Arc<Self>) {
+        if self.kubernetes.is_none() {
+            return;
+        }
+
+        let mut interval = tokio::time::interval(RENEW_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.elect().await;
+        }
+    }
+
+    async fn try_acquire(&self, kubernetes: &KubernetesContext) -> Result<bool, kube::Error> {
+        let lease_name = self.lease_name.as_str();
+        let leases: Api<Lease> = kubernetes.api();
+        let now = Utc::now();
+
+        let result = match leases.get_opt(lease_name).await? {
+            None => {
+                debug!("Lease '{}' does not exist, create it.", lease_name);
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(lease_name.to_string()),
+                        ..ObjectMeta::default()
+                    },
+                    spec: Some(LeaseSpec {
+                        holder_identity: Some(self.identity.clone()),
+                        lease_duration_seconds: Some(LEASE_DURATION_SECONDS),
+                        acquire_time: Some(MicroTime(now)),
+                        renew_time: Some(MicroTime(now)),
+                        lease_transitions: Some(0),
+                    }),
+                };
+                leases.create(&PostParams::default(), &lease).await
+            }
+            Some(mut lease) => {
+                let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+                if !acquire(spec, self.identity.as_str(), now) {
+                    return Ok(false);
+                }
+
+                // The replace is rejected if another replica updated the lease concurrently.
+                leases
+                    .replace(lease_name, &PostParams::default(), &lease)
+                    .await
+            }
+        };
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == CONFLICT => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Update the lease to be held (acquired or renewed) by the identity. Returns `false`
+/// without changing the lease if it is held by another replica and has not expired.
+fn acquire(spec: &mut LeaseSpec, identity: &str, now: DateTime<Utc>) -> bool {
+    let holder = spec.holder_identity.as_deref() == Some(identity);
+    let duration = spec
+        .lease_duration_seconds
+        .unwrap_or(LEASE_DURATION_SECONDS);
+    let expired = spec.renew_time.as_ref().map_or(true, |renewed| {
+        renewed.0 + chrono::Duration::seconds(i64::from(duration)) < now
+    });
+    if !holder && !expired {
+        return false;
+    }
+
+    if !holder {
+        spec.holder_identity = Some(identity.to_string());
+        spec.acquire_time = Some(MicroTime(now));
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+    }
+    spec.lease_duration_seconds = Some(LEASE_DURATION_SECONDS);
+    spec.renew_time = Some(MicroTime(now));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::coordination::v1::LeaseSpec;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+    use k8s_openapi::chrono::{Duration, Utc};
+
+    use crate::leader_election::{acquire, LEASE_DURATION_SECONDS};
+
+    fn lease(holder: &str, renewed_seconds_ago: i64) -> LeaseSpec {
+        let renewed = Utc::now() - Duration::seconds(renewed_seconds_ago);
+        LeaseSpec {
+            holder_identity: Some(holder.to_string()),
+            lease_duration_seconds: Some(LEASE_DURATION_SECONDS),
+            acquire_time: Some(MicroTime(renewed)),
+            renew_time: Some(MicroTime(renewed)),
+            lease_transitions: Some(1),
+        }
+    }
+
+    #[test]
+    fn renew_an_own_lease() {
+        let now = Utc::now();
+        let mut spec = lease("me", 1);
+        let acquired = spec.acquire_time.clone();
+
+        assert!(acquire(&mut spec, "me", now));
+        assert_eq!(spec.renew_time, Some(MicroTime(now)));
+        assert_eq!(spec.acquire_time, acquired);
+        assert_eq!(spec.lease_transitions, Some(1));
+    }
+
+    #[test]
+    fn keep_a_lease_of_another_replica_until_it_expires() {
+        let mut spec = lease("other", 1);
+        let held = spec.clone();
+
+        assert!(!acquire(&mut spec, "me", Utc::now()));
+        assert_eq!(spec, held);
+    }
+
+    #[test]
+    fn take_over_an_expired_lease() {
+        let now = Utc::now();
+        let mut spec = lease("other", i64::from(LEASE_DURATION_SECONDS) + 1);
+
+        assert!(acquire(&mut spec, "me", now));
+        assert_eq!(spec.holder_identity.as_deref(), Some("me"));
+        assert_eq!(spec.acquire_time, Some(MicroTime(now)));
+        assert_eq!(spec.renew_time, Some(MicroTime(now)));
+        assert_eq!(spec.lease_transitions, Some(2));
+
+        // A lease that was never renewed has expired.
+        let mut spec = LeaseSpec {
+            holder_identity: Some("other".to_string()),
+            ..LeaseSpec::default()
+        };
+        assert!(acquire(&mut spec, "me", now));
+        assert_eq!(spec.lease_transitions, Some(1));
+    }
+}
@@ -0,0 +1,20 @@
+//! The WirePact Kubernetes PKI. The binary (`k8s-pki`) runs the PKI, the library
+//! allows to embed it with custom certificate stores: implement the
+//! [cert_store::CertificateStore] and [cert_store::StoreBuilder] traits and
+//! register the builder with its URL scheme in the [cert_store::StoreRegistry],
+//! then [run] the PKI with the registry.
+
+pub use crate::cli::run;
+
+pub mod ca_rotation;
+pub mod cert_store;
+mod cli;
+pub mod crl;
+pub mod csr_controller;
+pub mod http_service;
+pub mod issuer_controller;
+pub mod leader_election;
+pub mod ocsp;
+pub mod pki_service;
+pub mod policy;
+pub mod trust_distributor;
@@ -0,0 +1,409 @@
+use std::cmp::Ordering;
+use std::error::Error;
+use std::sync::Arc;
+
+use log::{debug, error, info};
+use openssl::asn1::Asn1Time;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::{X509Name, X509Req, X509};
+use time::{Duration, OffsetDateTime};
+use tokio::sync::RwLock;
+
+use crate::cert_store::ocsp::{
+    create_response, error_response, ocsp_no_check, CertificateStatus, OcspRequest,
+    OcspResponseStatus,
+};
+use crate::cert_store::profile::{ExtendedKeyUsageFlag, KeyUsageFlag, Profile};
+use crate::cert_store::store::{CertificateAuthority, CertificateStore};
+use crate::cert_store::utils::{create_new_key, signature_digest, KeyAlgorithm};
+
+const DELEGATED_SIGNER_VALIDITY_HOURS: u32 = 24 * 7;
+const DELEGATED_SIGNER_RENEWAL_HOURS: i64 = 24;
+
+/// Answers OCSP requests with the revocation state of the store. The responses
+/// are either signed by the CA itself or by a delegated OCSP signing certificate
+/// that is issued by the CA and renewed before it expires.
+pub struct OcspResponder {
+    cert_store: Arc<dyn CertificateStore>,
+    delegated: bool,
+    key_algorithm: KeyAlgorithm,
+    response_validity: Duration,
+    delegated_signer: RwLock<Option<(X509, PKey<Private>)>>,
+}
+
+impl OcspResponder {
+    pub fn new(
+        cert_store: Arc<dyn CertificateStore>,
+        delegated: bool,
+        key_algorithm: KeyAlgorithm,
+        response_validity: Duration,
+    ) -> Self {
+        Self {
+            cert_store,
+            delegated,
+            key_algorithm,
+            response_validity,
+            delegated_signer: RwLock::new(None),
+        }
+    }
+
+    /// Answer the DER encoded OCSP request. Errors are reported
+    /// as OCSP response status.
+    pub async fn respond(&self, request: &[u8]) -> Vec<u8> {
+        let request = match OcspRequest::from_der(request) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Could not parse OCSP request: {}", e);
+                return error_response(OcspResponseStatus::MalformedRequest);
+            }
+        };
+
+        match self.create_response(&request).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Could not create OCSP response: {}", e);
+                error_response(OcspResponseStatus::InternalError)
+            }
+        }
+    }
+
+    async fn create_response(&self, request: &OcspRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+        let revoked = self.cert_store.revoked_certificates().await?;
+        let authorities = self.cert_store.authorities();
+
+        // A response is signed by a single issuer, thus only the certificates of the
+        // CA that issued the first known certificate are answered.
+        let mut issuer = None;
+        for cert_id in request.cert_ids.iter() {
+            for authority in authorities.all() {
+                if cert_id.is_issued_by(&authority.cert)? {
+                    issuer = Some(authority);
+                    break;
+                }
+            }
+            if issuer.is_some() {
+                break;
+            }
+        }
+        let issuer = match issuer {
+            Some(issuer) => issuer,
+            None => {
+                debug!("OCSP request for certificates of another CA.");
+                return Ok(error_response(OcspResponseStatus::Unauthorized));
+            }
+        };
+
+        let mut statuses = Vec::new();
+        for cert_id in request.cert_ids.iter() {
+            let status = if !cert_id.is_issued_by(&issuer.cert)? {
+                CertificateStatus::Unknown
+            } else {
+                match revoked
+                    .iter()
+                    .find(|r| r.serial_number == cert_id.serial_number)
+                {
+                    Some(revoked) => CertificateStatus::Revoked(revoked.clone()),
+                    // Only certificates that are in the inventory were issued by the PKI,
+                    // a serial number that is not known must not be reported as good.
+                    None => match self
+                        .cert_store
+                        .issued_certificate(cert_id.serial_number.as_str())
+                        .await?
+                    {
+                        Some(_) => CertificateStatus::Good,
+                        None => CertificateStatus::Unknown,
+                    },
+                }
+            };
+            debug!(
+                "OCSP status of certificate '{}': {:?}",
+                cert_id.serial_number, status
+            );
+            statuses.push(status);
+        }
+
+        let this_update = OffsetDateTime::now_utc();
+        let next_update = this_update + self.response_validity;
+        // The delegated signer is issued by the active CA, retired CAs sign their responses directly.
+        if self.delegated && issuer.cert.as_ref() == authorities.active.cert.as_ref() {
+            let (cert, key) = self.delegated_signer(&authorities.active).await?;
+            create_response(
+                request,
+                statuses.as_slice(),
+                cert.as_ref(),
+                &key,
+                true,
+                this_update,
+                next_update,
+            )
+        } else {
+            create_response(
+                request,
+                statuses.as_slice(),
+                &issuer.cert,
+                issuer.key.as_ref(),
+                false,
+                this_update,
+                next_update,
+            )
+        }
+    }
+
+    /// Return the delegated OCSP signing certificate and key. A new one is issued
+    /// if none exists, if it is about to expire or if it was issued by another CA.
+    async fn delegated_signer(
+        &self,
+        ca: &CertificateAuthority,
+    ) -> Result<(X509, PKey<Private>), Box<dyn Error>> {
+        let renewal = Asn1Time::from_unix(
+            (OffsetDateTime::now_utc() + Duration::hours(DELEGATED_SIGNER_RENEWAL_HOURS))
+                .unix_timestamp(),
+        )?;
+        if let Some((cert, key)) = self.delegated_signer.read().await.as_ref() {
+            if cert.not_after().compare(renewal.as_ref())? == Ordering::Greater
+                && cert.verify(ca.cert.public_key()?.as_ref())?
+            {
+                return Ok((cert.clone(), key.clone()));
+            }
+        }
+
+        info!("Issue new delegated OCSP signing certificate.");
+        let key = create_new_key(self.key_algorithm)?;
+        let mut request = X509Req::builder()?;
+        request.set_pubkey(key.as_ref())?;
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, "PKI OCSP Responder")?;
+        let name = name.build();
+        request.set_subject_name(name.as_ref())?;
+        request.sign(key.as_ref(), signature_digest(&key))?;
+
+        let profile = Profile {
+            name: "ocsp-signing".to_string(),
+            validity_hours: DELEGATED_SIGNER_VALIDITY_HOURS,
+            key_usages: vec![KeyUsageFlag::DigitalSignature],
+            extended_key_usages: vec![ExtendedKeyUsageFlag::OcspSigning],
+            max_path_length: None,
+            authorized_requesters: Vec::new(),
+            requested_validity: None,
+        };
+        let extensions = [ocsp_no_check()?];
+        let cert = self
+            .cert_store
+            .sign_csr(request.build(), name.as_ref(), &[], &profile, &extensions)
+            .await?;
+
+        *self.delegated_signer.write().await = Some((cert.clone(), key.clone()));
+        Ok((cert, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::ocsp::{
+        OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+    };
+    use openssl::stack::Stack;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509Crl, X509Name, X509Req, X509};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::cert_store::inventory::CertificateRecord;
+    use crate::cert_store::memory_store::InMemoryStore;
+    use crate::cert_store::profile::Profiles;
+    use crate::cert_store::revocation::{RevocationReason, RevokedCertificate};
+    use crate::cert_store::signer::{ExternalKey, Signer};
+    use crate::cert_store::store::{CertificateAuthority, CertificateStore};
+    use crate::cert_store::utils::{create_new_ca, create_new_key, signature_digest, KeyAlgorithm};
+    use crate::crl::CrlPublisher;
+    use crate::leader_election::LeaderElection;
+    use crate::ocsp::OcspResponder;
+
+    fn store() -> Arc<dyn CertificateStore> {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let cert = create_new_ca(&key).unwrap();
+        Arc::new(InMemoryStore::with_authority(CertificateAuthority {
+            cert,
+            key: Arc::new(key),
+            chain: Vec::new(),
+        }))
+    }
+
+    async fn sign(store: &dyn CertificateStore) -> X509 {
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "ocsp-test")
+            .unwrap();
+        let name = name.build();
+        let mut request = X509Req::builder().unwrap();
+        request.set_pubkey(key.as_ref()).unwrap();
+        request.set_subject_name(name.as_ref()).unwrap();
+        request.sign(key.as_ref(), signature_digest(&key)).unwrap();
+
+        let profiles = Profiles::load(None, 24, "default".to_string())
+            .await
+            .unwrap();
+        store
+            .sign_csr(
+                request.build(),
+                name.as_ref(),
+                &[],
+                profiles.get("").unwrap(),
+                &[],
+            )
+            .await
+            .unwrap()
+    }
+
+    async fn status(responder: &OcspResponder, ca: &X509, certificate: &X509) -> OcspCertStatus {
+        let id = || OcspCertId::from_cert(MessageDigest::sha1(), certificate, ca).unwrap();
+        let mut request = OcspRequest::new().unwrap();
+        request.add_id(id()).unwrap();
+
+        let response = responder
+            .respond(request.to_der().unwrap().as_slice())
+            .await;
+        let response = OcspResponse::from_der(response.as_slice()).unwrap();
+        let basic = response.basic().unwrap();
+        let status = basic.find_status(&id()).unwrap();
+        status.status
+    }
+
+    #[tokio::test]
+    async fn answer_the_status_of_issued_certificates() {
+        let store = store();
+        let ca = store.cert();
+        let responder = OcspResponder::new(
+            store.clone(),
+            false,
+            KeyAlgorithm::EcdsaP256,
+            Duration::hours(1),
+        );
+        let issued = sign(store.as_ref()).await;
+        let revoked = sign(store.as_ref()).await;
+        let unknown = sign(store.as_ref()).await;
+        for certificate in [&issued, &revoked] {
+            let record = CertificateRecord::new(certificate, "test", "default").unwrap();
+            store.record_certificate(record).await.unwrap();
+        }
+        store
+            .revoke_certificate(RevokedCertificate {
+                serial_number: revoked
+                    .serial_number()
+                    .to_bn()
+                    .unwrap()
+                    .to_hex_str()
+                    .unwrap()
+                    .to_string(),
+                revoked_at: OffsetDateTime::now_utc().unix_timestamp(),
+                reason: RevocationReason::KeyCompromise,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(status(&responder, &ca, &issued).await, OcspCertStatus::GOOD);
+        assert_eq!(
+            status(&responder, &ca, &revoked).await,
+            OcspCertStatus::REVOKED
+        );
+        assert_eq!(
+            status(&responder, &ca, &unknown).await,
+            OcspCertStatus::UNKNOWN
+        );
+    }
+
+    #[tokio::test]
+    async fn sign_and_revoke_with_all_ca_algorithms() {
+        // The CA key is either held in memory or not (like a key in a PKCS#11 token).
+        for (algorithm, external) in [
+            (KeyAlgorithm::EcdsaP256, false),
+            (KeyAlgorithm::EcdsaP384, false),
+            (KeyAlgorithm::Ed25519, false),
+            (KeyAlgorithm::Rsa2048, true),
+            (KeyAlgorithm::EcdsaP256, true),
+            (KeyAlgorithm::EcdsaP384, true),
+            (KeyAlgorithm::Ed25519, true),
+        ] {
+            let key = create_new_key(algorithm).unwrap();
+            let key: Arc<dyn Signer> = match external {
+                true => Arc::new(ExternalKey(key)),
+                false => Arc::new(key),
+            };
+            let cert = create_new_ca(key.as_ref()).unwrap();
+            let store: Arc<dyn CertificateStore> =
+                Arc::new(InMemoryStore::with_authority(CertificateAuthority {
+                    cert: cert.clone(),
+                    key,
+                    chain: Vec::new(),
+                }));
+            let ca_key = cert.public_key().unwrap();
+
+            let issued = sign(store.as_ref()).await;
+            assert!(issued.verify(&ca_key).unwrap(), "{:?}", algorithm);
+            let record = CertificateRecord::new(&issued, "test", "default").unwrap();
+            store.record_certificate(record).await.unwrap();
+
+            let publisher = CrlPublisher::new(
+                store.clone(),
+                Arc::new(LeaderElection::single()),
+                Duration::hours(1),
+            );
+            let crl = X509Crl::from_der(publisher.crl_der().await.unwrap().as_slice()).unwrap();
+            assert!(crl.verify(&ca_key).unwrap(), "{:?}", algorithm);
+
+            for delegated in [false, true] {
+                let responder =
+                    OcspResponder::new(store.clone(), delegated, algorithm, Duration::hours(1));
+                let id = || OcspCertId::from_cert(MessageDigest::sha1(), &issued, &cert).unwrap();
+                let mut request = OcspRequest::new().unwrap();
+                request.add_id(id()).unwrap();
+                let response = responder
+                    .respond(request.to_der().unwrap().as_slice())
+                    .await;
+                let response = OcspResponse::from_der(response.as_slice()).unwrap();
+                let basic = response.basic().unwrap();
+                assert_eq!(
+                    basic.find_status(&id()).unwrap().status,
+                    OcspCertStatus::GOOD
+                );
+
+                let mut trust = X509StoreBuilder::new().unwrap();
+                trust.add_cert(cert.clone()).unwrap();
+                let mut certs = Stack::new().unwrap();
+                certs.push(cert.clone()).unwrap();
+                basic
+                    .verify(&certs, &trust.build(), OcspFlag::empty())
+                    .unwrap_or_else(|e| panic!("{:?}: {}", algorithm, e));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_requests_for_other_cas() {
+        let other_store = store();
+        let responder =
+            OcspResponder::new(store(), true, KeyAlgorithm::EcdsaP256, Duration::hours(1));
+        let certificate = sign(other_store.as_ref()).await;
+        let mut request = OcspRequest::new().unwrap();
+        request
+            .add_id(
+                OcspCertId::from_cert(MessageDigest::sha1(), &certificate, &other_store.cert())
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let response = responder
+            .respond(request.to_der().unwrap().as_slice())
+            .await;
+        let response = OcspResponse::from_der(response.as_slice()).unwrap();
+        assert_eq!(response.status(), OcspResponseStatus::UNAUTHORIZED);
+
+        let response = responder.respond(b"invalid").await;
+        let response = OcspResponse::from_der(response.as_slice()).unwrap();
+        assert_eq!(response.status(), OcspResponseStatus::MALFORMED_REQUEST);
+    }
+}
@@ -1,45 +1,279 @@
-use log::{debug, warn};
-use openssl::x509::X509Req;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use openssl::x509::{X509Req, X509};
+use prost_types::Timestamp;
+use time::OffsetDateTime;
 use tonic::{Code, Request, Response, Status};
 
+use crate::cert_store::inventory::{CertificateFilter, CertificateRecord};
+use crate::cert_store::ocsp::authority_info_access;
+use crate::cert_store::profile::{Profiles, RequestedUsages};
+use crate::cert_store::revocation::{
+    crl_distribution_points, normalize_serial_number, RevocationReason, RevokedCertificate,
+};
 use crate::cert_store::store::CertificateStore;
-use crate::pki_service::grpc::{CaCertificate, SignCsrRequest, SignCsrResponse};
+use crate::cert_store::utils::certificates_to_pem;
+use crate::crl::{issuer_id, CrlPublisher};
+use crate::pki_service::grpc::{
+    CaCertificate, CertificateInfo, Crl, GetCertificateRequest, ListCertificatesRequest,
+    ListCertificatesResponse, RevokeCertificateRequest, SignCsrRequest, SignCsrResponse,
+    TrustBundle,
+};
+use crate::policy::IssuancePolicy;
+
+const ANONYMOUS_IDENTITY: &str = "anonymous";
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// An API key that is accepted by the PKI together with the
+/// identity of its holder (e.g. `translator=my-secret-key`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub identity: String,
+    pub key: String,
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((identity, key)) if !identity.is_empty() && !key.is_empty() => Ok(Self {
+                identity: identity.to_string(),
+                key: key.to_string(),
+            }),
+            _ => Err("API keys must be in the format '<identity>=<key>'.".to_string()),
+        }
+    }
+}
+
+/// Public URLs of the PKI that are embedded in issued certificates.
+#[derive(Debug, Clone, Default)]
+pub struct IssuerUrls {
+    /// URL of the CRL (CRL distribution points extension).
+    pub crl: Option<String>,
+    /// URL of the OCSP responder (authority information access extension).
+    pub ocsp: Option<String>,
+}
 
 pub struct PkiService {
-    cert_store: Box<dyn CertificateStore>,
-    api_key: Option<String>,
+    cert_store: Arc<dyn CertificateStore>,
+    crl_publisher: Arc<CrlPublisher>,
+    api_keys: Vec<ApiKey>,
+    policy: IssuancePolicy,
+    profiles: Profiles,
+    issuer_urls: IssuerUrls,
 }
 
 impl PkiService {
-    pub fn new(cert_store: Box<dyn CertificateStore>, api_key: Option<String>) -> Self {
+    pub fn new(
+        cert_store: Arc<dyn CertificateStore>,
+        crl_publisher: Arc<CrlPublisher>,
+        api_keys: Vec<ApiKey>,
+        policy: IssuancePolicy,
+        profiles: Profiles,
+        issuer_urls: IssuerUrls,
+    ) -> Self {
         Self {
             cert_store,
-            api_key,
+            crl_publisher,
+            api_keys,
+            policy,
+            profiles,
+            issuer_urls,
         }
     }
 
-    fn check_api_key<T>(&self, request: &Request<T>) -> bool {
-        match self.api_key.as_ref() {
-            None => {
-                debug!("No API key configured, allowing all requests.");
-                true
+    /// Check the PEM encoded CSR against the policies, sign it with the given
+    /// profile (restricted to the requested usages) and record the certificate
+    /// for the given requester.
+    pub async fn issue_certificate(
+        &self,
+        csr: &[u8],
+        profile: &str,
+        requested: &RequestedUsages,
+        requester: &str,
+    ) -> Result<X509, Status> {
+        let profile = match self.profiles.get(profile) {
+            Some(profile) => Ok(profile),
+            None => Err(Status::new(
+                Code::InvalidArgument,
+                format!("The profile '{}' does not exist.", profile),
+            )),
+        }?;
+        if !profile.authorizes(requester) {
+            warn!(
+                "Rejected CSR of '{}': the profile '{}' issues CA certificates.",
+                requester, profile.name
+            );
+            return Err(Status::new(
+                Code::PermissionDenied,
+                format!(
+                    "The profile '{}' issues CA certificates and is restricted to authorized requesters.",
+                    profile.name
+                ),
+            ));
+        }
+        let profile = match profile.restrict(requested) {
+            Ok(profile) => profile,
+            Err(message) => {
+                warn!("Rejected CSR of '{}': {}", requester, message);
+                return Err(Status::new(Code::InvalidArgument, message));
+            }
+        };
+
+        let csr = match X509Req::from_pem(csr) {
+            Ok(req) => Ok(req),
+            Err(e) => {
+                debug!("{:#?}", e);
+                Err(Status::new(
+                    Code::InvalidArgument,
+                    "The CSR could not be parsed from pem format.",
+                ))
+            }
+        }?;
+
+        let verified = match csr.public_key() {
+            Ok(public_key) => csr.verify(public_key.as_ref()).unwrap_or(false),
+            Err(_) => false,
+        };
+        if !verified {
+            warn!(
+                "Rejected CSR for '{:?}': signature does not match the public key.",
+                csr.subject_name()
+            );
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "The signature of the CSR could not be verified with its public key.",
+            ));
+        }
+
+        let key_check = match csr.public_key() {
+            Ok(public_key) => self.policy.key.check(public_key.as_ref()),
+            Err(_) => Err("The public key of the CSR could not be read.".to_string()),
+        };
+        if let Err(message) = key_check {
+            warn!(
+                "Rejected CSR for '{:?}' due to the key policy: {}",
+                csr.subject_name(),
+                message
+            );
+            return Err(Status::new(Code::InvalidArgument, message));
+        }
+
+        let alt_names = match self.policy.subject.alt_names(csr.as_ref()) {
+            Ok(alt_names) => alt_names,
+            Err(message) => return Err(Status::new(Code::InvalidArgument, message)),
+        };
+        if let Err(message) = self.policy.san.check(alt_names.as_slice()) {
+            warn!("Rejected CSR: {}", message);
+            return Err(Status::new(Code::InvalidArgument, message));
+        }
+
+        let subject = match self.policy.subject.apply(csr.subject_name(), requester) {
+            Ok(subject) => subject,
+            Err(message) => {
+                warn!(
+                    "Rejected CSR for '{:?}' due to the subject policy: {}",
+                    csr.subject_name(),
+                    message
+                );
+                return Err(Status::new(Code::InvalidArgument, message));
+            }
+        };
+
+        let mut extensions = Vec::new();
+        if let Some(url) = self.issuer_urls.crl.as_ref() {
+            // Certificates point to the CRL of their issuer, which stays
+            // available when the CA is rotated.
+            let url = issuer_id(&self.cert_store.cert())
+                .map(|id| format!("{}/{}", url.trim_end_matches('/'), id));
+            match url.and_then(|url| crl_distribution_points(url.as_str())) {
+                Ok(extension) => extensions.push(extension),
+                Err(_) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        "Could not create the CRL distribution points.",
+                    ))
+                }
+            }
+        }
+        if let Some(url) = self.issuer_urls.ocsp.as_ref() {
+            match authority_info_access(url) {
+                Ok(extension) => extensions.push(extension),
+                Err(_) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        "Could not create the authority information access.",
+                    ))
+                }
             }
-            Some(key) => {
-                let auth_header = request.metadata().get("Authorization");
-                match auth_header {
-                    Some(header) => {
-                        let header_key = header.to_str().unwrap();
-                        let result = header_key == key;
-                        if !result {
-                            warn!("Authorization key ({}) in request does not match configured key ({})", key, header_key);
-                        }
-                        result
-                    }
-                    None => {
-                        warn!("No Authorization header found in request, but an API key was provided.");
-                        false
-                    }
+        }
+
+        let cert = match self
+            .cert_store
+            .sign_csr(
+                csr,
+                subject.as_ref(),
+                alt_names.as_slice(),
+                &profile,
+                extensions.as_slice(),
+            )
+            .await
+        {
+            Ok(c) => Ok(c),
+            Err(_) => Err(Status::new(Code::Internal, "Could not sign the CSR.")),
+        }?;
+
+        let record = match CertificateRecord::new(cert.as_ref(), requester, &profile.name) {
+            Ok(record) => Ok(record),
+            Err(_) => Err(Status::new(
+                Code::Internal,
+                "Could not create the inventory record of the certificate.",
+            )),
+        }?;
+        // The certificate is already signed, thus it is returned even if it
+        // could not be recorded instead of letting the requester sign a new one.
+        if let Err(e) = self.cert_store.record_certificate(record).await {
+            error!("Could not record the issued certificate: {}", e);
+        }
+
+        Ok(cert)
+    }
+
+    /// Return the PEM encoded chain of an issued certificate
+    /// (the certificate followed by the intermediate CA certificates).
+    pub fn chain_pem(&self, cert: &X509) -> Result<Vec<u8>, Box<dyn Error>> {
+        certificates_to_pem(self.cert_store.certificate_chain(cert).as_slice())
+    }
+
+    /// Check the API key of the request and return the identity of the caller
+    /// (the name of the matching API key). Returns `None` if the request is not authorized.
+    fn authorize<T>(&self, request: &Request<T>) -> Option<String> {
+        if self.api_keys.is_empty() {
+            debug!("No API key configured, allowing all requests.");
+            return Some(ANONYMOUS_IDENTITY.to_string());
+        }
+
+        let auth_header = request.metadata().get("Authorization");
+        match auth_header {
+            Some(header) => {
+                let header_key = header.to_str().unwrap_or_default();
+                let api_key = self
+                    .api_keys
+                    .iter()
+                    .find(|api_key| api_key.key == header_key);
+                if api_key.is_none() {
+                    warn!("Authorization key in request does not match any configured key.");
                 }
+                api_key.map(|api_key| api_key.identity.clone())
+            }
+            None => {
+                warn!("No Authorization header found in request, but an API key was provided.");
+                None
             }
         }
     }
@@ -49,10 +283,29 @@ pub mod grpc {
     tonic::include_proto!("wirepact.pki");
 }
 
+fn certificate_info(record: CertificateRecord, revoked: bool) -> CertificateInfo {
+    CertificateInfo {
+        serial_number: record.serial_number,
+        subject: record.subject,
+        subject_alt_names: record.subject_alt_names,
+        not_before: Some(Timestamp {
+            seconds: record.not_before,
+            nanos: 0,
+        }),
+        not_after: Some(Timestamp {
+            seconds: record.not_after,
+            nanos: 0,
+        }),
+        requester: record.requester,
+        profile: record.profile,
+        revoked,
+    }
+}
+
 #[tonic::async_trait]
 impl grpc::pki_service_server::PkiService for PkiService {
     async fn get_ca(&self, request: Request<()>) -> Result<Response<CaCertificate>, Status> {
-        if !self.check_api_key(&request) {
+        if self.authorize(&request).is_none() {
             return Err(Status::new(Code::PermissionDenied, "Invalid API key"));
         }
 
@@ -68,39 +321,874 @@ impl grpc::pki_service_server::PkiService for PkiService {
         Ok(Response::new(CaCertificate { certificate: pem }))
     }
 
+    async fn get_trust_bundle(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<TrustBundle>, Status> {
+        if self.authorize(&request).is_none() {
+            return Err(Status::new(Code::PermissionDenied, "Invalid API key"));
+        }
+
+        debug!("Returning trust bundle to caller.");
+        let mut certificates = Vec::new();
+        for cert in self.cert_store.trust_bundle() {
+            match cert.to_pem() {
+                Ok(pem) => certificates.extend(pem),
+                Err(_) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        "Could not serialize the trust bundle.",
+                    ))
+                }
+            }
+        }
+
+        Ok(Response::new(TrustBundle { certificates }))
+    }
+
     async fn sign_csr(
         &self,
         request: Request<SignCsrRequest>,
     ) -> Result<Response<SignCsrResponse>, Status> {
-        if !self.check_api_key(&request) {
+        let requester = match self.authorize(&request) {
+            Some(identity) => Ok(identity),
+            None => Err(Status::new(Code::PermissionDenied, "Invalid API key")),
+        }?;
+
+        let request = request.into_inner();
+        let cert = self
+            .issue_certificate(
+                request.csr.as_slice(),
+                request.profile.as_str(),
+                &RequestedUsages::default(),
+                &requester,
+            )
+            .await?;
+
+        let pem = match cert.to_pem() {
+            Ok(pem) => Ok(pem),
+            Err(_) => Err(Status::new(
+                Code::Internal,
+                "Could not load or serialize certificate.",
+            )),
+        }?;
+        let chain = match self.chain_pem(&cert) {
+            Ok(chain) => Ok(chain),
+            Err(_) => Err(Status::new(
+                Code::Internal,
+                "Could not serialize the certificate chain.",
+            )),
+        }?;
+
+        debug!("Return signed certificate to requester.");
+        Ok(Response::new(SignCsrResponse {
+            certificate: pem,
+            chain,
+        }))
+    }
+
+    async fn revoke_certificate(
+        &self,
+        request: Request<RevokeCertificateRequest>,
+    ) -> Result<Response<()>, Status> {
+        if self.authorize(&request).is_none() {
             return Err(Status::new(Code::PermissionDenied, "Invalid API key"));
         }
 
-        let csr = match X509Req::from_pem(request.into_inner().csr.as_slice()) {
-            Ok(req) => Ok(req),
-            Err(e) => {
-                debug!("{:#?}", e);
-                Err(Status::new(
-                    Code::InvalidArgument,
-                    "The CSR could not be parsed from pem format.",
+        let request = request.into_inner();
+        let serial_number = match normalize_serial_number(request.serial_number.as_str()) {
+            Ok(serial_number) => Ok(serial_number),
+            Err(_) => Err(Status::new(
+                Code::InvalidArgument,
+                "The serial number could not be parsed from hex notation.",
+            )),
+        }?;
+        let reason = match RevocationReason::from_code(request.reason) {
+            Some(reason) => Ok(reason),
+            None => Err(Status::new(
+                Code::InvalidArgument,
+                "The revocation reason is not supported.",
+            )),
+        }?;
+
+        let revoked = RevokedCertificate {
+            serial_number: serial_number.clone(),
+            revoked_at: OffsetDateTime::now_utc().unix_timestamp(),
+            reason,
+        };
+        match self.cert_store.revoke_certificate(revoked).await {
+            Ok(true) => info!(
+                "Revoked certificate '{}' with reason {:?}.",
+                serial_number, reason
+            ),
+            Ok(false) => {
+                return Err(Status::new(
+                    Code::AlreadyExists,
+                    "The certificate is already revoked.",
                 ))
             }
+            Err(_) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    "Could not store the revocation.",
+                ))
+            }
+        }
+
+        if self.crl_publisher.publish_revocation().await.is_err() {
+            return Err(Status::new(
+                Code::Internal,
+                "The certificate was revoked, but the CRL could not be regenerated.",
+            ));
+        }
+
+        Ok(Response::new(()))
+    }
+
+    async fn get_crl(&self, request: Request<()>) -> Result<Response<Crl>, Status> {
+        if self.authorize(&request).is_none() {
+            return Err(Status::new(Code::PermissionDenied, "Invalid API key"));
+        }
+
+        debug!("Returning CRL to caller.");
+        let pem = match self.crl_publisher.crl_pem().await {
+            Ok(pem) => Ok(pem),
+            Err(_) => Err(Status::new(
+                Code::Internal,
+                "Could not generate or serialize the CRL.",
+            )),
         }?;
 
-        let cert = match self.cert_store.sign_csr(csr).await {
-            Ok(c) => Ok(c),
-            Err(_) => Err(Status::new(Code::Internal, "Could not sign the CSR.")),
+        Ok(Response::new(Crl { crl: pem }))
+    }
+
+    async fn list_certificates(
+        &self,
+        request: Request<ListCertificatesRequest>,
+    ) -> Result<Response<ListCertificatesResponse>, Status> {
+        if self.authorize(&request).is_none() {
+            return Err(Status::new(Code::PermissionDenied, "Invalid API key"));
+        }
+
+        let request = request.into_inner();
+        let offset = match request.page_token.as_str() {
+            "" => Ok(0),
+            token => token
+                .parse::<usize>()
+                .map_err(|_| Status::new(Code::InvalidArgument, "The page token is invalid.")),
         }?;
+        let page_size = match request.page_size as usize {
+            0 => DEFAULT_PAGE_SIZE,
+            size => size.min(MAX_PAGE_SIZE),
+        };
 
-        let pem = match cert.to_pem() {
-            Ok(pem) => Ok(pem),
+        let filter = CertificateFilter {
+            subject: request.subject,
+            profile: request.profile,
+            requester: request.requester,
+            include_expired: request.include_expired,
+        };
+        let (issued, more) = match self
+            .cert_store
+            .issued_certificates_page(&filter, offset, page_size)
+            .await
+        {
+            Ok(page) => Ok(page),
             Err(_) => Err(Status::new(
                 Code::Internal,
-                "Could not load or serialize certificate.",
+                "Could not load the issued certificates.",
+            )),
+        }?;
+        let revoked = match self.cert_store.revoked_certificates().await {
+            Ok(revoked) => Ok(revoked),
+            Err(_) => Err(Status::new(
+                Code::Internal,
+                "Could not load the revoked certificates.",
             )),
         }?;
 
-        debug!("Return signed certificate to requester.");
-        Ok(Response::new(SignCsrResponse { certificate: pem }))
+        let certificates: Vec<CertificateInfo> = issued
+            .into_iter()
+            .map(|record| {
+                let is_revoked = revoked
+                    .iter()
+                    .any(|r| r.serial_number == record.serial_number);
+                certificate_info(record, is_revoked)
+            })
+            .collect();
+        let next_page_token = match more {
+            true => (offset + certificates.len()).to_string(),
+            false => String::new(),
+        };
+
+        debug!("Returning {} issued certificate(s).", certificates.len());
+        Ok(Response::new(ListCertificatesResponse {
+            certificates,
+            next_page_token,
+        }))
+    }
+
+    async fn get_certificate(
+        &self,
+        request: Request<GetCertificateRequest>,
+    ) -> Result<Response<CertificateInfo>, Status> {
+        if self.authorize(&request).is_none() {
+            return Err(Status::new(Code::PermissionDenied, "Invalid API key"));
+        }
+
+        let request = request.into_inner();
+        let serial_number = match normalize_serial_number(request.serial_number.as_str()) {
+            Ok(serial_number) => Ok(serial_number),
+            Err(_) => Err(Status::new(
+                Code::InvalidArgument,
+                "The serial number could not be parsed from hex notation.",
+            )),
+        }?;
+
+        let record = match self
+            .cert_store
+            .issued_certificate(serial_number.as_str())
+            .await
+        {
+            Ok(Some(record)) => Ok(record),
+            Ok(None) => Err(Status::new(
+                Code::NotFound,
+                "The certificate was not issued by this PKI.",
+            )),
+            Err(_) => Err(Status::new(
+                Code::Internal,
+                "Could not load the issued certificates.",
+            )),
+        }?;
+        let revoked = match self.cert_store.revoked_certificates().await {
+            Ok(revoked) => Ok(revoked),
+            Err(_) => Err(Status::new(
+                Code::Internal,
+                "Could not load the revoked certificates.",
+            )),
+        }?;
+        let is_revoked = revoked.iter().any(|r| r.serial_number == serial_number);
+
+        Ok(Response::new(certificate_info(record, is_revoked)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::stack::Stack;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Name, X509Req, X509};
+    use regex::Regex;
+    use time::Duration;
+    use tokio::net::TcpListener;
+    use tonic::metadata::MetadataValue;
+    use tonic::transport::{Channel, Server};
+    use tonic::{Code, Request};
+
+    use crate::cert_store::inventory::CertificateRecord;
+    use crate::cert_store::memory_store::InMemoryStore;
+    use crate::cert_store::profile::{Profile, Profiles};
+    use crate::cert_store::revocation::{RevocationReason, RevokedCertificate};
+    use crate::cert_store::signer::KeyStorage;
+    use crate::cert_store::store::{CertificateAuthority, CertificateStore};
+    use crate::cert_store::utils::{
+        create_new_ca, create_new_key, name_to_string, signature_digest, KeyAlgorithm,
+    };
+    use crate::crl::CrlPublisher;
+    use crate::leader_election::LeaderElection;
+    use crate::pki_service::grpc::pki_service_client::PkiServiceClient;
+    use crate::pki_service::grpc::pki_service_server::{PkiService as _, PkiServiceServer};
+    use crate::pki_service::grpc::{
+        GetCertificateRequest, ListCertificatesRequest, SignCsrRequest,
+    };
+    use crate::pki_service::{ApiKey, IssuerUrls, PkiService};
+    use crate::policy::{IssuancePolicy, SanPolicy, SubjectPolicy};
+
+    async fn service() -> PkiService {
+        let key = create_new_key(KeyAlgorithm::Rsa2048).unwrap();
+        let cert = create_new_ca(&key).unwrap();
+        service_with_store(InMemoryStore::with_authority(CertificateAuthority {
+            cert,
+            key: Arc::new(key),
+            chain: Vec::new(),
+        }))
+        .await
+    }
+
+    async fn service_with_store(store: InMemoryStore) -> PkiService {
+        let store: Arc<dyn CertificateStore> = Arc::new(store);
+        let crl_publisher = Arc::new(CrlPublisher::new(
+            store.clone(),
+            Arc::new(LeaderElection::single()),
+            Duration::hours(1),
+        ));
+        let profiles = Profiles::load(None, 24, "default".to_string())
+            .await
+            .unwrap();
+        PkiService::new(
+            store,
+            crl_publisher,
+            Vec::new(),
+            IssuancePolicy::default(),
+            profiles,
+            IssuerUrls::default(),
+        )
+    }
+
+    /// Serve the service on a random local port and connect a client to it.
+    async fn client(service: PkiService) -> PkiServiceClient<Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = Box::pin(futures::stream::unfold(listener, |listener| async move {
+            let connection = listener.accept().await.map(|(stream, _)| stream);
+            Some((connection, listener))
+        }));
+        tokio::spawn(
+            Server::builder()
+                .add_service(PkiServiceServer::new(service))
+                .serve_with_incoming(incoming),
+        );
+
+        PkiServiceClient::connect(format!("http://{}", address))
+            .await
+            .unwrap()
+    }
+
+    fn csr(public_key: &PKey<Private>, signing_key: &PKey<Private>) -> X509Req {
+        let mut builder = X509Req::builder().unwrap();
+        builder.set_pubkey(public_key.as_ref()).unwrap();
+        builder.set_version(0).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "test-csr")
+            .unwrap();
+        let name = name.build();
+        builder.set_subject_name(name.as_ref()).unwrap();
+        builder
+            .sign(signing_key.as_ref(), signature_digest(signing_key))
+            .unwrap();
+        builder.build()
+    }
+
+    fn csr_with_alt_names(key: &PKey<Private>) -> X509Req {
+        let mut builder = X509Req::builder().unwrap();
+        builder.set_pubkey(key.as_ref()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "test-csr")
+            .unwrap();
+        builder.set_subject_name(name.build().as_ref()).unwrap();
+        let mut alt_names = SubjectAlternativeName::new();
+        alt_names
+            .dns("app.svc.cluster.local")
+            .uri("spiffe://cluster.local/ns/default/sa/app")
+            .ip("10.0.0.1");
+        let mut extensions = Stack::new().unwrap();
+        extensions
+            .push(alt_names.build(&builder.x509v3_context(None)).unwrap())
+            .unwrap();
+        builder.add_extensions(&extensions).unwrap();
+        builder.sign(key.as_ref(), signature_digest(key)).unwrap();
+        builder.build()
+    }
+
+    /// Return the DNS names, URIs and IP addresses of the certificate.
+    fn alt_names(certificate: &X509) -> Vec<String> {
+        certificate
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .map(
+                        |name| match (name.dnsname(), name.uri(), name.ipaddress()) {
+                            (Some(dns), _, _) => dns.to_string(),
+                            (_, Some(uri), _) => uri.to_string(),
+                            (_, _, Some(ip)) => format!("{:?}", ip),
+                            _ => String::new(),
+                        },
+                    )
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn tampered_pem(csr: &X509Req, tamper: impl Fn(&mut Vec<u8>)) -> Vec<u8> {
+        let mut der = csr.to_der().unwrap();
+        tamper(&mut der);
+        X509Req::from_der(der.as_slice()).unwrap().to_pem().unwrap()
+    }
+
+    async fn sign(service: &PkiService, pem: Vec<u8>) -> Result<Vec<u8>, Code> {
+        service
+            .sign_csr(Request::new(SignCsrRequest {
+                csr: pem,
+                profile: String::new(),
+            }))
+            .await
+            .map(|response| response.into_inner().certificate)
+            .map_err(|status| status.code())
+    }
+
+    #[tokio::test]
+    async fn sign_valid_csr() {
+        let service = service().await;
+        let key = create_new_key(KeyAlgorithm::Rsa2048).unwrap();
+        let pem = csr(&key, &key).to_pem().unwrap();
+
+        let certificate = sign(&service, pem).await.unwrap();
+        let certificate = X509::from_pem(certificate.as_slice()).unwrap();
+
+        assert!(certificate.public_key().unwrap().public_eq(key.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn copy_the_requested_alt_names() {
+        let service = service().await;
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let pem = csr_with_alt_names(&key).to_pem().unwrap();
+
+        let certificate = sign(&service, pem).await.unwrap();
+        let certificate = X509::from_pem(certificate.as_slice()).unwrap();
+        assert_eq!(
+            alt_names(&certificate),
+            vec![
+                "app.svc.cluster.local".to_string(),
+                "spiffe://cluster.local/ns/default/sa/app".to_string(),
+                "[10, 0, 0, 1]".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_alt_names_outside_the_san_policy() {
+        let mut service = service().await;
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        for policy in [
+            SanPolicy {
+                dns_suffixes: vec!["example.com".to_string()],
+                ..SanPolicy::default()
+            },
+            SanPolicy {
+                uri_schemes: vec!["https".to_string()],
+                ..SanPolicy::default()
+            },
+            SanPolicy {
+                ip_ranges: vec!["192.168.0.0/16".parse().unwrap()],
+                ..SanPolicy::default()
+            },
+        ] {
+            service.policy.san = policy;
+            let pem = csr_with_alt_names(&key).to_pem().unwrap();
+            assert_eq!(sign(&service, pem).await, Err(Code::InvalidArgument));
+        }
+
+        service.policy.san = SanPolicy {
+            dns_suffixes: vec!["svc.cluster.local".to_string()],
+            uri_schemes: vec!["spiffe".to_string()],
+            ip_ranges: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let pem = csr_with_alt_names(&key).to_pem().unwrap();
+        assert!(sign(&service, pem).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn drop_the_alt_names_if_the_subject_is_the_identity() {
+        let mut service = service().await;
+        service.policy.subject.from_identity = true;
+        // The requested names are dropped, thus they are not checked either.
+        service.policy.san = SanPolicy {
+            dns_suffixes: vec!["example.com".to_string()],
+            ..SanPolicy::default()
+        };
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let pem = csr_with_alt_names(&key).to_pem().unwrap();
+
+        let certificate = sign(&service, pem).await.unwrap();
+        let certificate = X509::from_pem(certificate.as_slice()).unwrap();
+        assert!(certificate.subject_alt_names().is_none());
+        assert_eq!(
+            name_to_string(certificate.subject_name()).unwrap(),
+            "CN=anonymous"
+        );
+    }
+
+    #[tokio::test]
+    async fn sign_csr_with_any_supported_key_type() {
+        let service = service().await;
+        for algorithm in [
+            KeyAlgorithm::Rsa3072,
+            KeyAlgorithm::EcdsaP256,
+            KeyAlgorithm::EcdsaP384,
+            KeyAlgorithm::Ed25519,
+        ] {
+            let key = create_new_key(algorithm).unwrap();
+            let pem = csr(&key, &key).to_pem().unwrap();
+
+            let certificate = sign(&service, pem).await.unwrap();
+            let certificate = X509::from_pem(certificate.as_slice()).unwrap();
+
+            assert!(certificate.public_key().unwrap().public_eq(key.as_ref()));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_csr_with_weak_key() {
+        let service = service().await;
+        let key = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+        let pem = csr(&key, &key).to_pem().unwrap();
+
+        assert_eq!(sign(&service, pem).await, Err(Code::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn apply_subject_policy() {
+        let mut service = service().await;
+        service.policy.subject = SubjectPolicy {
+            common_name_pattern: Some(Regex::new("^(?:test-.*)$").unwrap()),
+            forced_attributes: vec!["O=WirePact".parse().unwrap()],
+            ..SubjectPolicy::default()
+        };
+        let key = create_new_key(KeyAlgorithm::Rsa2048).unwrap();
+        let pem = csr(&key, &key).to_pem().unwrap();
+
+        let certificate = sign(&service, pem).await.unwrap();
+        let certificate = X509::from_pem(certificate.as_slice()).unwrap();
+        assert_eq!(
+            name_to_string(certificate.subject_name()).unwrap(),
+            "CN=test-csr, O=WirePact"
+        );
+
+        service.policy.subject.common_name_pattern = Some(Regex::new("^(?:other)$").unwrap());
+        let pem = csr(&key, &key).to_pem().unwrap();
+        assert_eq!(sign(&service, pem).await, Err(Code::InvalidArgument));
+
+        service.policy.subject.from_identity = true;
+        let pem = csr(&key, &key).to_pem().unwrap();
+        let certificate = sign(&service, pem).await.unwrap();
+        let certificate = X509::from_pem(certificate.as_slice()).unwrap();
+        assert_eq!(
+            name_to_string(certificate.subject_name()).unwrap(),
+            "CN=anonymous, O=WirePact"
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_csr_signed_with_other_key() {
+        let service = service().await;
+        let key = create_new_key(KeyAlgorithm::Rsa2048).unwrap();
+        let other_key = create_new_key(KeyAlgorithm::Rsa2048).unwrap();
+        let pem = csr(&key, &other_key).to_pem().unwrap();
+
+        assert_eq!(sign(&service, pem).await, Err(Code::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn reject_csr_with_tampered_subject() {
+        let service = service().await;
+        let key = create_new_key(KeyAlgorithm::Rsa2048).unwrap();
+        let pem = tampered_pem(&csr(&key, &key), |der| {
+            let position = der
+                .windows(8)
+                .position(|window| window == b"test-csr")
+                .unwrap();
+            der[position] = b'b';
+        });
+
+        assert_eq!(sign(&service, pem).await, Err(Code::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn reject_csr_with_tampered_signature() {
+        let service = service().await;
+        let key = create_new_key(KeyAlgorithm::Rsa2048).unwrap();
+        let pem = tampered_pem(&csr(&key, &key), |der| {
+            let last = der.len() - 1;
+            der[last] ^= 0xff;
+        });
+
+        assert_eq!(sign(&service, pem).await, Err(Code::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn reject_unparsable_csr() {
+        let service = service().await;
+
+        assert_eq!(
+            sign(&service, b"not a csr".to_vec()).await,
+            Err(Code::InvalidArgument)
+        );
+    }
+
+    #[tokio::test]
+    async fn get_ca_over_channel() {
+        let mut store = InMemoryStore::new(KeyAlgorithm::EcdsaP256, KeyStorage::default());
+        store.init(None).await.unwrap();
+        let service = service_with_store(store).await;
+        let ca = service.cert_store.cert();
+        let mut client = client(service).await;
+
+        let response = client.get_ca(Request::new(())).await.unwrap();
+        let certificate = X509::from_pem(response.into_inner().certificate.as_slice()).unwrap();
+
+        assert_eq!(certificate, ca);
+    }
+
+    #[tokio::test]
+    async fn restrict_ca_profiles_to_authorized_requesters() {
+        let mut service = service().await;
+        let sub_ca: Profile = serde_yaml::from_str(
+            "{ name: sub-ca, validityHours: 24, maxPathLength: 0, authorizedRequesters: [ops] }",
+        )
+        .unwrap();
+        service.profiles = Profiles::new(vec![sub_ca], "sub-ca".to_string()).unwrap();
+        service.api_keys = vec![
+            "translator=translator-key".parse::<ApiKey>().unwrap(),
+            "ops=ops-key".parse::<ApiKey>().unwrap(),
+        ];
+        let key = create_new_key(KeyAlgorithm::EcdsaP256).unwrap();
+        let request = |api_key: &'static str| {
+            let mut request = Request::new(SignCsrRequest {
+                csr: csr(&key, &key).to_pem().unwrap(),
+                profile: "sub-ca".to_string(),
+            });
+            request
+                .metadata_mut()
+                .insert("authorization", MetadataValue::from_static(api_key));
+            request
+        };
+
+        let status = service
+            .sign_csr(request("translator-key"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+
+        let response = service.sign_csr(request("ops-key")).await.unwrap();
+        let certificate = X509::from_pem(response.get_ref().certificate.as_slice()).unwrap();
+        assert!(String::from_utf8(certificate.to_text().unwrap())
+            .unwrap()
+            .contains("CA:TRUE, pathlen:0"));
+    }
+
+    /// Record the certificates `01` to `07`: `03` is expired, `05` and `06` are
+    /// issued with the server profile by `translator` and `06` is revoked.
+    async fn service_with_inventory() -> PkiService {
+        let service = service().await;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        for number in 1..=7 {
+            let (profile, requester) = match number {
+                5 | 6 => ("server", "translator"),
+                _ => ("default", "anonymous"),
+            };
+            let record = CertificateRecord {
+                serial_number: format!("{:02}", number),
+                subject: format!("CN=service-{}", number),
+                subject_alt_names: Vec::new(),
+                not_before: now - 7200,
+                not_after: if number == 3 { now - 3600 } else { now + 3600 },
+                requester: requester.to_string(),
+                profile: profile.to_string(),
+            };
+            service.cert_store.record_certificate(record).await.unwrap();
+        }
+        service
+            .cert_store
+            .revoke_certificate(RevokedCertificate {
+                serial_number: "06".to_string(),
+                revoked_at: now,
+                reason: RevocationReason::Superseded,
+            })
+            .await
+            .unwrap();
+        service
+    }
+
+    async fn list(
+        service: &PkiService,
+        request: ListCertificatesRequest,
+    ) -> Result<(Vec<String>, String), Code> {
+        service
+            .list_certificates(Request::new(request))
+            .await
+            .map(|response| {
+                let response = response.into_inner();
+                let serial_numbers = response
+                    .certificates
+                    .into_iter()
+                    .map(|certificate| certificate.serial_number)
+                    .collect();
+                (serial_numbers, response.next_page_token)
+            })
+            .map_err(|status| status.code())
+    }
+
+    #[tokio::test]
+    async fn list_certificates_in_pages() {
+        let service = service_with_inventory().await;
+        let page = |page_token: &str| ListCertificatesRequest {
+            page_size: 2,
+            page_token: page_token.to_string(),
+            ..ListCertificatesRequest::default()
+        };
+
+        let mut serial_numbers = Vec::new();
+        let mut pages = 0;
+        let mut page_token = String::new();
+        loop {
+            let (page, next_page_token) = list(&service, page(page_token.as_str())).await.unwrap();
+            assert!(page.len() <= 2);
+            serial_numbers.extend(page);
+            pages += 1;
+            if next_page_token.is_empty() {
+                break;
+            }
+            page_token = next_page_token;
+        }
+
+        assert_eq!(pages, 3);
+        assert_eq!(serial_numbers, vec!["01", "02", "04", "05", "06", "07"]);
+        assert_eq!(
+            list(&service, page("6")).await,
+            Ok((Vec::new(), String::new()))
+        );
+        assert_eq!(
+            list(&service, page("next")).await,
+            Err(Code::InvalidArgument)
+        );
+    }
+
+    #[tokio::test]
+    async fn list_certificates_with_filters() {
+        let service = service_with_inventory().await;
+
+        let (all, next_page_token) = list(
+            &service,
+            ListCertificatesRequest {
+                include_expired: true,
+                ..ListCertificatesRequest::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(all.len(), 7);
+        assert!(next_page_token.is_empty());
+
+        let (server, _) = list(
+            &service,
+            ListCertificatesRequest {
+                profile: "server".to_string(),
+                requester: "translator".to_string(),
+                ..ListCertificatesRequest::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(server, vec!["05", "06"]);
+
+        let (subject, _) = list(
+            &service,
+            ListCertificatesRequest {
+                subject: "service-7".to_string(),
+                ..ListCertificatesRequest::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(subject, vec!["07"]);
+
+        let revoked = service
+            .get_certificate(Request::new(GetCertificateRequest {
+                serial_number: "0:6".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(revoked.serial_number, "06");
+        assert!(revoked.revoked);
+    }
+
+    #[tokio::test]
+    async fn sign_csr_over_channel() {
+        let service = service().await;
+        let store = service.cert_store.clone();
+        let mut client = client(service).await;
+        let key = create_new_key(KeyAlgorithm::EcdsaP384).unwrap();
+
+        let response = client
+            .sign_csr(Request::new(SignCsrRequest {
+                csr: csr(&key, &key).to_pem().unwrap(),
+                profile: "server".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let certificate = X509::from_pem(response.certificate.as_slice()).unwrap();
+
+        assert!(certificate.public_key().unwrap().public_eq(key.as_ref()));
+        assert!(certificate
+            .verify(store.cert().public_key().unwrap().as_ref())
+            .unwrap());
+        assert_eq!(response.chain, response.certificate);
+        let issued = store.issued_certificates().await.unwrap();
+        assert_eq!(issued.len(), 1);
+        assert_eq!(issued[0].profile, "server");
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_csr_over_channel() {
+        let mut client = client(service().await).await;
+
+        let status = client
+            .sign_csr(Request::new(SignCsrRequest {
+                csr: b"not a csr".to_vec(),
+                profile: String::new(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = client
+            .sign_csr(Request::new(SignCsrRequest {
+                csr: Vec::new(),
+                profile: "unknown".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn require_api_key_over_channel() {
+        let mut service = service().await;
+        service.api_keys = vec!["translator=my-secret-key".parse::<ApiKey>().unwrap()];
+        let store = service.cert_store.clone();
+        let mut client = client(service).await;
+        let key = create_new_key(KeyAlgorithm::Rsa2048).unwrap();
+        let authorized = |mut request: Request<SignCsrRequest>| {
+            request
+                .metadata_mut()
+                .insert("authorization", MetadataValue::from_static("my-secret-key"));
+            request
+        };
+        let request = || SignCsrRequest {
+            csr: csr(&key, &key).to_pem().unwrap(),
+            profile: String::new(),
+        };
+
+        let status = client.get_ca(Request::new(())).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        let status = client.sign_csr(Request::new(request())).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+
+        client
+            .sign_csr(authorized(Request::new(request())))
+            .await
+            .unwrap();
+        let issued = store.issued_certificates().await.unwrap();
+        assert_eq!(issued.len(), 1);
+        assert_eq!(issued[0].requester, "translator");
     }
 }
@@ -0,0 +1,569 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use openssl::asn1::Asn1Object;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKeyRef};
+use openssl::x509::{X509Name, X509NameRef, X509ReqRef};
+use regex::Regex;
+
+use crate::cert_store::utils::{requested_alt_names, AltName};
+
+/// A range of IP addresses in CIDR notation (e.g. `10.0.0.0/8`).
+/// A plain IP address is treated as a range with a single address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    let remaining_bits = prefix % 8;
+
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+
+        let network = IpAddr::from_str(address.trim())
+            .map_err(|_| format!("'{}' is not a valid IP address.", address))?;
+        let max_prefix = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("'{}' is not a valid prefix length.", prefix))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { network, prefix })
+    }
+}
+
+/// All policies that a CSR must satisfy before it is signed.
+#[derive(Debug, Clone, Default)]
+pub struct IssuancePolicy {
+    pub san: SanPolicy,
+    pub key: KeyPolicy,
+    pub subject: SubjectPolicy,
+}
+
+/// A named elliptic curve that may be used by keys in CSRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcCurve {
+    P256,
+    P384,
+    P521,
+}
+
+impl EcCurve {
+    fn from_nid(nid: Nid) -> Option<Self> {
+        match nid {
+            Nid::X9_62_PRIME256V1 => Some(EcCurve::P256),
+            Nid::SECP384R1 => Some(EcCurve::P384),
+            Nid::SECP521R1 => Some(EcCurve::P521),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for EcCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "p-256" | "prime256v1" | "secp256r1" => Ok(EcCurve::P256),
+            "p-384" | "secp384r1" => Ok(EcCurve::P384),
+            "p-521" | "secp521r1" => Ok(EcCurve::P521),
+            _ => Err(format!(
+                "Unknown curve '{}', supported are P-256, P-384 and P-521.",
+                s
+            )),
+        }
+    }
+}
+
+/// Policy for the public keys of CSRs. RSA, EC and Ed25519 keys are supported,
+/// all other key types are rejected.
+#[derive(Debug, Clone)]
+pub struct KeyPolicy {
+    pub min_rsa_bits: u32,
+    pub allowed_curves: Vec<EcCurve>,
+    pub allow_ed25519: bool,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            min_rsa_bits: 2048,
+            allowed_curves: vec![EcCurve::P256, EcCurve::P384],
+            allow_ed25519: true,
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// Check the public key of a CSR against the policy.
+    /// Returns a message that names the violated rule if the key is not allowed.
+    pub fn check<T: HasPublic>(&self, key: &PKeyRef<T>) -> Result<(), String> {
+        match key.id() {
+            Id::RSA => {
+                if key.bits() < self.min_rsa_bits {
+                    return Err(format!(
+                        "RSA keys must have at least {} bits, but the key has {} bits.",
+                        self.min_rsa_bits,
+                        key.bits()
+                    ));
+                }
+            }
+            Id::EC => {
+                let curve = key
+                    .ec_key()
+                    .ok()
+                    .and_then(|key| key.group().curve_name())
+                    .and_then(EcCurve::from_nid);
+                match curve {
+                    Some(curve) if self.allowed_curves.contains(&curve) => {}
+                    Some(curve) => {
+                        return Err(format!(
+                            "The curve {:?} is not allowed by the policy.",
+                            curve
+                        ))
+                    }
+                    None => return Err("The curve of the EC key is not supported.".to_string()),
+                }
+            }
+            Id::ED25519 => {
+                if !self.allow_ed25519 {
+                    return Err("Ed25519 keys are not allowed by the policy.".to_string());
+                }
+            }
+            _ => return Err("The key type is not supported, use RSA, EC or Ed25519.".to_string()),
+        }
+
+        Ok(())
+    }
+}
+
+/// An attribute of a subject name with its value (e.g. `O=WirePact`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubjectAttribute {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for SubjectAttribute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=').ok_or_else(|| {
+            "Subject attributes must be in the format '<name>=<value>'.".to_string()
+        })?;
+        let name = name.trim();
+        if Asn1Object::from_str(name).is_err() {
+            return Err(format!("'{}' is not a known subject attribute.", name));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Build a subject name of the attributes in the given order.
+pub fn subject_name(attributes: &[SubjectAttribute]) -> Result<X509Name, String> {
+    if attributes.is_empty() {
+        return Err("The subject must contain at least one attribute.".to_string());
+    }
+
+    let build = || -> Result<X509Name, openssl::error::ErrorStack> {
+        let mut name = X509Name::builder()?;
+        for attribute in attributes.iter() {
+            name.append_entry_by_text(attribute.name.as_str(), attribute.value.as_str())?;
+        }
+        Ok(name.build())
+    };
+    build().map_err(|e| e.to_string())
+}
+
+/// Policy for the subject of issued certificates. The subject of the CSR is
+/// checked against the allowed and required attributes and the pattern for
+/// the common name, forced attributes replace the requested values. If the
+/// subject is taken from the identity of the caller, the requested subject
+/// is ignored and the certificate gets the identity as common name.
+#[derive(Debug, Clone, Default)]
+pub struct SubjectPolicy {
+    /// Short names of the attributes that may be requested (e.g. `CN`).
+    /// If empty, all attributes are allowed.
+    pub allowed_attributes: Vec<String>,
+    /// Short names of the attributes that must be requested.
+    pub required_attributes: Vec<String>,
+    /// Pattern that all requested common names must match completely.
+    pub common_name_pattern: Option<Regex>,
+    /// Attributes that are always set by the PKI.
+    pub forced_attributes: Vec<SubjectAttribute>,
+    pub from_identity: bool,
+}
+
+impl SubjectPolicy {
+    /// Return the subject for the certificate of the CSR with the given subject
+    /// that is requested by the given identity. Returns a message that names the
+    /// violated rule if the requested subject is not allowed.
+    pub fn apply(&self, subject: &X509NameRef, identity: &str) -> Result<X509Name, String> {
+        let mut attributes = Vec::new();
+        if self.from_identity {
+            attributes.push(("CN".to_string(), identity.to_string()));
+        } else {
+            for entry in subject.entries() {
+                let name = entry
+                    .object()
+                    .nid()
+                    .short_name()
+                    .map_err(|e| e.to_string())?;
+                let value = entry.data().as_utf8().map_err(|e| e.to_string())?;
+                attributes.push((name.to_string(), value.to_string()));
+            }
+            self.check(attributes.as_slice())?;
+        }
+
+        for forced in self.forced_attributes.iter() {
+            attributes.retain(|(name, _)| !forced.name.eq_ignore_ascii_case(name));
+        }
+        attributes.extend(
+            self.forced_attributes
+                .iter()
+                .map(|forced| (forced.name.clone(), forced.value.clone())),
+        );
+
+        let build = || -> Result<X509Name, openssl::error::ErrorStack> {
+            let mut name = X509Name::builder()?;
+            for (attribute, value) in attributes.iter() {
+                name.append_entry_by_text(attribute, value)?;
+            }
+            Ok(name.build())
+        };
+        build().map_err(|e| e.to_string())
+    }
+
+    /// Return the subject alternative names for the certificate of the CSR. If the
+    /// subject is taken from the identity, the requested names are dropped, otherwise
+    /// a caller could still claim any name next to its identity.
+    pub fn alt_names(&self, request: &X509ReqRef) -> Result<Vec<AltName>, String> {
+        if self.from_identity {
+            return Ok(Vec::new());
+        }
+
+        requested_alt_names(request).map_err(|e| e.to_string())
+    }
+
+    fn check(&self, attributes: &[(String, String)]) -> Result<(), String> {
+        for (name, _) in attributes.iter() {
+            let forced = self
+                .forced_attributes
+                .iter()
+                .any(|forced| forced.name.eq_ignore_ascii_case(name));
+            if !forced
+                && !self.allowed_attributes.is_empty()
+                && !self
+                    .allowed_attributes
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(name))
+            {
+                return Err(format!(
+                    "The subject attribute '{}' is not allowed by the policy.",
+                    name
+                ));
+            }
+        }
+
+        for required in self.required_attributes.iter() {
+            if !attributes
+                .iter()
+                .any(|(name, _)| required.eq_ignore_ascii_case(name))
+            {
+                return Err(format!(
+                    "The subject attribute '{}' is required by the policy.",
+                    required
+                ));
+            }
+        }
+
+        if let Some(pattern) = self.common_name_pattern.as_ref() {
+            // A subject without common name would bypass the pattern, unless the PKI sets it.
+            let forced = self
+                .forced_attributes
+                .iter()
+                .any(|forced| forced.name.eq_ignore_ascii_case("CN"));
+            if !forced && !attributes.iter().any(|(name, _)| name == "CN") {
+                return Err("The common name is required by the pattern of the policy.".to_string());
+            }
+            for (_, value) in attributes.iter().filter(|(name, _)| name == "CN") {
+                if !pattern.is_match(value) {
+                    return Err(format!(
+                        "The common name '{}' does not match the pattern of the policy.",
+                        value
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Policy for the subject alternative names that may be requested in a CSR.
+/// Each kind of name is only restricted if at least one rule for it is configured.
+#[derive(Debug, Clone, Default)]
+pub struct SanPolicy {
+    pub dns_suffixes: Vec<String>,
+    pub uri_schemes: Vec<String>,
+    pub ip_ranges: Vec<IpRange>,
+}
+
+impl SanPolicy {
+    /// Check the subject alternative names of a certificate against the policy.
+    /// Returns a message that describes the violation if any name is not allowed.
+    pub fn check(&self, names: &[AltName]) -> Result<(), String> {
+        for name in names.iter() {
+            if !self.allows(name) {
+                return Err(format!(
+                    "The subject alternative name '{}' is not allowed by the policy.",
+                    name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn allows(&self, name: &AltName) -> bool {
+        match name {
+            AltName::Dns(dns) => {
+                if self.dns_suffixes.is_empty() {
+                    return true;
+                }
+
+                let dns = dns.to_lowercase();
+                self.dns_suffixes.iter().any(|suffix| {
+                    let suffix = suffix.trim_start_matches('.').to_lowercase();
+                    dns == suffix || dns.ends_with(format!(".{}", suffix).as_str())
+                })
+            }
+            AltName::Uri(uri) => {
+                if self.uri_schemes.is_empty() {
+                    return true;
+                }
+
+                match uri.split_once(':') {
+                    Some((scheme, _)) => self
+                        .uri_schemes
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(scheme)),
+                    None => false,
+                }
+            }
+            AltName::Ip(ip) => {
+                self.ip_ranges.is_empty() || self.ip_ranges.iter().any(|range| range.contains(ip))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use openssl::nid::Nid;
+    use openssl::x509::X509Name;
+    use regex::Regex;
+
+    use crate::cert_store::utils::{name_to_string, AltName};
+    use crate::policy::{subject_name, IpRange, SanPolicy, SubjectAttribute, SubjectPolicy};
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn dns_policy(suffixes: &[&str]) -> SanPolicy {
+        SanPolicy {
+            dns_suffixes: suffixes.iter().map(|suffix| suffix.to_string()).collect(),
+            ..SanPolicy::default()
+        }
+    }
+
+    fn allows_dns(policy: &SanPolicy, dns: &str) -> bool {
+        policy.allows(&AltName::Dns(dns.to_string()))
+    }
+
+    #[test]
+    fn ip_range_contains_addresses_of_the_prefix() {
+        let range: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains(&ip("10.0.0.0")));
+        assert!(range.contains(&ip("10.255.255.255")));
+        assert!(!range.contains(&ip("11.0.0.0")));
+
+        let range: IpRange = "192.168.4.0/22".parse().unwrap();
+        assert!(range.contains(&ip("192.168.7.255")));
+        assert!(!range.contains(&ip("192.168.8.0")));
+        assert!(!range.contains(&ip("192.168.3.255")));
+
+        let range: IpRange = "fd00::/8".parse().unwrap();
+        assert!(range.contains(&ip("fd12:3456::1")));
+        assert!(!range.contains(&ip("fe80::1")));
+    }
+
+    #[test]
+    fn ip_range_edge_cases() {
+        let single: IpRange = "10.1.2.3".parse().unwrap();
+        assert!(single.contains(&ip("10.1.2.3")));
+        assert!(!single.contains(&ip("10.1.2.4")));
+
+        let all: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("203.0.113.7")));
+        // IPv4 ranges never contain IPv6 addresses (and vice versa).
+        assert!(!all.contains(&ip("::ffff:203.0.113.7")));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("fd00::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn dns_suffix_matching() {
+        let policy = dns_policy(&["svc.cluster.local"]);
+        assert!(allows_dns(&policy, "svc.cluster.local"));
+        assert!(allows_dns(&policy, "api.default.svc.cluster.local"));
+        assert!(!allows_dns(&policy, "evilsvc.cluster.local"));
+        assert!(!allows_dns(&policy, "svc.cluster.local.evil.com"));
+        assert!(!allows_dns(&policy, "cluster.local"));
+    }
+
+    #[test]
+    fn dns_suffix_matching_ignores_case_and_leading_dot() {
+        let policy = dns_policy(&[".Svc.Cluster.Local"]);
+        assert!(allows_dns(&policy, "API.default.SVC.cluster.local"));
+        assert!(allows_dns(&policy, "svc.cluster.local"));
+    }
+
+    #[test]
+    fn dns_suffix_matching_of_wildcards() {
+        let policy = dns_policy(&["svc.cluster.local"]);
+        assert!(allows_dns(&policy, "*.default.svc.cluster.local"));
+        assert!(allows_dns(&policy, "*.svc.cluster.local"));
+        // The wildcard would match names outside of the suffix.
+        assert!(!allows_dns(&policy, "*.cluster.local"));
+        assert!(!allows_dns(&policy, "*"));
+    }
+
+    #[test]
+    fn dns_names_are_not_restricted_without_suffixes() {
+        assert!(allows_dns(&SanPolicy::default(), "example.com"));
+    }
+
+    fn subject(attributes: &[(Nid, &str)]) -> X509Name {
+        let mut name = X509Name::builder().unwrap();
+        for (nid, value) in attributes.iter() {
+            name.append_entry_by_nid(*nid, value).unwrap();
+        }
+        name.build()
+    }
+
+    fn pattern_policy() -> SubjectPolicy {
+        SubjectPolicy {
+            common_name_pattern: Some(Regex::new("^(?:[a-z]+\\.default)$").unwrap()),
+            ..SubjectPolicy::default()
+        }
+    }
+
+    #[test]
+    fn common_names_must_match_the_pattern() {
+        let policy = pattern_policy();
+
+        let allowed = policy
+            .apply(&subject(&[(Nid::COMMONNAME, "api.default")]), "test")
+            .unwrap();
+        assert_eq!(name_to_string(&allowed).unwrap(), "CN=api.default");
+        assert!(policy
+            .apply(&subject(&[(Nid::COMMONNAME, "api.default.evil")]), "test")
+            .is_err());
+        assert!(policy
+            .apply(
+                &subject(&[(Nid::COMMONNAME, "api.default"), (Nid::COMMONNAME, "API"),]),
+                "test"
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn common_name_is_required_by_the_pattern() {
+        let policy = pattern_policy();
+        let without_common_name = subject(&[(Nid::ORGANIZATIONNAME, "WirePact")]);
+
+        assert!(policy.apply(&without_common_name, "test").is_err());
+        assert!(SubjectPolicy::default()
+            .apply(&without_common_name, "test")
+            .is_ok());
+
+        // The common name that is set by the PKI does not need to be requested.
+        let forced = SubjectPolicy {
+            forced_attributes: vec![SubjectAttribute {
+                name: "CN".to_string(),
+                value: "forced".to_string(),
+            }],
+            ..pattern_policy()
+        };
+        let name = forced.apply(&without_common_name, "test").unwrap();
+        assert_eq!(name_to_string(&name).unwrap(), "O=WirePact, CN=forced");
+    }
+
+    #[test]
+    fn build_the_subject_name_of_attributes() {
+        let attributes: Vec<SubjectAttribute> = ["CN=Intermediate CA", "O=WirePact"]
+            .iter()
+            .map(|attribute| attribute.parse().unwrap())
+            .collect();
+        let name = subject_name(attributes.as_slice()).unwrap();
+        assert_eq!(
+            name_to_string(name.as_ref()).unwrap(),
+            "CN=Intermediate CA, O=WirePact"
+        );
+        assert!(subject_name(&[]).is_err());
+    }
+}
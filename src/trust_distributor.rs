@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use k8s_openapi::api::core::v1::{ConfigMap, Namespace};
+use kube::api::{DeleteParams, ListParams, ObjectMeta, Patch, PatchParams};
+use kube::runtime::watcher;
+use kube::{Api, Client, ResourceExt};
+use log::{debug, error, info, warn};
+
+use crate::cert_store::store::CertificateStore;
+use crate::cert_store::utils::certificates_to_pem;
+use crate::cert_store::KubernetesContext;
+use crate::leader_election::LeaderElection;
+
+/// Key of the active CA certificate in the ConfigMap.
+pub const CA_KEY: &str = "ca.crt";
+/// Key of the trust bundle (active and retired CAs) in the ConfigMap.
+pub const BUNDLE_KEY: &str = "ca-bundle.crt";
+
+const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+const MANAGED_BY: &str = "wirepact-pki";
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Publishes the CA certificate and the trust bundle to a ConfigMap in every
+/// namespace that matches the label selector and removes the ConfigMap from
+/// namespaces that no longer match.
+pub struct TrustDistributor {
+    kubernetes: KubernetesContext,
+    cert_store: Arc<dyn CertificateStore>,
+    leader_election: Arc<LeaderElection>,
+    config_map_name: String,
+    namespace_selector: String,
+}
+
+impl TrustDistributor {
+    pub fn new(
+        kubernetes: KubernetesContext,
+        cert_store: Arc<dyn CertificateStore>,
+        leader_election: Arc<LeaderElection>,
+        config_map_name: String,
+        namespace_selector: String,
+    ) -> Self {
+        Self {
+            kubernetes,
+            cert_store,
+            leader_election,
+            config_map_name,
+            namespace_selector,
+        }
+    }
+
+    /// Run the controller while this replica is the leader.
+    pub async fn run(self: Arc<Self>) {
+        self.leader_election.lead(|| self.watch()).await
+    }
+
+    async fn watch(&self) {
+        let client = self.kubernetes.client.clone();
+
+        info!(
+            "Publishing the trust bundle to the ConfigMap '{}' in namespaces matching '{}'.",
+            self.config_map_name, self.namespace_selector
+        );
+
+        let mut interval = tokio::time::interval(SYNC_INTERVAL);
+        let mut reloads = self.cert_store.reloads();
+        let mut namespaces = Box::pin(watcher(
+            Api::<Namespace>::all(client.clone()),
+            ListParams::default(),
+        ));
+        loop {
+            tokio::select! {
+                // Sync periodically to repair modified or deleted ConfigMaps.
+                _ = interval.tick() => {},
+                Ok(_) = reloads.changed() => debug!("The CA was reloaded, publish the trust bundle."),
+                event = namespaces.try_next() => match event {
+                    Ok(Some(_)) => {},
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("Error while watching namespaces: {}", e);
+                        tokio::time::sleep(RETRY_INTERVAL).await;
+                        continue;
+                    }
+                },
+            }
+
+            if let Err(e) = self.sync(&client).await {
+                error!("Could not publish the trust bundle: {}", e);
+            }
+        }
+    }
+
+    /// Apply the ConfigMap to all matching namespaces where it is missing or outdated and
+    /// remove it from the other namespaces. A failure in one namespace does not stop the
+    /// sync of the others.
+    async fn sync(&self, client: &Client) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let ca = String::from_utf8(self.cert_store.cert().to_pem()?)?;
+        let bundle = certificates_to_pem(self.cert_store.trust_bundle().as_slice())
+            .map_err(|e| e.to_string())?;
+        let data = BTreeMap::from([
+            (CA_KEY.to_string(), ca),
+            (BUNDLE_KEY.to_string(), String::from_utf8(bundle)?),
+        ]);
+
+        let namespaces = Api::<Namespace>::all(client.clone())
+            .list(&ListParams::default().labels(self.namespace_selector.as_str()))
+            .await?;
+        let matching: HashSet<String> = namespaces.iter().map(|ns| ns.name_any()).collect();
+        let existing: HashMap<String, BTreeMap<String, String>> =
+            Api::<ConfigMap>::all(client.clone())
+                .list(
+                    &ListParams::default()
+                        .labels(format!("{}={}", MANAGED_BY_LABEL, MANAGED_BY).as_str())
+                        .fields(format!("metadata.name={}", self.config_map_name).as_str()),
+                )
+                .await?
+                .into_iter()
+                .map(|config_map| {
+                    (
+                        config_map.namespace().unwrap_or_default(),
+                        config_map.data.unwrap_or_default(),
+                    )
+                })
+                .collect();
+
+        let (apply, remove) = plan(&matching, &existing, &data);
+        for namespace in apply {
+            debug!("Publish the trust bundle to namespace '{}'.", namespace);
+            if let Err(e) = self.apply(client, &namespace, &data).await {
+                error!(
+                    "Could not publish the trust bundle to namespace '{}': {}",
+                    namespace, e
+                );
+            }
+        }
+        for namespace in remove {
+            info!(
+                "Namespace '{}' does not match the selector anymore, remove the trust bundle.",
+                namespace
+            );
+            if let Err(e) = Api::<ConfigMap>::namespaced(client.clone(), &namespace)
+                .delete(&self.config_map_name, &DeleteParams::default())
+                .await
+            {
+                error!(
+                    "Could not remove the trust bundle from namespace '{}': {}",
+                    namespace, e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn apply(
+        &self,
+        client: &Client,
+        namespace: &str,
+        data: &BTreeMap<String, String>,
+    ) -> Result<(), kube::Error> {
+        let config_map = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(self.config_map_name.clone()),
+                namespace: Some(namespace.to_string()),
+                labels: Some(BTreeMap::from([(
+                    MANAGED_BY_LABEL.to_string(),
+                    MANAGED_BY.to_string(),
+                )])),
+                ..ObjectMeta::default()
+            },
+            data: Some(data.clone()),
+            ..ConfigMap::default()
+        };
+        Api::<ConfigMap>::namespaced(client.clone(), namespace)
+            .patch(
+                &self.config_map_name,
+                &PatchParams::apply(MANAGED_BY).force(),
+                &Patch::Apply(&config_map),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Return the matching namespaces whose ConfigMap is missing or differs from the data
+/// (other keys are ignored) and the namespaces whose ConfigMap must be removed,
+/// both sorted by name.
+fn plan(
+    matching: &HashSet<String>,
+    existing: &HashMap<String, BTreeMap<String, String>>,
+    data: &BTreeMap<String, String>,
+) -> (Vec<String>, Vec<String>) {
+    let up_to_date = |namespace: &String| {
+        existing.get(namespace).is_some_and(|existing| {
+            data.iter()
+                .all(|(key, value)| existing.get(key) == Some(value))
+        })
+    };
+
+    let mut apply: Vec<String> = matching
+        .iter()
+        .filter(|namespace| !up_to_date(namespace))
+        .cloned()
+        .collect();
+    let mut remove: Vec<String> = existing
+        .keys()
+        .filter(|namespace| !matching.contains(*namespace))
+        .cloned()
+        .collect();
+    apply.sort();
+    remove.sort();
+    (apply, remove)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap, HashSet};
+
+    use crate::trust_distributor::{plan, BUNDLE_KEY, CA_KEY};
+
+    fn data(ca: &str) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            (CA_KEY.to_string(), ca.to_string()),
+            (BUNDLE_KEY.to_string(), format!("{}-bundle", ca)),
+        ])
+    }
+
+    fn names(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn apply_only_missing_or_outdated_config_maps() {
+        let mut extended = data("new");
+        extended.insert("other.crt".to_string(), "added by someone".to_string());
+        let existing = HashMap::from([
+            ("current".to_string(), data("new")),
+            ("extended".to_string(), extended),
+            ("outdated".to_string(), data("old")),
+        ]);
+
+        let (apply, remove) = plan(
+            &names(&["current", "extended", "outdated", "missing"]),
+            &existing,
+            &data("new"),
+        );
+        assert_eq!(apply, vec!["missing", "outdated"]);
+        assert!(remove.is_empty());
+
+        let (apply, _) = plan(&names(&["current"]), &existing, &data("new"));
+        assert!(apply.is_empty());
+    }
+
+    #[test]
+    fn remove_config_maps_of_namespaces_that_do_not_match() {
+        let existing = HashMap::from([
+            ("kept".to_string(), data("new")),
+            ("removed".to_string(), data("new")),
+        ]);
+
+        let (apply, remove) = plan(&names(&["kept"]), &existing, &data("new"));
+        assert!(apply.is_empty());
+        assert_eq!(remove, vec!["removed"]);
+    }
+}